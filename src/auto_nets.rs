@@ -0,0 +1,227 @@
+use std::net::{IpAddr, Ipv4Addr};
+
+use crate::network::{Ports, Subnet};
+use crate::ssh::{self, Remote};
+use nix::unistd::{Gid, Uid};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum AutoNetsError {
+    #[error("IO Error `{0}`")]
+    Io(#[from] std::io::Error),
+
+    #[error("Could not get routes from remote `{0}`")]
+    Command(String),
+}
+
+/// Route types that don't describe a network reachable through the remote.
+const IGNORED_ROUTE_TYPES: [&str; 8] = [
+    "local",
+    "broadcast",
+    "unreachable",
+    "blackhole",
+    "prohibit",
+    "throw",
+    "multicast",
+    "anycast",
+];
+
+fn parse_subnet(s: &str) -> Option<Subnet> {
+    let (address, cidr) = match s.split_once('/') {
+        Some((address, cidr)) => (address.parse::<IpAddr>().ok()?, Some(cidr.parse().ok()?)),
+        None => (s.parse::<IpAddr>().ok()?, None),
+    };
+    let max_cidr = match address {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    };
+    let cidr = cidr.unwrap_or(max_cidr);
+    (cidr <= max_cidr).then_some(Subnet {
+        address,
+        cidr,
+        ports: Ports::None,
+    })
+}
+
+/// Default routes and local networks on the remote are not worth forwarding.
+const fn is_forwardable(subnet: &Subnet) -> bool {
+    if subnet.cidr == 0 {
+        return false;
+    }
+    match subnet.address {
+        IpAddr::V4(ip) => !(ip.is_loopback() || ip.is_link_local() || ip.is_multicast()),
+        IpAddr::V6(ip) => {
+            let link_local = ip.segments()[0] & 0xffc0 == 0xfe80;
+            !(ip.is_loopback() || ip.is_multicast() || link_local)
+        }
+    }
+}
+
+/// Parse the output of `ip route show`.
+pub fn parse_ip_route(output: &str) -> Vec<Subnet> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let mut dst = fields.next()?;
+            if IGNORED_ROUTE_TYPES.contains(&dst) {
+                return None;
+            }
+            if dst == "unicast" {
+                dst = fields.next()?;
+            }
+            if line.contains("scope host") {
+                return None;
+            }
+            parse_subnet(dst)
+        })
+        .filter(is_forwardable)
+        .collect()
+}
+
+/// Parse the IPv4 table from the output of `netstat -rn`.
+pub fn parse_netstat(output: &str) -> Vec<Subnet> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let address = fields.first()?.parse::<Ipv4Addr>().ok()?;
+            let mask = fields.get(2)?.parse::<Ipv4Addr>().ok()?;
+            let cidr = u32::from(mask).count_ones();
+            Some(Subnet {
+                address: IpAddr::V4(address),
+                #[allow(clippy::cast_possible_truncation)]
+                cidr: cidr as u8,
+                ports: Ports::None,
+            })
+        })
+        .filter(is_forwardable)
+        .collect()
+}
+
+async fn run_remote(
+    ssh_cmd: &[String],
+    remote: &Remote,
+    run_as: Option<(Uid, Gid)>,
+    command: &str,
+) -> Result<Option<String>, AutoNetsError> {
+    log::debug!("running `{command}` on {remote}");
+    let output = ssh::command(ssh_cmd, &[], remote, run_as)
+        .arg(command)
+        .output()
+        .await?;
+    if output.status.success() {
+        Ok(Some(String::from_utf8_lossy(&output.stdout).into_owned()))
+    } else {
+        log::debug!(
+            "`{command}` failed on {remote}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        Ok(None)
+    }
+}
+
+/// Get the networks routed by the remote host, using a separate ssh connection.
+///
+/// ssh runs as `run_as` when given.
+pub async fn get_remote_subnets(
+    ssh_cmd: &[String],
+    remote: &Remote,
+    run_as: Option<(Uid, Gid)>,
+) -> Result<Vec<Subnet>, AutoNetsError> {
+    let run = |command| run_remote(ssh_cmd, remote, run_as, command);
+
+    if let Some(output) = run("ip -4 route show table main").await? {
+        let mut subnets = parse_ip_route(&output);
+        // IPv6 may be disabled on the remote, that's not an error.
+        if let Some(output) = run("ip -6 route show table main").await? {
+            subnets.extend(parse_ip_route(&output));
+        }
+        return Ok(subnets);
+    }
+
+    if let Some(output) = run("netstat -rn").await? {
+        return Ok(parse_netstat(&output));
+    }
+
+    Err(AutoNetsError::Command(remote.to_string()))
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_strings(subnets: &[Subnet]) -> Vec<String> {
+        subnets
+            .iter()
+            .map(|s| format!("{}/{}", s.address, s.cidr))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_ip_route_v4() {
+        let output = "\
+default via 10.0.0.1 dev eth0 proto dhcp src 10.0.0.5 metric 100
+10.0.0.0/24 dev eth0 proto kernel scope link src 10.0.0.5 metric 100
+10.0.0.1 dev eth0 proto dhcp scope link src 10.0.0.5 metric 100
+169.254.0.0/16 dev eth0 scope link metric 1000
+172.17.0.0/16 dev docker0 proto kernel scope link src 172.17.0.1 linkdown
+unreachable 192.168.99.0/24
+local 10.0.0.5 dev eth0 proto kernel scope host src 10.0.0.5
+192.168.10.0/24 via 10.0.0.254 dev eth0
+";
+        let subnets = parse_ip_route(output);
+        assert_eq!(
+            to_strings(&subnets),
+            [
+                "10.0.0.0/24",
+                "10.0.0.1/32",
+                "172.17.0.0/16",
+                "192.168.10.0/24"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_ip_route_v6() {
+        let output = "\
+::1 dev lo proto kernel metric 256 pref medium
+2404:6800:4004:80c::/64 dev eth0 proto ra metric 100 pref medium
+fe80::/64 dev eth0 proto kernel metric 256 pref medium
+default via fe80::1 dev eth0 proto ra metric 100 pref medium
+";
+        let subnets = parse_ip_route(output);
+        assert_eq!(to_strings(&subnets), ["2404:6800:4004:80c::/64"]);
+    }
+
+    #[test]
+    fn test_parse_netstat() {
+        let output = "\
+Kernel IP routing table
+Destination     Gateway         Genmask         Flags   MSS Window  irtt Iface
+0.0.0.0         10.0.0.1        0.0.0.0         UG        0 0          0 eth0
+10.0.0.0        0.0.0.0         255.255.255.0   U         0 0          0 eth0
+127.0.0.0       0.0.0.0         255.0.0.0       U         0 0          0 lo
+192.168.0.0     10.0.0.254      255.255.0.0     UG        0 0          0 eth0
+";
+        let subnets = parse_netstat(output);
+        assert_eq!(to_strings(&subnets), ["10.0.0.0/24", "192.168.0.0/16"]);
+    }
+
+    #[tokio::test]
+    async fn test_run_remote_as_user() {
+        if !Uid::effective().is_root() {
+            return;
+        }
+        // Stands in for ssh, the remote and the command end up as ignored arguments.
+        let ssh_cmd = ["sh", "-c", "id -u; id -g", "sh"].map(String::from);
+        let remote: Remote = "host.example.org".parse().unwrap();
+        let run_as = Some((Uid::from_raw(65534), Gid::from_raw(65533)));
+
+        let output = run_remote(&ssh_cmd, &remote, run_as, "netstat -rn")
+            .await
+            .unwrap();
+        assert_eq!(output.as_deref(), Some("65534\n65533\n"));
+    }
+}
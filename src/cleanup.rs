@@ -0,0 +1,465 @@
+//! Remove rules left behind by a session that didn't exit cleanly.
+
+use std::path::Path;
+
+use thiserror::Error;
+
+use crate::{
+    command::Line,
+    commands::Commands,
+    firewall::tproxy::local_route,
+    network::Family,
+    state::{self, State, StateError, STATE_DIR},
+};
+
+#[derive(Error, Debug)]
+pub enum CleanupError {
+    #[error("Command Error `{0}`")]
+    Command(#[from] crate::command::Error),
+
+    #[error("State Error `{0}`")]
+    State(#[from] StateError),
+}
+
+const TABLES: [&str; 2] = ["nat", "mangle"];
+
+fn is_port(s: &str) -> bool {
+    s.parse::<u16>().is_ok()
+}
+
+/// Matches the chains created by the NAT (`sshuttle-1024`) and TPROXY
+/// (`sshuttle-t-tcp-1024`) firewalls, for any port.
+fn is_sshuttle_chain(name: &str) -> bool {
+    let parts: Vec<&str> = match name.strip_prefix("sshuttle-") {
+        Some(rest) => rest.split('-').collect(),
+        None => return false,
+    };
+    match parts.as_slice() {
+        [port] => is_port(port),
+        [kind, protocol, port] => {
+            matches!(*kind, "m" | "t" | "d") && matches!(*protocol, "tcp" | "udp") && is_port(port)
+        }
+        _ => false,
+    }
+}
+
+/// The chains set up by the other sessions that are still running, which must
+/// be left alone.
+fn live_chains(states: &[State]) -> Vec<String> {
+    let mut chains = Vec::new();
+    for cmd in states.iter().flat_map(|state| state.setup.iter()) {
+        let Line(_, args) = &cmd.line;
+        let words = args
+            .iter()
+            .map(String::as_str)
+            .chain(cmd.input.iter().flat_map(|input| input.split_whitespace()));
+        for word in words {
+            if is_sshuttle_chain(word) && !chains.iter().any(|c| c == word) {
+                chains.push(word.to_string());
+            }
+        }
+    }
+    chains
+}
+
+/// The rules routing packets marked with `mark` to `table`, in the output of
+/// `ip rule show`, such as `32765:\tfrom all fwmark 0x1 lookup 100`.
+fn parse_rules(listing: &str, mark: u32, table: u32) -> Vec<String> {
+    listing
+        .lines()
+        .filter_map(|line| {
+            let (_, rule) = line.split_once(':')?;
+            let args: Vec<&str> = rule.split_whitespace().collect();
+            let value = |name: &str| {
+                args.windows(2)
+                    .find(|w| w[0] == name)
+                    .map(|w| w[1].split('/').next().unwrap_or(w[1]))
+            };
+            let fwmark = value("fwmark")?.strip_prefix("0x")?;
+            let matches = u32::from_str_radix(fwmark, 16).ok()? == mark
+                && value("lookup")?.parse::<u32>().ok()? == table;
+            matches.then(|| args.join(" "))
+        })
+        .collect()
+}
+
+/// The route delivering marked packets locally, in the output of
+/// `ip route show table <table>`, such as `local default dev lo scope host`.
+fn parse_routes(listing: &str, family: Family) -> Vec<String> {
+    listing
+        .lines()
+        .filter_map(|line| {
+            let args: Vec<&str> = line.split_whitespace().collect();
+            let ours = matches!(args.as_slice(), ["local", dest, "dev", "lo", ..]
+                if *dest == "default" || *dest == local_route(family));
+            ours.then(|| args.join(" "))
+        })
+        .collect()
+}
+
+/// The mark the NAT firewall sets with `--user`, as listed by `iptables -S`.
+fn nat_mark(chain: &str) -> Option<String> {
+    let port = chain.strip_prefix("sshuttle-")?.parse::<u16>().ok()?;
+    Some(format!("{port:#x}/0xffffffff"))
+}
+
+/// Rules and chains found in one table.
+#[derive(Debug, Default)]
+struct Found {
+    chains: Vec<String>,
+    rules: Vec<Vec<String>>,
+}
+
+/// Find our chains, and the rules in other chains that use them, in the output
+/// of `iptables -S`.
+///
+/// `marks` are the marks of NAT chains, used to find the rules that mark packets
+/// from the `--user`; those don't jump to one of our chains. Chains in `live`
+/// belong to running sessions, and are skipped along with the rules using them.
+fn parse_listing(listing: &str, marks: &[String], live: &[String]) -> Found {
+    let mut found = Found::default();
+    let is_stale = |chain: &str| is_sshuttle_chain(chain) && !live.iter().any(|c| c == chain);
+
+    for line in listing.lines() {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        match args.as_slice() {
+            ["-N", chain] if is_stale(chain) => found.chains.push((*chain).to_string()),
+            ["-A", chain, rule @ ..] if !is_sshuttle_chain(chain) => {
+                let jumps_to_us = rule.windows(2).any(|w| w[0] == "-j" && is_stale(w[1]));
+                let marks_for_us = *chain == "OUTPUT"
+                    && rule.contains(&"--uid-owner")
+                    && rule
+                        .windows(2)
+                        .any(|w| w[0] == "--set-xmark" && marks.iter().any(|m| m == w[1]));
+                if jumps_to_us || marks_for_us {
+                    found
+                        .rules
+                        .push(args[1..].iter().map(|s| (*s).to_string()).collect());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    found
+}
+
+/// Delete the rules first, then flush every chain before deleting any, as our
+/// chains jump to each other.
+fn cleanup_commands(family: Family, table: &str, found: &Found, commands: &mut Commands) {
+    for rule in &found.rules {
+        let mut args = vec!["-D"];
+        args.extend(rule.iter().map(String::as_str));
+        commands.ipt(family, table, &args);
+    }
+    for chain in &found.chains {
+        commands.ipt(family, table, &["-F", chain]);
+    }
+    for chain in &found.chains {
+        commands.ipt(family, table, &["-X", chain]);
+    }
+}
+
+/// Delete each rule found, then the local route the TPROXY firewall added to
+/// the table. Other routes in the table are never touched.
+#[rustfmt::skip]
+fn routing_commands(
+    family: Family,
+    mark: u32,
+    table: u32,
+    rules: &[String],
+    routes: &[String],
+    commands: &mut Commands,
+) {
+    let mark = format!("{mark:#x}");
+    let table = table.to_string();
+    for _ in rules {
+        commands.ip_ignore_errors(family, &["rule", "del", "fwmark", &mark, "lookup", &table]);
+    }
+    if !routes.is_empty() {
+        commands.ip_ignore_errors(family, &["route", "del", "local", local_route(family), "dev", "lo", "table", &table]);
+    }
+}
+
+async fn list_ip(family: Family, args: &[&str]) -> Option<String> {
+    let flag = match family {
+        Family::Ipv4 => "-4",
+        Family::Ipv6 => "-6",
+    };
+    let line = Line::new("ip", std::iter::once(flag).chain(args.iter().copied()));
+    match line.run_with_input(None).await {
+        Ok(success) => Some(success.stdout),
+        Err(err) => {
+            log::warn!("Could not run {line}: {}", err.stderr.trim());
+            None
+        }
+    }
+}
+
+async fn list_table(family: Family, table: &str) -> Option<String> {
+    let cmd = match family {
+        Family::Ipv4 => "iptables",
+        Family::Ipv6 => "ip6tables",
+    };
+    match Line::new(cmd, ["-w", "-t", table, "-S"])
+        .run_with_input(None)
+        .await
+    {
+        Ok(success) => Some(success.stdout),
+        Err(err) => {
+            // ip6tables might not be installed, or a table not available.
+            log::warn!("Could not list {cmd} {table} table: {}", err.stderr.trim());
+            None
+        }
+    }
+}
+
+/// Undo the recorded state of sessions that are no longer running, then remove
+/// every chain, and rule jumping to one, created by the NAT and TPROXY firewalls
+/// that is still left, printing what was removed.
+///
+/// The policy routing rules of the TPROXY firewall are removed for `tproxy_mark`
+/// and `tproxy_table`, along with the local route in that table, but only when
+/// such a rule was found. The chains and routing of sessions that are still
+/// running are kept.
+pub async fn cleanup(tproxy_mark: u32, tproxy_table: u32) -> Result<(), CleanupError> {
+    let mut commands = Commands::new();
+    let mut removed = Vec::new();
+
+    for pid in state::recover(Path::new(STATE_DIR)).await? {
+        removed.push(format!("firewall state of process {pid}"));
+    }
+
+    let live = state::live_states(Path::new(STATE_DIR))?;
+    for state in &live {
+        log::info!("Keeping the firewall of running process {}", state.pid);
+    }
+    let keep_routing = state::live_table(&live, &tproxy_table.to_string());
+    let live = live_chains(&live);
+
+    for family in [Family::Ipv4, Family::Ipv6] {
+        let mut marks = Vec::new();
+        for table in TABLES {
+            if let Some(listing) = list_table(family, table).await {
+                let found = parse_listing(&listing, &marks, &live);
+                marks.extend(found.chains.iter().filter_map(|chain| nat_mark(chain)));
+
+                for rule in &found.rules {
+                    removed.push(format!("{family:?} {table}: rule -A {}", rule.join(" ")));
+                }
+                for chain in &found.chains {
+                    removed.push(format!("{family:?} {table}: chain {chain}"));
+                }
+                cleanup_commands(family, table, &found, &mut commands);
+            }
+        }
+
+        if keep_routing {
+            continue;
+        }
+        let table = tproxy_table.to_string();
+        let rules = list_ip(family, &["rule", "show"])
+            .await
+            .map(|listing| parse_rules(&listing, tproxy_mark, tproxy_table))
+            .unwrap_or_default();
+        if rules.is_empty() {
+            continue;
+        }
+        let routes = list_ip(family, &["route", "show", "table", &table])
+            .await
+            .map(|listing| parse_routes(&listing, family))
+            .unwrap_or_default();
+
+        for rule in &rules {
+            removed.push(format!("{family:?} routing rule {rule}"));
+        }
+        for route in &routes {
+            removed.push(format!("{family:?} route {route} table {table}"));
+        }
+        routing_commands(
+            family,
+            tproxy_mark,
+            tproxy_table,
+            &rules,
+            &routes,
+            &mut commands,
+        );
+    }
+
+    commands.run_all().await?;
+
+    if removed.is_empty() {
+        println!("No sshuttle_rust rules found");
+    } else {
+        for item in removed {
+            println!("Removed {item}");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_sshuttle_chain() {
+        assert!(is_sshuttle_chain("sshuttle-1024"));
+        assert!(is_sshuttle_chain("sshuttle-t-tcp-1024"));
+        assert!(is_sshuttle_chain("sshuttle-d-udp-1025"));
+        assert!(!is_sshuttle_chain("sshuttle-x-tcp-1024"));
+        assert!(!is_sshuttle_chain("sshuttle-foo"));
+        assert!(!is_sshuttle_chain("OUTPUT"));
+    }
+
+    #[test]
+    fn test_cleanup_nat() {
+        let nat = "\
+-P PREROUTING ACCEPT
+-P OUTPUT ACCEPT
+-N DOCKER
+-N sshuttle-1024
+-A PREROUTING -m mark --mark 0x400 -j sshuttle-1024
+-A OUTPUT -m mark --mark 0x400 -j sshuttle-1024
+-A OUTPUT -j DOCKER
+-A sshuttle-1024 -m addrtype --dst-type LOCAL -j RETURN
+-A sshuttle-1024 -d 10.0.0.0/8 -p tcp -j REDIRECT --to-ports 1024
+";
+        let mangle = "\
+-P OUTPUT ACCEPT
+-A OUTPUT -m owner --uid-owner 1000 -j MARK --set-xmark 0x400/0xffffffff
+-A OUTPUT -m owner --uid-owner 1000 -j MARK --set-xmark 0x1/0xffffffff
+";
+        let mut commands = Commands::new();
+
+        let found = parse_listing(nat, &[], &[]);
+        cleanup_commands(Family::Ipv4, "nat", &found, &mut commands);
+        let marks: Vec<_> = found.chains.iter().filter_map(|c| nat_mark(c)).collect();
+        let found = parse_listing(mangle, &marks, &[]);
+        cleanup_commands(Family::Ipv4, "mangle", &found, &mut commands);
+
+        let lines: Vec<_> = commands.iter().map(|c| c.line.to_string()).collect();
+        assert_eq!(
+            lines,
+            [
+                "iptables -w -t nat -D PREROUTING -m mark --mark 0x400 -j sshuttle-1024",
+                "iptables -w -t nat -D OUTPUT -m mark --mark 0x400 -j sshuttle-1024",
+                "iptables -w -t nat -F sshuttle-1024",
+                "iptables -w -t nat -X sshuttle-1024",
+                "iptables -w -t mangle -D OUTPUT -m owner --uid-owner 1000 -j MARK --set-xmark 0x400/0xffffffff",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cleanup_tproxy() {
+        let mangle = "\
+-P PREROUTING ACCEPT
+-N sshuttle-d-tcp-1025
+-N sshuttle-m-tcp-1025
+-N sshuttle-t-tcp-1025
+-A PREROUTING -j sshuttle-t-tcp-1025
+-A OUTPUT -j sshuttle-m-tcp-1025
+-A sshuttle-d-tcp-1025 -j MARK --set-xmark 0x1/0xffffffff
+-A sshuttle-t-tcp-1025 -p tcp -m socket -j sshuttle-d-tcp-1025
+";
+        let mut commands = Commands::new();
+        let found = parse_listing(mangle, &[], &[]);
+        cleanup_commands(Family::Ipv6, "mangle", &found, &mut commands);
+
+        let lines: Vec<_> = commands.iter().map(|c| c.line.to_string()).collect();
+        assert_eq!(
+            lines,
+            [
+                "ip6tables -w -t mangle -D PREROUTING -j sshuttle-t-tcp-1025",
+                "ip6tables -w -t mangle -D OUTPUT -j sshuttle-m-tcp-1025",
+                "ip6tables -w -t mangle -F sshuttle-d-tcp-1025",
+                "ip6tables -w -t mangle -F sshuttle-m-tcp-1025",
+                "ip6tables -w -t mangle -F sshuttle-t-tcp-1025",
+                "ip6tables -w -t mangle -X sshuttle-d-tcp-1025",
+                "ip6tables -w -t mangle -X sshuttle-m-tcp-1025",
+                "ip6tables -w -t mangle -X sshuttle-t-tcp-1025",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cleanup_keeps_live_chains() {
+        let nat = "\
+-N sshuttle-1024
+-N sshuttle-1026
+-A OUTPUT -j sshuttle-1024
+-A OUTPUT -j sshuttle-1026
+-A sshuttle-1026 -j RETURN
+";
+        let mangle = "\
+-A OUTPUT -m owner --uid-owner 1000 -j MARK --set-xmark 0x400/0xffffffff
+-A OUTPUT -m owner --uid-owner 1000 -j MARK --set-xmark 0x402/0xffffffff
+";
+        let mut setup = Commands::new();
+        setup.push_with_input(
+            Line::new("iptables-restore", ["-w", "--noflush"]),
+            "*nat\n-N sshuttle-1026\nCOMMIT\n".to_string(),
+        );
+        let live = live_chains(&[State {
+            pid: 1,
+            start_time: None,
+            setup,
+            restore: Commands::new(),
+        }]);
+        assert_eq!(live, ["sshuttle-1026"]);
+
+        let mut commands = Commands::new();
+        let found = parse_listing(nat, &[], &live);
+        cleanup_commands(Family::Ipv4, "nat", &found, &mut commands);
+        let marks: Vec<_> = found.chains.iter().filter_map(|c| nat_mark(c)).collect();
+        let found = parse_listing(mangle, &marks, &live);
+        cleanup_commands(Family::Ipv4, "mangle", &found, &mut commands);
+
+        let lines: Vec<_> = commands.iter().map(|c| c.line.to_string()).collect();
+        assert_eq!(
+            lines,
+            [
+                "iptables -w -t nat -D OUTPUT -j sshuttle-1024",
+                "iptables -w -t nat -F sshuttle-1024",
+                "iptables -w -t nat -X sshuttle-1024",
+                "iptables -w -t mangle -D OUTPUT -m owner --uid-owner 1000 -j MARK --set-xmark 0x400/0xffffffff",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cleanup_routing() {
+        let rules = "\
+0:\tfrom all lookup local
+32764:\tfrom all fwmark 0x1 lookup 100
+32765:\tfrom all fwmark 0x2 lookup 100
+32766:\tfrom all lookup main
+32767:\tfrom all lookup default
+";
+        let rules = parse_rules(rules, 1, 100);
+        assert_eq!(rules, ["from all fwmark 0x1 lookup 100"]);
+
+        let routes = "\
+local default dev lo scope host
+10.1.0.0/16 via 10.0.0.1 dev eth0
+";
+        let routes = parse_routes(routes, Family::Ipv4);
+        assert_eq!(routes, ["local default dev lo scope host"]);
+        let routes6 = parse_routes("local ::/0 dev lo metric 1024 pref medium\n", Family::Ipv6);
+        assert_eq!(routes6, ["local ::/0 dev lo metric 1024 pref medium"]);
+
+        let mut commands = Commands::new();
+        routing_commands(Family::Ipv4, 1, 100, &rules, &routes, &mut commands);
+
+        let lines: Vec<_> = commands.iter().map(|c| c.line.to_string()).collect();
+        assert_eq!(
+            lines,
+            [
+                "ip -4 rule del fwmark 0x1 lookup 100",
+                "ip -4 route del local 0.0.0.0/0 dev lo table 100",
+            ]
+        );
+        assert!(commands.iter().all(|c| c.ignore_errors));
+    }
+}
@@ -1,35 +1,76 @@
+use std::collections::HashMap;
 use std::net::IpAddr;
 use std::net::SocketAddr;
-use std::sync::Arc;
-use std::time::Duration;
+use std::panic::PanicHookInfo;
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex as StdMutex, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use fast_socks5::client::Socks5Stream;
 use fast_socks5::SocksError;
 
 use nix::errno::Errno;
+use nix::unistd::{Gid, Uid};
 use thiserror::Error;
-use tokio::io::copy_bidirectional;
-use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{copy_bidirectional, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
 use tokio::select;
-use tokio::sync::mpsc;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, watch};
 use tokio::task::JoinError;
-use tokio::time::sleep;
+use tokio::time::{error::Elapsed, interval, sleep, sleep_until, timeout, Instant};
 use tokio::{process::Command, spawn, task::JoinHandle};
 
 use crate::command::Error;
+use crate::commands::Commands;
+use crate::daemon::{Daemon, DaemonError};
+use crate::firewall::tproxy::{bind_transparent_udp, recv_from_orig_dst};
 use crate::firewall::{
     Firewall, FirewallConfig, FirewallError, FirewallListenerConfig, FirewallSubnetConfig,
 };
-use crate::network::{ListenerAddr, Subnets};
-use crate::options::FirewallType;
+use crate::helper::{Helper, HelperRequest};
+use crate::mux::{MuxClient, MuxError};
+use crate::network::{ListenerAddr, Ports, Subnet, Subnets};
+use crate::options::{FirewallType, SocksVersion};
+use crate::privileges::{drop_privileges, PrivilegesError, RestoreKeeper};
+use crate::ssh::{self, Remote};
+#[cfg(feature = "builtin-ssh")]
+use crate::ssh_session::{SessionError, SessionOptions, SshSession};
+use crate::state::{self, State, StateError, StateFile, STATE_DIR};
+#[cfg(feature = "builtin-ssh")]
+use crate::upstream::SshUpstream;
+use crate::upstream::{Proxy, ServerUpstream, SocksProxy, SocksUpstream, Upstream, UpstreamError};
 
 pub struct Config {
     pub includes: Subnets,
     pub excludes: Subnets,
-    pub remote: Option<String>,
+    pub remote: Option<Remote>,
+    /// The ssh program and its leading arguments.
+    pub ssh_cmd: Vec<String>,
     pub listen: Vec<ListenerAddr>,
     pub socks_addr: SocketAddr,
+    /// What to connect through when there is no --remote.
+    pub proxy: Proxy,
     pub firewall: FirewallType,
+    pub user: Option<String>,
+    pub tproxy_mark: u32,
+    pub tproxy_table: u32,
+    pub iptables_restore: bool,
+    pub udp_timeout: Duration,
+    pub dns_server: Option<SocketAddr>,
+    pub dns_port: u16,
+    pub socks_ready_timeout: Duration,
+    pub ssh_max_retries: u32,
+    pub hold_connections: bool,
+    pub firewall_helper: Option<String>,
+    /// Switch to this user and group once the firewall is set up.
+    pub run_as: Option<(Uid, Gid)>,
+    /// Run this command on the remote with ssh, and forward everything over its
+    /// stdin and stdout instead of running `ssh -D`.
+    pub server_cmd: Option<String>,
+    /// Connect with the built in ssh client instead of running `ssh -D`.
+    #[cfg(feature = "builtin-ssh")]
+    pub builtin_ssh: Option<SessionOptions>,
 }
 
 #[derive(Error, Debug)]
@@ -52,69 +93,412 @@ pub enum ClientError {
     #[error("Socks5 Error `{0}`")]
     Socks5(#[from] SocksError),
 
-    #[error("Error setting up Ctrl-C handler `{0}`")]
-    CtrlC(#[from] ctrlc::Error),
+    #[error("Timeout Error `{0}`")]
+    Timeout(#[from] Elapsed),
+
+    #[error("Socks server {0} not ready after {1:?}")]
+    SocksNotReady(SocketAddr, Duration),
+
+    #[error("ssh failed, giving up after {0} retries")]
+    SshFailed(u32),
+
+    #[error("Watch Error `{0}`")]
+    Watch(#[from] watch::error::RecvError),
+
+    #[error("Daemon Error `{0}`")]
+    Daemon(#[from] DaemonError),
+
+    #[error("State Error `{0}`")]
+    State(#[from] StateError),
+
+    #[error("Firewall helper error `{0}`")]
+    Helper(String),
+
+    #[error("Privileges Error `{0}`")]
+    Privileges(#[from] PrivilegesError),
+
+    #[error("Upstream Error `{0}`")]
+    Upstream(#[from] UpstreamError),
+
+    #[error("Mux Error `{0}`")]
+    Mux(#[from] MuxError),
+
+    #[cfg(feature = "builtin-ssh")]
+    #[error("ssh Session Error `{0}`")]
+    Session(#[from] SessionError),
 }
 
-pub async fn main(config: &Config) -> Result<(), ClientError> {
-    let (control_tx, control_rx) = mpsc::channel(1);
+/// The commands to set up the firewall, and the commands to restore it.
+pub fn firewall_commands(
+    firewall: &(dyn Firewall + Send + Sync),
+    firewall_config: &FirewallConfig,
+    iptables_restore: bool,
+) -> Result<(Commands, Commands), FirewallError> {
+    let mut setup_commands = firewall.setup_firewall(firewall_config)?;
+    let shutdown_commands = firewall.restore_firewall(firewall_config)?;
 
-    let tx_clone = control_tx.clone();
-    ctrlc::set_handler(move || {
-        #[allow(clippy::expect_used)]
-        tx_clone
-            .blocking_send(Message::Shutdown)
-            .expect("Could not send signal on channel.");
-    })?;
+    if iptables_restore {
+        setup_commands = setup_commands.to_iptables_restore()?;
+    }
+
+    Ok((setup_commands, shutdown_commands))
+}
+
+/// Print the commands that would set up and restore the firewall, without running them.
+pub fn dry_run(config: &Config) -> Result<(), FirewallError> {
+    let firewall = get_firewall(config.firewall, config.tproxy_mark, config.tproxy_table);
+    let (setup_commands, shutdown_commands) = firewall_commands(
+        firewall.as_ref(),
+        &get_firewall_config(config),
+        config.iptables_restore,
+    )?;
+
+    println!("# Set up firewall");
+    print!("{}", setup_commands.to_shell_script());
+    println!();
+    println!("# Restore firewall");
+    print!("{}", shutdown_commands.to_shell_script());
+    Ok(())
+}
+
+/// `keeper` restores the firewall instead of us, as it must after `--setuid`.
+pub async fn main(
+    config: &Config,
+    daemon: Option<&mut Daemon>,
+    keeper: Option<RestoreKeeper>,
+) -> Result<(), ClientError> {
+    let (control_tx, control_rx) = mpsc::channel(1);
+    handle_signals(control_tx.clone())?;
 
-    let firewall_config = get_firewall_config(config);
-    let firewall = get_firewall(config);
-    let setup_commands = firewall.setup_firewall(&firewall_config)?;
-    let shutdown_commands = firewall.restore_firewall(&firewall_config)?;
+    let firewall = get_firewall(config.firewall, config.tproxy_mark, config.tproxy_table);
+    let mut firewall_config = get_firewall_config(config);
 
-    log::info!("Setting up firewall {:#?}", setup_commands);
-    setup_commands.run_all().await?;
+    let mut firewall_setup = if let Some(program) = &config.firewall_helper {
+        // The helper only forwards the traffic of the user that runs it.
+        firewall_config.filter_from_user = Some(Uid::current().to_string());
+        FirewallSetup::Helper {
+            program: program.clone(),
+            request: HelperRequest {
+                firewall: config.firewall,
+                tproxy_mark: config.tproxy_mark,
+                tproxy_table: config.tproxy_table,
+                iptables_restore: config.iptables_restore,
+                config: firewall_config,
+            },
+            helper: None,
+        }
+    } else {
+        let (setup_commands, shutdown_commands) =
+            firewall_commands(firewall.as_ref(), &firewall_config, config.iptables_restore)?;
+        let (setup_commands, restore) =
+            guard_firewall(setup_commands, shutdown_commands, keeper).await?;
+        FirewallSetup::Local {
+            setup_commands,
+            restore,
+        }
+    };
 
     log::debug!("run_everything");
-    let client_result = run_everything(config, firewall, control_tx, control_rx).await;
+    let client_result = run_everything(
+        config,
+        firewall,
+        &mut firewall_setup,
+        daemon,
+        control_tx,
+        control_rx,
+    )
+    .await;
     if let Err(err) = &client_result {
         log::error!("run_everything error: {err}");
     } else {
         log::debug!("run_everything exited normally");
     }
 
-    log::info!("Restoring firewall{:#?}", shutdown_commands);
-    let shutdown_result = shutdown_commands.run_all().await;
-    if let Err(err) = &shutdown_result {
-        log::error!("Error restoring firewall: {err}");
-    } else {
-        log::debug!("Restored firewall");
-    }
+    let shutdown_result = firewall_setup.restore().await;
 
     client_result?;
     shutdown_result?;
     Ok(())
 }
 
+/// Turn termination signals into a shutdown request.
+fn handle_signals(control_tx: mpsc::Sender<Message>) -> Result<(), std::io::Error> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut quit = signal(SignalKind::quit())?;
+    let mut hangup = signal(SignalKind::hangup())?;
+
+    spawn(async move {
+        loop {
+            let name = select! {
+                _ = terminate.recv() => "SIGTERM",
+                _ = interrupt.recv() => "SIGINT",
+                _ = quit.recv() => "SIGQUIT",
+                _ = hangup.recv() => "SIGHUP",
+            };
+            log::info!("{name} received, shutting down");
+            if control_tx.send(Message::Shutdown).await.is_err() {
+                break;
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Record the commands in a state file, and make sure the firewall is restored
+/// however we exit from now on, by `keeper` when given.
+///
+/// Returns the commands to set up the firewall, and the guard that restores it.
+pub async fn guard_firewall(
+    mut setup_commands: Commands,
+    shutdown_commands: Commands,
+    keeper: Option<RestoreKeeper>,
+) -> Result<(Commands, RestoreGuard), ClientError> {
+    // Undo whatever a crashed session left behind before adding our own rules.
+    let state_dir = Path::new(STATE_DIR);
+    state::recover(state_dir).await?;
+    let pid = std::process::id();
+    // Another session may already have set up the routing we share.
+    state::skip_shared_routing(&mut setup_commands, state_dir, pid);
+    let state = State {
+        pid,
+        start_time: state::start_time(pid),
+        setup: setup_commands,
+        restore: shutdown_commands,
+    };
+    let state_file = StateFile::create(state_dir, &state)?;
+
+    let restore = RestoreGuard::new(match keeper {
+        // The keeper finds the restore commands in the state file.
+        Some(keeper) => Restore::Keeper(keeper),
+        None => Restore::Commands(state.restore, state_file),
+    });
+    Ok((state.setup, restore))
+}
+
+/// How the firewall is set up once the socks server is ready.
+#[allow(clippy::large_enum_variant)]
+enum FirewallSetup {
+    /// Run the commands in this process.
+    Local {
+        setup_commands: Commands,
+        restore: RestoreGuard,
+    },
+    /// Have a privileged helper process run them. The helper restores the
+    /// firewall when we exit, however we exit.
+    Helper {
+        program: String,
+        request: HelperRequest,
+        helper: Option<Helper>,
+    },
+}
+
+impl FirewallSetup {
+    async fn setup(&mut self) -> Result<(), ClientError> {
+        match self {
+            FirewallSetup::Local { setup_commands, .. } => {
+                log::info!("Setting up firewall {:#?}", setup_commands);
+                setup_commands.run_all().await.map_err(|err| {
+                    log::error!("Error setting up firewall, rolling back: {err}");
+                    err
+                })?;
+            }
+            FirewallSetup::Helper {
+                program,
+                request,
+                helper,
+            } => {
+                *helper = Some(Helper::start(program, request).await?);
+            }
+        }
+        Ok(())
+    }
+
+    async fn restore(self) -> Result<(), ClientError> {
+        match self {
+            FirewallSetup::Local { restore, .. } => restore.restore().await,
+            FirewallSetup::Helper {
+                helper: Some(helper),
+                ..
+            } => helper.stop().await,
+            FirewallSetup::Helper { helper: None, .. } => Ok(()),
+        }
+    }
+}
+
+/// How `RestoreGuard` restores the firewall.
+enum Restore {
+    /// Run the commands, then remove the state file.
+    Commands(Commands, StateFile),
+    /// Have the keeper do it, as we dropped the privileges it takes.
+    Keeper(RestoreKeeper),
+}
+
+/// Runs the restore commands exactly once, either explicitly with `restore`, when
+/// dropped, or from the panic hook.
+///
+/// The state file is only removed if the restore commands succeed, so that
+/// `--cleanup` can try again.
+pub struct RestoreGuard {
+    restore: Arc<StdMutex<Option<Restore>>>,
+    /// Puts back the panic hook that ours replaced.
+    remove_hook: Option<Box<dyn FnOnce() + Send + Sync>>,
+}
+
+fn take(restore: &StdMutex<Option<Restore>>) -> Option<Restore> {
+    restore
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .take()
+}
+
+impl RestoreGuard {
+    /// A panic anywhere, including in a spawned task, restores the firewall and
+    /// exits. The hook only holds a weak reference, the guard owns the restore.
+    fn new(restore: Restore) -> Self {
+        let restore = Arc::new(StdMutex::new(Some(restore)));
+        let weak = Arc::downgrade(&restore);
+        let previous_hook: Arc<dyn Fn(&PanicHookInfo) + Sync + Send> =
+            Arc::from(std::panic::take_hook());
+        let hook = previous_hook.clone();
+        std::panic::set_hook(Box::new(move |info| {
+            hook(info);
+            if let Some(restore) = weak.upgrade().and_then(|restore| take(&restore)) {
+                log::error!("Panic, restoring firewall: {info}");
+                Self::restore_blocking(restore);
+                std::process::exit(101);
+            }
+        }));
+        Self {
+            restore,
+            remove_hook: Some(Box::new(move || {
+                std::panic::set_hook(Box::new(move |info| previous_hook(info)));
+            })),
+        }
+    }
+
+    /// Once the firewall is restored there is nothing left for our hook to do.
+    fn remove_hook(&mut self) {
+        // The hook can't be changed while panicking, it does nothing by now anyway.
+        if !std::thread::panicking() {
+            if let Some(remove_hook) = self.remove_hook.take() {
+                remove_hook();
+            }
+        }
+    }
+
+    pub async fn restore(mut self) -> Result<(), ClientError> {
+        let result = match take(&self.restore) {
+            Some(restore) => Self::run(restore).await,
+            None => Ok(()),
+        };
+        self.remove_hook();
+        result
+    }
+
+    async fn run(restore: Restore) -> Result<(), ClientError> {
+        match restore {
+            Restore::Commands(mut commands, state_file) => {
+                state::skip_shared_routing(&mut commands, state_file.dir(), std::process::id());
+                log::info!("Restoring firewall{:#?}", commands);
+                if let Err(err) = commands.run_all().await {
+                    log::error!("Error restoring firewall: {err}");
+                    return Err(err.into());
+                }
+                state_file.remove();
+            }
+            Restore::Keeper(keeper) => {
+                log::info!("Restoring firewall with the privileged keeper process");
+                tokio::task::spawn_blocking(move || keeper.restore()).await??;
+            }
+        }
+        log::debug!("Restored firewall");
+        Ok(())
+    }
+
+    fn restore_blocking(restore: Restore) {
+        match restore {
+            Restore::Commands(commands, state_file) => {
+                Self::run_blocking(commands, state_file);
+            }
+            Restore::Keeper(keeper) => match keeper.restore() {
+                Ok(()) => log::debug!("Restored firewall"),
+                Err(err) => log::error!("Error restoring firewall: {err}"),
+            },
+        }
+    }
+
+    fn run_blocking(mut commands: Commands, state_file: StateFile) {
+        state::skip_shared_routing(&mut commands, state_file.dir(), std::process::id());
+        log::info!("Restoring firewall{:#?}", commands);
+        // We might be on a runtime thread, which can't block on another
+        // runtime, so run the commands on a thread of their own.
+        let result = std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .map_err(|err| err.to_string())?;
+            runtime
+                .block_on(commands.run_all())
+                .map_err(|err| err.to_string())
+        })
+        .join();
+        match result {
+            Ok(Ok(())) => {
+                state_file.remove();
+                log::debug!("Restored firewall");
+            }
+            Ok(Err(err)) => log::error!("Error restoring firewall: {err}"),
+            Err(_) => log::error!("Error restoring firewall: thread panicked"),
+        }
+    }
+}
+
+impl Drop for RestoreGuard {
+    fn drop(&mut self) {
+        if let Some(restore) = take(&self.restore) {
+            Self::restore_blocking(restore);
+        }
+        self.remove_hook();
+    }
+}
+
 async fn run_everything(
     config: &Config,
     firewall: Box<dyn Firewall + Send + Sync>,
+    firewall_setup: &mut FirewallSetup,
+    daemon: Option<&mut Daemon>,
     control_tx: mpsc::Sender<Message>,
     mut control_rx: mpsc::Receiver<Message>,
 ) -> Result<(), ClientError> {
-    let client = run_client(config, firewall);
-
     if let Some(remote) = &config.remote {
         // ssh shutdown sequence with ssh:
-        // ctrlc handler sends signal to control_tx.
+        // signal handler sends Shutdown to control_tx.
         // ssh handler receives event from control_rx.
         // ssh handler kills ssh.
         // ssh_handle completes, and the select finishes.
         // we return.
-        let c = run_ssh(config, remote.to_string(), control_rx).await?;
+        let (ready_tx, mut ready_rx) = watch::channel(false);
+        let (c, upstream) = start_ssh(config, remote.clone(), control_rx, ready_tx).await?;
         let ssh_handle = c.handle;
 
         tokio::pin!(ssh_handle);
+
+        // Don't redirect anything until ssh is ready to accept it.
+        select! {
+            res = &mut ssh_handle => {
+                log::info!("ssh_handle finished before socks server was ready");
+                res??;
+                return Ok(());
+            },
+            res = wait_until_ready(&mut ready_rx) => res?,
+        }
+
+        // Firewall rules stay in place while ssh is restarted, but don't catch ssh
+        // itself, as the remote was resolved once and is excluded.
+        setup_firewall(firewall_setup, daemon).await?;
+        let hold = config.hold_connections.then_some(ready_rx);
+        let client = run_client(config, firewall, upstream, hold);
         tokio::pin!(client);
 
         select! {
@@ -135,9 +519,22 @@ async fn run_everything(
         _ = control_tx.send(Message::Shutdown).await;
     } else {
         // ssh shutdown sequence without ssh:
-        // ctrlc handler sends signal to control_tx.
+        // signal handler sends Shutdown to control_tx.
         // the select finishes.
         // we return.
+        if let Proxy::Socks(socks_proxy) = &config.proxy {
+            select! {
+                res = wait_for_socks(socks_proxy, config.socks_ready_timeout) => res?,
+                Some(_) = control_rx.recv() => {
+                    log::info!("control_rx shutdown requested");
+                    return Ok(());
+                }
+            }
+        }
+
+        setup_firewall(firewall_setup, daemon).await?;
+        let client = run_client(config, firewall, config.proxy.upstream(), None);
+
         select! {
             res = client => {
                 log::info!("client finished");
@@ -152,6 +549,82 @@ async fn run_everything(
     Ok(())
 }
 
+async fn setup_firewall(
+    firewall_setup: &mut FirewallSetup,
+    daemon: Option<&mut Daemon>,
+) -> Result<(), ClientError> {
+    firewall_setup.setup().await?;
+
+    // Everything is working, so it is safe to detach.
+    if let Some(daemon) = daemon {
+        daemon.notify_ready()?;
+    }
+    Ok(())
+}
+
+/// Delay between the first two attempts to contact the socks server, doubled after each failure.
+const SOCKS_PROBE_DELAY: Duration = Duration::from_millis(100);
+const SOCKS_PROBE_MAX_DELAY: Duration = Duration::from_secs(2);
+
+/// Wait until the socks server accepts connections, or give up after `ready_timeout`.
+///
+/// A server that is up but won't accept our credentials fails straight away.
+async fn wait_for_socks(proxy: &SocksProxy, ready_timeout: Duration) -> Result<(), ClientError> {
+    let socks_addr = proxy.addr;
+    let probe = async {
+        let mut delay = SOCKS_PROBE_DELAY;
+        loop {
+            match probe_socks(proxy).await {
+                Ok(()) => break Ok(()),
+                Err(err @ ClientError::Upstream(_)) => break Err(err),
+                Err(err) => {
+                    log::debug!("socks server {socks_addr} not ready yet: {err}");
+                    sleep(delay).await;
+                    delay = (delay * 2).min(SOCKS_PROBE_MAX_DELAY);
+                }
+            }
+        }
+    };
+
+    timeout(ready_timeout, probe)
+        .await
+        .map_err(|_| ClientError::SocksNotReady(socks_addr, ready_timeout))??;
+    log::info!("socks server {socks_addr} is ready");
+    Ok(())
+}
+
+/// Check the socks server is up by offering it the authentication methods we can use.
+async fn probe_socks(proxy: &SocksProxy) -> Result<(), ClientError> {
+    let mut stream = TcpStream::connect(proxy.addr).await?;
+    // SOCKS4 has no greeting, only the request itself.
+    if matches!(proxy.version, SocksVersion::Socks4) {
+        return Ok(());
+    }
+
+    // "No authentication", and "username/password" if we have a username.
+    let greeting: &[u8] = if proxy.user.is_some() {
+        &[0x05, 0x02, 0x00, 0x02]
+    } else {
+        &[0x05, 0x01, 0x00]
+    };
+    stream.write_all(greeting).await?;
+
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    match reply {
+        [0x05, 0x00] => Ok(()),
+        [0x05, 0x02] if proxy.user.is_some() => Ok(()),
+        [0x05, method @ (0x02 | 0xff)] => {
+            Err(proxy.error(SocksError::AuthMethodUnacceptable(vec![method])))
+        }
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("unexpected socks greeting reply {reply:?}"),
+        )
+        .into()),
+    }
+}
+
 // async fn read_tcpstream(
 //     stream: &mut TcpStream,
 //     buf: &mut [u8],
@@ -184,14 +657,49 @@ async fn run_everything(
 //     }
 // }
 
-fn get_firewall(config: &Config) -> Box<dyn Firewall + Send + Sync> {
-    match config.firewall {
+pub fn get_firewall(
+    firewall: FirewallType,
+    tproxy_mark: u32,
+    tproxy_table: u32,
+) -> Box<dyn Firewall + Send + Sync> {
+    match firewall {
         FirewallType::Nat => Box::new(crate::firewall::nat::NatFirewall::new()),
-        FirewallType::TProxy => Box::new(crate::firewall::tproxy::TProxyFirewall::new()),
+        FirewallType::TProxy => Box::new(crate::firewall::tproxy::TProxyFirewall::new(
+            tproxy_mark,
+            tproxy_table,
+        )),
+        FirewallType::Nftables => Box::new(crate::firewall::nftables::NftablesFirewall::new()),
     }
 }
 
+/// The address connections to the upstream go to: the resolved remote, or the
+/// socks server or HTTP proxy when there is no remote.
+fn upstream_addr(config: &Config) -> Option<SocketAddr> {
+    match (&config.remote, &config.proxy) {
+        (Some(remote), _) => remote.resolved.as_ref().map(|resolved| resolved.addr),
+        (None, Proxy::Socks(proxy)) => Some(proxy.addr),
+        (None, Proxy::Http(proxy)) => proxy.resolved,
+        (None, Proxy::Direct) => None,
+    }
+}
+
+/// The subnets not to redirect: the upstream, so we can reconnect to it while the
+/// firewall is up, followed by those asked for.
+fn get_excludes(config: &Config) -> Subnets {
+    let mut excludes: Vec<Subnet> = upstream_addr(config)
+        .into_iter()
+        .map(|addr| Subnet {
+            address: addr.ip(),
+            cidr: if addr.is_ipv4() { 32 } else { 128 },
+            ports: Ports::Single(addr.port()),
+        })
+        .collect();
+    excludes.extend(config.excludes.0.iter().cloned());
+    Subnets::new(excludes)
+}
+
 fn get_firewall_config(config: &Config) -> FirewallConfig {
+    let excludes = get_excludes(config);
     let familys = config
         .listen
         .iter()
@@ -200,18 +708,20 @@ fn get_firewall_config(config: &Config) -> FirewallConfig {
                 enable: true,
                 listener: addr.clone(),
                 includes: config.includes.ipv4(),
-                excludes: config.excludes.ipv4(),
+                excludes: excludes.ipv4(),
             }),
             IpAddr::V6(_) => FirewallListenerConfig::Ipv6(FirewallSubnetConfig {
                 enable: true,
                 listener: addr.clone(),
                 includes: config.includes.ipv6(),
-                excludes: config.excludes.ipv6(),
+                excludes: excludes.ipv6(),
             }),
         })
         .collect();
     FirewallConfig {
-        filter_from_user: None,
+        filter_from_user: config.user.clone(),
+        dns_port: config.dns_server.map(|_| config.dns_port),
+        dns_server: config.dns_server,
         listeners: familys,
     }
 }
@@ -223,70 +733,353 @@ enum Message {
 
 struct Task {
     // tx: mpsc::Sender<Message>,
-    handle: JoinHandle<Result<(), std::io::Error>>,
+    handle: JoinHandle<Result<(), ClientError>>,
+}
+
+enum SshExit {
+    Shutdown,
+    Failed,
+}
+
+/// Delay before the first restart of ssh, doubled after each failed retry.
+const SSH_RETRY_DELAY: Duration = Duration::from_secs(1);
+const SSH_RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Exponential backoff with up to 50% jitter, so clients sharing a server don't
+/// all reconnect at once.
+fn backoff_delay(retries: u32) -> Duration {
+    let delay = SSH_RETRY_DELAY
+        .saturating_mul(1 << retries.saturating_sub(1).min(16))
+        .min(SSH_RETRY_MAX_DELAY);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.subsec_nanos());
+    delay + delay / 2 / 1000 * (nanos % 1000)
+}
+
+async fn wait_until_ready(ready: &mut watch::Receiver<bool>) -> Result<(), ClientError> {
+    while !*ready.borrow_and_update() {
+        ready.changed().await?;
+    }
+    Ok(())
+}
+
+/// Start ssh, or the built in ssh client if it was asked for.
+async fn start_ssh(
+    config: &Config,
+    remote: Remote,
+    rx: mpsc::Receiver<Message>,
+    ready_tx: watch::Sender<bool>,
+) -> Result<(Task, Arc<dyn Upstream>), ClientError> {
+    if let Some(server_cmd) = &config.server_cmd {
+        let (mux_tx, mux_rx) = watch::channel(None);
+        let task = run_server(config, server_cmd.clone(), remote, rx, ready_tx, mux_tx);
+        return Ok((task, Arc::new(ServerUpstream(mux_rx))));
+    }
+
+    #[cfg(feature = "builtin-ssh")]
+    if let Some(options) = &config.builtin_ssh {
+        let (session_tx, session_rx) = watch::channel(None);
+        let task = run_session(config, options.clone(), remote, rx, ready_tx, session_tx);
+        return Ok((task, Arc::new(SshUpstream(session_rx))));
+    }
+
+    let task = run_ssh(config, remote, rx, ready_tx).await?;
+    Ok((
+        task,
+        Arc::new(SocksUpstream(SocksProxy::new(config.socks_addr))),
+    ))
+}
+
+/// Wait before restarting ssh, returns false if we are shutting down instead.
+async fn wait_to_restart(
+    retries: &mut u32,
+    max_retries: u32,
+    rx: &mut mpsc::Receiver<Message>,
+    ready_tx: &watch::Sender<bool>,
+) -> Result<bool, ClientError> {
+    // Only count failures since ssh was last working.
+    if ready_tx.send_replace(false) {
+        *retries = 0;
+    }
+    if *retries >= max_retries {
+        return Err(ClientError::SshFailed(*retries));
+    }
+    *retries += 1;
+
+    let delay = backoff_delay(*retries);
+    log::warn!("restarting ssh in {delay:?} (retry {retries} of {max_retries})");
+    select! {
+        msg = rx.recv() => {
+            log::info!("ssh shutdown requested while waiting to restart: {msg:?}");
+            Ok(false)
+        }
+        () = sleep(delay) => Ok(true),
+    }
 }
 
 async fn run_ssh(
     config: &Config,
-    remote: String,
+    remote: Remote,
     mut rx: mpsc::Receiver<Message>,
+    ready_tx: watch::Sender<bool>,
 ) -> Result<Task, ClientError> {
     let socks = config.socks_addr;
+    let ready_timeout = config.socks_ready_timeout;
+    let max_retries = config.ssh_max_retries;
+    let run_as = config.run_as;
+    let ssh_cmd = config.ssh_cmd.clone();
 
-    let handle: JoinHandle<Result<(), std::io::Error>> = spawn(async move {
-        let args = vec![
-            "-D".to_string(),
-            socks.to_string(),
-            "-N".to_string(),
-            remote,
-        ];
+    let handle: JoinHandle<Result<(), ClientError>> = spawn(async move {
+        let socks_arg = socks.to_string();
 
-        let mut child = Command::new("ssh").args(args).spawn()?;
+        let mut retries = 0;
+        loop {
+            // Use the ssh agent and known_hosts of the user we switch to.
+            let command = ssh::command(&ssh_cmd, &["-D", &socks_arg, "-N"], &remote, run_as);
 
-        tokio::select! {
-            msg = rx.recv() => {
-                log::info!("ssh shutdown requested, killing child ssh: {msg:?}");
-                child.kill().await?;
-                Ok(())
+            match run_ssh_once(command, socks, ready_timeout, &mut rx, &ready_tx).await? {
+                SshExit::Shutdown => return Ok(()),
+                SshExit::Failed => {}
             }
-            status = child.wait() => {
-                match status {
-                    Ok(rc) => {
-                        if rc.success() {
-                            log::error!("ssh exited with rc: {rc}");
-                            Ok(())
-                        } else {
-                            log::info!("ssh exited with rc: {rc}");
-                            Err(std::io::Error::new(std::io::ErrorKind::Other, "ssh failed"))
+
+            if !wait_to_restart(&mut retries, max_retries, &mut rx, &ready_tx).await? {
+                return Ok(());
+            }
+        }
+    });
+
+    Ok(Task { handle })
+}
+
+/// Keep a session of the built in ssh client connected, publishing it on `session_tx`.
+#[cfg(feature = "builtin-ssh")]
+fn run_session(
+    config: &Config,
+    options: SessionOptions,
+    remote: Remote,
+    mut rx: mpsc::Receiver<Message>,
+    ready_tx: watch::Sender<bool>,
+    session_tx: watch::Sender<Option<SshSession>>,
+) -> Task {
+    let max_retries = config.ssh_max_retries;
+
+    let handle: JoinHandle<Result<(), ClientError>> = spawn(async move {
+        let mut retries = 0;
+        loop {
+            let result = select! {
+                msg = rx.recv() => {
+                    log::info!("ssh shutdown requested while connecting: {msg:?}");
+                    return Ok(());
+                }
+                res = SshSession::start(remote.clone(), &options) => res,
+            };
+
+            match result {
+                Ok((session, closed)) => {
+                    session_tx.send_replace(Some(session));
+                    ready_tx.send_replace(true);
+                    select! {
+                        msg = rx.recv() => {
+                            log::info!("ssh shutdown requested, closing session: {msg:?}");
+                            session_tx.send_replace(None);
+                            return Ok(());
+                        }
+                        res = closed => {
+                            let err = res.map_or_else(|_| SessionError::Closed.to_string(), |err| err.to_string());
+                            log::error!("ssh session to {remote} ended: {err}");
                         }
                     }
-                    Err(err) => {
-                        log::error!("ssh wait failed: {err}");
-                        Err(err)
-                    }
+                    session_tx.send_replace(None);
                 }
+                Err(err) => log::error!("ssh session to {remote} failed: {err}"),
+            }
+
+            if !wait_to_restart(&mut retries, max_retries, &mut rx, &ready_tx).await? {
+                return Ok(());
             }
         }
     });
 
-    Ok(Task { handle })
+    Task { handle }
+}
+
+/// Keep `sshuttle_rust server` running on the remote, publishing the connection to it on `mux_tx`.
+fn run_server(
+    config: &Config,
+    server_cmd: String,
+    remote: Remote,
+    mut rx: mpsc::Receiver<Message>,
+    ready_tx: watch::Sender<bool>,
+    mux_tx: watch::Sender<Option<MuxClient>>,
+) -> Task {
+    let ready_timeout = config.socks_ready_timeout;
+    let max_retries = config.ssh_max_retries;
+    let run_as = config.run_as;
+    let ssh_cmd = config.ssh_cmd.clone();
+
+    let handle: JoinHandle<Result<(), ClientError>> = spawn(async move {
+        let mut retries = 0;
+        loop {
+            let mut command = ssh::command(&ssh_cmd, &[], &remote, run_as);
+            command
+                .arg(&server_cmd)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped());
+
+            let exit = run_server_once(command, ready_timeout, &mut rx, &ready_tx, &mux_tx).await;
+            mux_tx.send_replace(None);
+            match exit? {
+                SshExit::Shutdown => return Ok(()),
+                SshExit::Failed => {}
+            }
+
+            if !wait_to_restart(&mut retries, max_retries, &mut rx, &ready_tx).await? {
+                return Ok(());
+            }
+        }
+    });
+
+    Task { handle }
+}
+
+async fn run_server_once(
+    mut command: Command,
+    ready_timeout: Duration,
+    rx: &mut mpsc::Receiver<Message>,
+    ready_tx: &watch::Sender<bool>,
+    mux_tx: &watch::Sender<Option<MuxClient>>,
+) -> Result<SshExit, ClientError> {
+    let mut child = command.spawn()?;
+    let no_pipe = || std::io::Error::new(std::io::ErrorKind::BrokenPipe, "no pipe to ssh");
+    let stdin = child.stdin.take().ok_or_else(no_pipe)?;
+    let stdout = child.stdout.take().ok_or_else(no_pipe)?;
+
+    let start = timeout(ready_timeout, MuxClient::start(stdout, stdin));
+    let reader = select! {
+        msg = rx.recv() => {
+            log::info!("ssh shutdown requested, killing child ssh: {msg:?}");
+            child.kill().await?;
+            return Ok(SshExit::Shutdown);
+        }
+        status = child.wait() => {
+            log::error!("ssh exited with rc: {} before the server was ready", status?);
+            return Ok(SshExit::Failed);
+        }
+        res = start => match res {
+            Ok(Ok((mux, reader))) => {
+                mux_tx.send_replace(Some(mux));
+                reader
+            }
+            Ok(Err(err)) => {
+                log::error!("server failed to start: {err}, killing child ssh");
+                child.kill().await?;
+                return Ok(SshExit::Failed);
+            }
+            Err(_) => {
+                log::error!("server not ready after {ready_timeout:?}, killing child ssh");
+                child.kill().await?;
+                return Ok(SshExit::Failed);
+            }
+        },
+    };
+    log::info!("server is ready");
+    ready_tx.send_replace(true);
+
+    select! {
+        msg = rx.recv() => {
+            log::info!("ssh shutdown requested, killing child ssh: {msg:?}");
+            child.kill().await?;
+            Ok(SshExit::Shutdown)
+        }
+        status = child.wait() => {
+            log::error!("ssh exited with rc: {}", status?);
+            Ok(SshExit::Failed)
+        }
+        res = reader => {
+            match res? {
+                Ok(()) => log::error!("server closed the connection, killing child ssh"),
+                Err(err) => log::error!("connection to server failed: {err}, killing child ssh"),
+            }
+            child.kill().await?;
+            Ok(SshExit::Failed)
+        }
+    }
+}
+
+async fn run_ssh_once(
+    mut command: Command,
+    socks: SocketAddr,
+    ready_timeout: Duration,
+    rx: &mut mpsc::Receiver<Message>,
+    ready_tx: &watch::Sender<bool>,
+) -> Result<SshExit, ClientError> {
+    let mut child = command.spawn()?;
+
+    let socks = SocksProxy::new(socks);
+    let ready = wait_for_socks(&socks, ready_timeout);
+    tokio::pin!(ready);
+    let mut waiting = true;
+
+    loop {
+        select! {
+            msg = rx.recv() => {
+                log::info!("ssh shutdown requested, killing child ssh: {msg:?}");
+                child.kill().await?;
+                return Ok(SshExit::Shutdown);
+            }
+            status = child.wait() => {
+                let rc = status.map_err(|err| {
+                    log::error!("ssh wait failed: {err}");
+                    err
+                })?;
+                log::error!("ssh exited with rc: {rc}");
+                return Ok(SshExit::Failed);
+            }
+            res = &mut ready, if waiting => {
+                waiting = false;
+                if let Err(err) = res {
+                    log::error!("{err}, killing child ssh");
+                    child.kill().await?;
+                    return Ok(SshExit::Failed);
+                }
+                ready_tx.send_replace(true);
+            }
+        }
+    }
 }
 
 async fn run_client(
     config: &Config,
     firewall: Box<dyn Firewall + Send + Sync>,
+    upstream: Arc<dyn Upstream>,
+    hold: Option<watch::Receiver<bool>>,
 ) -> Result<Task, ClientError> {
-    let socks_addr = config.socks_addr;
     let listen = config.listen.clone();
+    let udp_timeout = config.udp_timeout;
+    // Only tproxy tells us where the query was originally going.
+    let transparent = matches!(config.firewall, FirewallType::TProxy);
 
     let firewall: Arc<dyn Firewall + Send + Sync> = Arc::from(firewall);
     for l_addr in listen {
         match l_addr.protocol {
-            crate::network::Protocol::Tcp => listen_tcp(&firewall, l_addr, socks_addr).await?,
-            crate::network::Protocol::Udp => {}
+            crate::network::Protocol::Tcp => {
+                if let Some(dns_server) = config.dns_server {
+                    let dns_addr = SocketAddr::new(l_addr.ip(), config.dns_port);
+                    listen_dns(&firewall, dns_addr, &upstream, dns_server, transparent).await?;
+                }
+                listen_tcp(&firewall, l_addr, &upstream, hold.clone()).await?;
+            }
+            crate::network::Protocol::Udp => {
+                listen_udp(&firewall, l_addr, &upstream, udp_timeout).await?;
+            }
         }
     }
 
+    if let Some((uid, gid)) = config.run_as {
+        drop_privileges(uid, gid)?;
+    }
+
     loop {
         sleep(Duration::from_secs(60)).await;
     }
@@ -295,9 +1088,11 @@ async fn run_client(
 async fn listen_tcp(
     firewall: &Arc<dyn Firewall + Send + Sync>,
     l_addr: ListenerAddr,
-    socks_addr: SocketAddr,
+    upstream: &Arc<dyn Upstream>,
+    hold: Option<watch::Receiver<bool>>,
 ) -> Result<(), ClientError> {
     let firewall = Arc::clone(firewall);
+    let upstream = Arc::clone(upstream);
     let listener = TcpListener::bind(l_addr.addr).await?;
     firewall.setup_tcp_listener(&listener)?;
 
@@ -309,8 +1104,10 @@ async fn listen_tcp(
                 Err(err) => break Err(err.into()),
             };
             let l_addr = l_addr.clone();
+            let upstream = Arc::clone(&upstream);
+            let hold = hold.clone();
             tokio::spawn(async move {
-                handle_tcp_client(socket, &l_addr, socks_addr, firewall)
+                handle_tcp_client(socket, &l_addr, &upstream, firewall, hold)
                     .await
                     .map_err(|err| {
                         log::error!("handle_tcp_client failed: {err}");
@@ -326,8 +1123,9 @@ async fn listen_tcp(
 async fn handle_tcp_client(
     socket: TcpStream,
     l_addr: &ListenerAddr,
-    socks_addr: SocketAddr,
+    upstream: &Arc<dyn Upstream>,
     firewall: Arc<dyn Firewall + Send + Sync>,
+    hold: Option<watch::Receiver<bool>>,
 ) -> Result<(), ClientError> {
     let mut local = socket;
     let local_addr = local.peer_addr()?;
@@ -336,15 +1134,14 @@ async fn handle_tcp_client(
     let remote_addr = firewall.get_dst_addr(&local)?;
     log::info!("{l_addr} got connection from {local_addr} to {remote_addr}");
 
-    let (addr_str, port) = {
-        let addr = remote_addr.ip().to_string();
-        let port = remote_addr.port();
-        (addr, port)
-    };
+    if let Some(mut ready) = hold {
+        if !*ready.borrow() {
+            log::info!("holding connection from {local_addr} until ssh is back");
+        }
+        wait_until_ready(&mut ready).await?;
+    }
 
-    let mut remote_config = fast_socks5::client::Config::default();
-    remote_config.set_skip_auth(false);
-    let mut remote = Socks5Stream::connect(socks_addr, addr_str, port, remote_config).await?;
+    let mut remote = upstream.connect(remote_addr).await?;
 
     let result = copy_bidirectional(&mut local, &mut remote).await;
     // let result = my_bidirectional_copy(&mut local, &mut remote).await;
@@ -354,6 +1151,228 @@ async fn handle_tcp_client(
     Ok(())
 }
 
+/// Datagrams are forwarded per flow, identified by the client address and the
+/// address the client originally sent to.
+type UdpFlows = HashMap<(SocketAddr, SocketAddr), mpsc::Sender<Vec<u8>>>;
+
+const UDP_BUFFER_SIZE: usize = 65536;
+const UDP_FLOW_QUEUE: usize = 64;
+/// Receive errors in a row after which the UDP listener gives up.
+const UDP_MAX_ERRORS: u32 = 10;
+/// Delay after a receive error, multiplied by the number of errors in a row.
+const UDP_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+async fn listen_udp(
+    firewall: &Arc<dyn Firewall + Send + Sync>,
+    l_addr: ListenerAddr,
+    upstream: &Arc<dyn Upstream>,
+    udp_timeout: Duration,
+) -> Result<(), ClientError> {
+    let upstream = Arc::clone(upstream);
+    let socket = UdpSocket::bind(l_addr.addr).await?;
+    firewall.setup_udp_socket(&socket)?;
+
+    let _handle: JoinHandle<Result<(), ClientError>> = tokio::spawn(async move {
+        let mut flows: UdpFlows = HashMap::new();
+        let mut buf = vec![0; UDP_BUFFER_SIZE];
+        // Flows that timed out are forgotten, even if no new flow is started.
+        let mut prune = interval(udp_timeout.max(Duration::from_secs(1)));
+        let mut errors = 0;
+        loop {
+            let received = select! {
+                received = recv_from_orig_dst(&socket, &mut buf) => received,
+                _ = prune.tick() => {
+                    flows.retain(|_, tx| !tx.is_closed());
+                    continue;
+                }
+            };
+            let (len, src, dst) = match received {
+                Ok(result) => {
+                    errors = 0;
+                    result
+                }
+                Err(err) => {
+                    errors += 1;
+                    log::error!("{l_addr} failed to receive datagram: {err}");
+                    if errors >= UDP_MAX_ERRORS {
+                        log::error!("{l_addr} giving up after {errors} errors in a row");
+                        return Err(err.into());
+                    }
+                    sleep(UDP_ERROR_DELAY * errors).await;
+                    continue;
+                }
+            };
+            let data = buf[..len].to_vec();
+
+            let result = match flows.get(&(src, dst)) {
+                Some(tx) => tx.try_send(data),
+                None => Err(mpsc::error::TrySendError::Closed(data)),
+            };
+            match result {
+                Ok(()) => {}
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    log::debug!("{l_addr} dropping datagram from {src} to {dst}, queue full");
+                }
+                // New flow, or the old one has timed out.
+                Err(mpsc::error::TrySendError::Closed(data)) => {
+                    start_udp_flow(&mut flows, &l_addr, src, dst, data, &upstream, udp_timeout);
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+fn start_udp_flow(
+    flows: &mut UdpFlows,
+    l_addr: &ListenerAddr,
+    src: SocketAddr,
+    dst: SocketAddr,
+    data: Vec<u8>,
+    upstream: &Arc<dyn Upstream>,
+    udp_timeout: Duration,
+) {
+    log::info!("{l_addr} got datagram from {src} to {dst}");
+    flows.retain(|_, tx| !tx.is_closed());
+
+    let (tx, rx) = mpsc::channel(UDP_FLOW_QUEUE);
+    // Can't fail, the channel is empty and the receiver is alive.
+    _ = tx.try_send(data);
+    flows.insert((src, dst), tx);
+
+    let upstream = Arc::clone(upstream);
+    tokio::spawn(async move {
+        Box::pin(handle_udp_flow(src, dst, rx, &upstream, udp_timeout))
+            .await
+            .map_err(|err| {
+                log::error!("handle_udp_flow failed: {err}");
+                err
+            })
+            .ok();
+    });
+}
+
+async fn handle_udp_flow(
+    src: SocketAddr,
+    dst: SocketAddr,
+    mut rx: mpsc::Receiver<Vec<u8>>,
+    upstream: &Arc<dyn Upstream>,
+    udp_timeout: Duration,
+) -> Result<(), ClientError> {
+    let mut remote = upstream.datagram(dst).await?;
+
+    // Replies need to come from the address the client sent to.
+    let local = bind_transparent_udp(dst)?;
+
+    let mut buf = vec![0; UDP_BUFFER_SIZE];
+    let mut deadline = Instant::now() + udp_timeout;
+    loop {
+        select! {
+            msg = rx.recv() => {
+                match msg {
+                    Some(data) => {
+                        remote.send_to(&data, dst).await?;
+                        deadline = Instant::now() + udp_timeout;
+                    }
+                    None => break,
+                }
+            }
+            res = remote.recv(&mut buf) => {
+                let len = res?;
+                local.send_to(&buf[..len], src).await?;
+                deadline = Instant::now() + udp_timeout;
+            }
+            () = sleep_until(deadline) => {
+                log::debug!("udp flow from {src} to {dst} timed out");
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Give up on a DNS query if the upstream resolver hasn't answered by now.
+const DNS_TIMEOUT: Duration = Duration::from_secs(10);
+
+async fn listen_dns(
+    firewall: &Arc<dyn Firewall + Send + Sync>,
+    dns_addr: SocketAddr,
+    upstream: &Arc<dyn Upstream>,
+    dns_server: SocketAddr,
+    transparent: bool,
+) -> Result<(), ClientError> {
+    let upstream = Arc::clone(upstream);
+    let socket = Arc::new(UdpSocket::bind(dns_addr).await?);
+    firewall.setup_udp_socket(&socket)?;
+    log::info!("{dns_addr} forwarding DNS requests to {dns_server}");
+
+    let _handle: JoinHandle<Result<(), ClientError>> = tokio::spawn(async move {
+        let mut buf = vec![0; UDP_BUFFER_SIZE];
+        loop {
+            let result = if transparent {
+                recv_from_orig_dst(&socket, &mut buf)
+                    .await
+                    .map(|(len, src, dst)| (len, src, Some(dst)))
+                    .map_err(ClientError::from)
+            } else {
+                socket
+                    .recv_from(&mut buf)
+                    .await
+                    .map(|(len, src)| (len, src, None))
+                    .map_err(ClientError::from)
+            };
+            let (len, src, dst) = match result {
+                Ok(result) => result,
+                Err(err) => {
+                    log::error!("{dns_addr} failed to receive DNS request: {err}");
+                    continue;
+                }
+            };
+            let request = buf[..len].to_vec();
+            let socket = Arc::clone(&socket);
+            let upstream = Arc::clone(&upstream);
+
+            tokio::spawn(async move {
+                handle_dns_request(socket, src, dst, request, &upstream, dns_server)
+                    .await
+                    .map_err(|err| {
+                        log::error!("handle_dns_request failed: {err}");
+                        err
+                    })
+                    .ok();
+            });
+        }
+    });
+    Ok(())
+}
+
+async fn handle_dns_request(
+    socket: Arc<UdpSocket>,
+    src: SocketAddr,
+    dst: Option<SocketAddr>,
+    request: Vec<u8>,
+    upstream: &Arc<dyn Upstream>,
+    dns_server: SocketAddr,
+) -> Result<(), ClientError> {
+    log::debug!("DNS request from {src} to {dst:?}");
+    let response = timeout(DNS_TIMEOUT, upstream.query_dns(&request, dns_server)).await??;
+
+    match dst {
+        // Replies need to come from the address the client sent to.
+        Some(dst) => {
+            let local = bind_transparent_udp(dst)?;
+            local.send_to(&response, src).await?;
+        }
+        // The nat firewall rewrites the source address of the reply for us.
+        None => {
+            socket.send_to(&response, src).await?;
+        }
+    }
+
+    Ok(())
+}
+
 // async fn my_bidirectional_copy(
 //     local: &mut TcpStream,
 //     remote: &mut Socks5Stream<TcpStream>,
@@ -417,3 +1436,162 @@ async fn handle_tcp_client(
 
 //     Ok(())
 // }
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::upstream::HttpProxy;
+
+    #[test]
+    fn test_backoff_delay() {
+        for (retries, base) in [(1, 1), (2, 2), (3, 4), (5, 16), (6, 30), (100, 30)] {
+            let delay = backoff_delay(retries);
+            let base = Duration::from_secs(base);
+            assert!(delay >= base, "{retries}: {delay:?} < {base:?}");
+            assert!(delay <= base + base / 2, "{retries}: {delay:?} too large");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_probe_socks() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut proxy = SocksProxy::new(listener.local_addr().unwrap());
+        proxy.user = Some("alice".to_string());
+        let server = tokio::spawn(async move {
+            let mut greetings = Vec::new();
+            for reply in [0x02, 0xff] {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut greeting = [0u8; 4];
+                socket.read_exact(&mut greeting).await.unwrap();
+                socket.write_all(&[0x05, reply]).await.unwrap();
+                greetings.push(greeting);
+            }
+            greetings
+        });
+
+        probe_socks(&proxy).await.unwrap();
+        // The server is up, so there is no point waiting for it.
+        let err = wait_for_socks(&proxy, Duration::from_secs(5))
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err,
+            ClientError::Upstream(UpstreamError::SocksAuth(_, _))
+        ));
+        assert_eq!(server.await.unwrap(), [[0x05, 0x02, 0x00, 0x02]; 2]);
+    }
+
+    #[tokio::test]
+    async fn test_restore_guard() {
+        let dir =
+            std::env::temp_dir().join(format!("sshuttle_rust-test-guard-{}", std::process::id()));
+        let guard = |pid| {
+            let mut restore = Commands::new();
+            restore.push(crate::command::Line::new("true", [""; 0]));
+            let state = State {
+                pid,
+                start_time: None,
+                setup: Commands::new(),
+                restore,
+            };
+            let state_file = StateFile::create(&dir, &state).unwrap();
+            RestoreGuard::new(Restore::Commands(state.restore, state_file))
+        };
+
+        let restore = guard(u32::MAX - 1);
+        assert_eq!(Arc::strong_count(&restore.restore), 1);
+        restore.restore().await.unwrap();
+        assert!(!dir.join(format!("{}.json", u32::MAX - 1)).exists());
+
+        // Dropping the guard restores too.
+        drop(guard(u32::MAX));
+        assert!(!dir.join(format!("{}.json", u32::MAX)).exists());
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    fn test_config(remote: Option<Remote>, proxy: Proxy) -> Config {
+        Config {
+            includes: "0.0.0.0/0".parse().unwrap(),
+            excludes: "10.0.0.0/8".parse().unwrap(),
+            remote,
+            ssh_cmd: vec!["ssh".to_string()],
+            listen: vec![ListenerAddr {
+                protocol: crate::network::Protocol::Tcp,
+                addr: "127.0.0.1:1024".parse().unwrap(),
+            }],
+            socks_addr: "127.0.0.1:1080".parse().unwrap(),
+            proxy,
+            firewall: FirewallType::Nat,
+            user: None,
+            tproxy_mark: 1,
+            tproxy_table: 100,
+            iptables_restore: false,
+            udp_timeout: Duration::from_secs(30),
+            dns_server: None,
+            dns_port: 1053,
+            socks_ready_timeout: Duration::from_secs(5),
+            ssh_max_retries: 3,
+            hold_connections: false,
+            firewall_helper: None,
+            run_as: None,
+            server_cmd: None,
+            #[cfg(feature = "builtin-ssh")]
+            builtin_ssh: None,
+        }
+    }
+
+    /// The rules of the NAT chain that match on a destination.
+    fn dest_rules(config: &Config) -> Vec<String> {
+        let firewall = get_firewall(config.firewall, config.tproxy_mark, config.tproxy_table);
+        let (setup, _) =
+            firewall_commands(firewall.as_ref(), &get_firewall_config(config), false).unwrap();
+        setup
+            .iter()
+            .map(|c| c.line.to_string())
+            .filter(|line| line.contains("-A sshuttle-1024") && line.contains("--dest"))
+            .collect()
+    }
+
+    #[test]
+    fn test_remote_excluded() {
+        let mut remote: Remote = "host.example.org".parse().unwrap();
+        remote.resolved = Some(ssh::Resolved {
+            addr: "192.0.2.1:2222".parse().unwrap(),
+            host_key_alias: "host.example.org".to_string(),
+        });
+        let config = test_config(Some(remote), Proxy::Direct);
+
+        assert_eq!(
+            dest_rules(&config),
+            [
+                "iptables -w -t nat -A sshuttle-1024 -j RETURN --dest 192.0.2.1/32 -p tcp --dport 2222",
+                "iptables -w -t nat -A sshuttle-1024 -j RETURN --dest 10.0.0.0/8 -p tcp",
+                "iptables -w -t nat -A sshuttle-1024 -j REDIRECT --dest 0.0.0.0/0 -p tcp --to-ports 1024",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_proxy_excluded() {
+        let mut proxy: HttpProxy = "user@proxy.example.org:3128".parse().unwrap();
+        proxy.resolved = Some("192.0.2.2:3128".parse().unwrap());
+        let config = test_config(None, Proxy::Http(proxy));
+        assert_eq!(
+            dest_rules(&config),
+            [
+                "iptables -w -t nat -A sshuttle-1024 -j RETURN --dest 192.0.2.2/32 -p tcp --dport 3128",
+                "iptables -w -t nat -A sshuttle-1024 -j RETURN --dest 10.0.0.0/8 -p tcp",
+                "iptables -w -t nat -A sshuttle-1024 -j REDIRECT --dest 0.0.0.0/0 -p tcp --to-ports 1024",
+            ]
+        );
+
+        let socks = SocksProxy::new("192.0.2.3:1080".parse().unwrap());
+        let config = test_config(None, Proxy::Socks(socks));
+        assert_eq!(
+            dest_rules(&config)[0],
+            "iptables -w -t nat -A sshuttle-1024 -j RETURN --dest 192.0.2.3/32 -p tcp --dport 1080"
+        );
+    }
+}
@@ -3,6 +3,7 @@
 //! This will run a Unix command, and keep track of stdout, stderr, and any errors.
 
 use log::info;
+use serde::{Deserialize, Serialize};
 use std::{
     error,
     fmt::Display,
@@ -11,7 +12,10 @@ use std::{
     str::{self, Utf8Error},
     time::{Duration, Instant},
 };
-use tokio::{io, process::Command};
+use tokio::{
+    io::{self, AsyncWriteExt},
+    process::Command,
+};
 
 pub fn duration_string(duration: &Duration) -> String {
     let seconds = duration.as_secs() % 60;
@@ -128,7 +132,7 @@ impl error::Error for Error {}
 
 pub type Result = result::Result<Success, Error>;
 
-#[derive(Clone, Eq, PartialEq)]
+#[derive(Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Line(pub String, pub Vec<String>);
 
 fn get_exit_code(output: &result::Result<Output, io::Error>) -> i32 {
@@ -149,6 +153,26 @@ fn get_stdin_out(
     }
 }
 
+async fn output_with_input(
+    cmd: &str,
+    args: &[String],
+    input: &str,
+) -> result::Result<Output, io::Error> {
+    let mut child = Command::new(cmd)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input.as_bytes()).await?;
+        // stdin is dropped here, so the child sees EOF.
+    }
+
+    child.wait_with_output().await
+}
+
 impl Line {
     pub fn new(cmd: impl Into<String>, args: impl IntoIterator<Item = impl Into<String>>) -> Self {
         let cmd = cmd.into();
@@ -156,18 +180,26 @@ impl Line {
         Self(cmd, args)
     }
 
-    pub async fn run(&self) -> Result {
+    pub async fn run_with_input(&self, input: Option<&str>) -> Result {
         let start = Instant::now();
         info!("Running command: {self}");
 
         let Self(cmd, args) = &self;
-        let output = Command::new(cmd)
-            .args(args)
-            .stdin(Stdio::null())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .output()
-            .await;
+        let output = match input {
+            None => {
+                Command::new(cmd)
+                    .args(args)
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::piped())
+                    .output()
+                    .await
+            }
+            Some(input) => {
+                log::debug!("Command input:\n{input}");
+                output_with_input(cmd, args, input).await
+            }
+        };
 
         let exit_code = get_exit_code(&output);
         let duration = start.elapsed();
@@ -1,14 +1,30 @@
 use std::slice::Iter;
 
+use serde::{Deserialize, Serialize};
+
 use crate::command::{Error, ErrorKind, Line};
 
-#[derive(Debug)]
+/// Quote an argument for a POSIX shell, unless it is plainly safe.
+fn shell_quote(arg: &str) -> String {
+    let safe = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:=@,+%".contains(c));
+    if safe {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Command {
     pub line: Line,
+    pub input: Option<String>,
     pub ignore_errors: bool,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Commands(Vec<Command>);
 
 impl Commands {
@@ -18,7 +34,7 @@ impl Commands {
 
     pub async fn run_all(&self) -> Result<(), Error> {
         for cmd in &self.0 {
-            if let Err(err) = cmd.line.run().await {
+            if let Err(err) = cmd.line.run_with_input(cmd.input.as_deref()).await {
                 if let ErrorKind::BadExitCode { .. } = err.kind {
                     if cmd.ignore_errors {
                         log::info!("Ignoring error: {}", err);
@@ -43,9 +59,14 @@ impl Commands {
         self.0.iter()
     }
 
+    pub fn retain(&mut self, f: impl FnMut(&Command) -> bool) {
+        self.0.retain(f);
+    }
+
     pub fn push(&mut self, line: Line) {
         self.0.push(Command {
             line,
+            input: None,
             ignore_errors: false,
         });
     }
@@ -53,9 +74,46 @@ impl Commands {
     pub fn push_ignore_errors(&mut self, line: Line) {
         self.0.push(Command {
             line,
+            input: None,
             ignore_errors: true,
         });
     }
+
+    pub fn push_with_input(&mut self, line: Line, input: String) {
+        self.0.push(Command {
+            line,
+            input: Some(input),
+            ignore_errors: false,
+        });
+    }
+
+    /// Render the commands as a shell script, passing any input as a here-document.
+    pub fn to_shell_script(&self) -> String {
+        let mut script = String::new();
+        for cmd in &self.0 {
+            let Line(program, args) = &cmd.line;
+            script.push_str(&shell_quote(program));
+            for arg in args {
+                script.push(' ');
+                script.push_str(&shell_quote(arg));
+            }
+            if cmd.input.is_some() {
+                script.push_str(" <<'EOF'");
+            }
+            if cmd.ignore_errors {
+                script.push_str(" || true");
+            }
+            script.push('\n');
+            if let Some(input) = &cmd.input {
+                script.push_str(input);
+                if !input.ends_with('\n') {
+                    script.push('\n');
+                }
+                script.push_str("EOF\n");
+            }
+        }
+        script
+    }
 }
 
 // impl Index<usize> for Commands {
@@ -64,3 +122,33 @@ impl Commands {
 //         &self.0[index]
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_shell_script() {
+        let mut commands = Commands::new();
+        commands.push_ignore_errors(Line::new("iptables", ["-X", "sshuttle-1024"]));
+        commands.push(Line::new(
+            "iptables",
+            ["-m", "comment", "--comment", "it's here", "-j", ""],
+        ));
+        commands.push_with_input(
+            Line::new("iptables-restore", ["-w", "--noflush"]),
+            "*nat\nCOMMIT\n".to_string(),
+        );
+
+        assert_eq!(
+            commands.to_shell_script(),
+            r"iptables -X sshuttle-1024 || true
+iptables -m comment --comment 'it'\''s here' -j ''
+iptables-restore -w --noflush <<'EOF'
+*nat
+COMMIT
+EOF
+"
+        );
+    }
+}
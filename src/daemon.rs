@@ -0,0 +1,152 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    os::unix::prelude::{AsRawFd, FromRawFd},
+    path::{Path, PathBuf},
+};
+
+use nix::{
+    errno::Errno,
+    unistd::{chown, dup2, fork, pipe, setsid, ForkResult, Gid, Uid},
+};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum DaemonError {
+    #[error("Errno error `{0}`")]
+    Errno(#[from] Errno),
+
+    #[error("IO Error `{0}`")]
+    Io(#[from] std::io::Error),
+
+    #[error("Already running as process {0}, according to {}", .1.display())]
+    Running(u32, PathBuf),
+}
+
+/// The detached process, returned by `daemonize`.
+///
+/// The pidfile is removed when this is dropped.
+pub struct Daemon {
+    ready: Option<File>,
+    pidfile: PathBuf,
+}
+
+impl Daemon {
+    /// Tell the original process that start up succeeded, so it can exit.
+    pub fn notify_ready(&mut self) -> Result<(), DaemonError> {
+        if let Some(mut ready) = self.ready.take() {
+            ready.write_all(b"1")?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        match std::fs::remove_file(&self.pidfile) {
+            Ok(()) => {}
+            // After --setuid the directory may not be writable, but the file is ours.
+            Err(err) if err.kind() == std::io::ErrorKind::PermissionDenied => {
+                if let Err(err) = OpenOptions::new()
+                    .write(true)
+                    .truncate(true)
+                    .open(&self.pidfile)
+                {
+                    log::error!("Could not empty pidfile {}: {err}", self.pidfile.display());
+                }
+            }
+            Err(err) => {
+                log::error!("Could not remove pidfile {}: {err}", self.pidfile.display());
+            }
+        }
+    }
+}
+
+/// Detach from the terminal.
+///
+/// The original process doesn't return, it waits until the daemon calls
+/// `notify_ready` and exits successfully, or exits with an error if the daemon
+/// exits first. This must be called before any threads are started.
+///
+/// The pidfile belongs to `owner` when given, so it can still be emptied once
+/// privileges are dropped.
+pub fn daemonize(pidfile: &Path, owner: Option<(Uid, Gid)>) -> Result<Daemon, DaemonError> {
+    let pidfile = std::env::current_dir()?.join(pidfile);
+    if let Some(pid) = running_pid(&pidfile) {
+        return Err(DaemonError::Running(pid, pidfile));
+    }
+    let (read_fd, write_fd) = pipe()?;
+    let read_end = unsafe { File::from_raw_fd(read_fd) };
+    let write_end = unsafe { File::from_raw_fd(write_fd) };
+
+    match unsafe { fork() }? {
+        ForkResult::Parent { .. } => {
+            drop(write_end);
+            wait_for_ready(read_end);
+        }
+        ForkResult::Child => {
+            drop(read_end);
+        }
+    }
+
+    setsid()?;
+    std::env::set_current_dir("/")?;
+
+    let dev_null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    for fd in 0..=2 {
+        dup2(dev_null.as_raw_fd(), fd)?;
+    }
+
+    std::fs::write(&pidfile, format!("{}\n", std::process::id()))?;
+    if let Some((uid, gid)) = owner {
+        chown(&pidfile, Some(uid), Some(gid))?;
+    }
+
+    Ok(Daemon {
+        ready: Some(write_end),
+        pidfile,
+    })
+}
+
+/// The process id in `pidfile`, if that process is still running.
+fn running_pid(pidfile: &Path) -> Option<u32> {
+    let pid: u32 = std::fs::read_to_string(pidfile).ok()?.trim().parse().ok()?;
+    let running = pid != std::process::id() && Path::new("/proc").join(pid.to_string()).exists();
+    running.then_some(pid)
+}
+
+fn wait_for_ready(mut ready: File) -> ! {
+    let mut buf = [0u8; 1];
+    if matches!(ready.read(&mut buf), Ok(1)) {
+        std::process::exit(0);
+    }
+    eprintln!("sshuttle_rust failed to start, see the logs for details");
+    std::process::exit(1);
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_running_pid() {
+        let pidfile =
+            std::env::temp_dir().join(format!("sshuttle_rust-test-{}.pid", std::process::id()));
+        assert_eq!(running_pid(&pidfile), None);
+
+        // Process 1 is always running.
+        std::fs::write(&pidfile, "1\n").unwrap();
+        assert_eq!(running_pid(&pidfile), Some(1));
+        std::fs::write(&pidfile, format!("{}\n", u32::MAX)).unwrap();
+        assert_eq!(running_pid(&pidfile), None);
+        // Emptied by a daemon that had dropped privileges.
+        std::fs::write(&pidfile, "").unwrap();
+        assert_eq!(running_pid(&pidfile), None);
+
+        std::fs::remove_file(&pidfile).unwrap();
+    }
+}
@@ -9,6 +9,7 @@ use nix::{
         SockaddrIn, SockaddrIn6, SockaddrLike,
     },
 };
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tokio::net::{TcpListener, TcpStream, UdpSocket};
 
@@ -19,6 +20,7 @@ use crate::{
 };
 
 pub mod nat;
+pub mod nftables;
 pub mod tproxy;
 
 #[derive(Error, Debug)]
@@ -34,6 +36,9 @@ pub enum FirewallError {
 
     #[error("Cannot get destination address")]
     CannotGetDstAddress,
+
+    #[error("Cannot pass `{0:?}` to iptables-restore")]
+    RestoreArgument(String),
 }
 
 fn get_dst_addr_sockopt(s: &TcpStream) -> Result<SocketAddr, FirewallError> {
@@ -79,6 +84,7 @@ pub trait Firewall {
     fn restore_firewall(&self, config: &FirewallConfig) -> Result<Commands, FirewallError>;
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct FirewallSubnetConfig<T: SubnetsFamily> {
     pub enable: bool,
     pub listener: ListenerAddr,
@@ -92,13 +98,29 @@ impl<T: SubnetsFamily> FirewallSubnetConfig<T> {
     }
 }
 
+#[derive(Serialize, Deserialize)]
 pub enum FirewallListenerConfig {
     Ipv4(FirewallSubnetConfig<SubnetsV4>),
     Ipv6(FirewallSubnetConfig<SubnetsV6>),
 }
 
-#[derive(Default)]
+#[derive(Default, Serialize, Deserialize)]
 pub struct FirewallConfig {
     pub filter_from_user: Option<String>,
+    /// Redirect DNS requests (UDP port 53) to this local port.
+    pub dns_port: Option<u16>,
+    /// Where the DNS requests are forwarded to, requests sent straight to it are
+    /// left alone.
+    pub dns_server: Option<SocketAddr>,
     pub listeners: Vec<FirewallListenerConfig>,
 }
+
+impl FirewallConfig {
+    /// The DNS server, if it belongs to `family`.
+    pub fn dns_server(&self, family: Family) -> Option<SocketAddr> {
+        self.dns_server.filter(|addr| match family {
+            Family::Ipv4 => addr.is_ipv4(),
+            Family::Ipv6 => addr.is_ipv6(),
+        })
+    }
+}
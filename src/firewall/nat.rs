@@ -54,15 +54,28 @@ impl NatFirewall {
         ipt!("-F", &chain);
 
         if let Some(user) = &config.filter_from_user {
-            ipm!("-I", "OUTPUT", "1", "-m", "owner", "--uid-owner", user, "-j", "MARK", "set-mark", &port);
+            ipm!("-I", "OUTPUT", "1", "-m", "owner", "--uid-owner", user, "-j", "MARK", "--set-mark", &port);
 
             ipt!("-I", "OUTPUT", "1", "-m", "mark", "--mark", &port, "-j", &chain);
-            ipt!("-I", "PREROUTING", "1", "-m", "mark","--mark", &port, "-j", &chain);
+            ipt!("-I", "PREROUTING", "1", "-m", "mark", "--mark", &port, "-j", &chain);
         } else {
             ipt!("-I", "OUTPUT", "1", "-j", &chain);
             ipt!("-I", "PREROUTING", "1", "-j", &chain);
         }
 
+        // DNS comes before the local addresses are skipped, to catch requests to local
+        // resolvers such as 127.0.0.53, but not those to our listener or the DNS server.
+        if let Some(dns_port) = config.dns_port {
+            let dns_port = dns_port.to_string();
+            let listener = subnet_config.listener.ip().to_string();
+            ipt!("-A", &chain, "-j", "RETURN", "-p", "udp", "--dest", &listener, "--dport", &dns_port);
+            if let Some(server) = config.dns_server(family) {
+                let (ip, port) = (server.ip().to_string(), server.port().to_string());
+                ipt!("-A", &chain, "-j", "RETURN", "-p", "udp", "--dest", &ip, "--dport", &port);
+            }
+            ipt!("-A", &chain, "-j", "REDIRECT", "-p", "udp", "--dport", "53", "--to-ports", &dns_port);
+        }
+
         ipt!("-A", &chain, "-j", "RETURN", "-m", "addrtype", "--dst-type", "LOCAL");
 
         for subnet in subnet_config.excludes.iter() {
@@ -129,7 +142,7 @@ impl NatFirewall {
         if let Some(user) = &config.filter_from_user {
             ipm!("-D", "OUTPUT", "-m", "owner", "--uid-owner", user, "-j", "MARK", "--set-mark", &port);
             ipt!("-D", "OUTPUT", "-m", "mark", "--mark", &port, "-j", &chain);
-            ipt!("-D", "PREROUTING", "1", "-m", "mark","--mark", &port, "-j", &chain);
+            ipt!("-D", "PREROUTING", "-m", "mark", "--mark", &port, "-j", &chain);
         } else {
             ipt!("-D", "OUTPUT", "-j", &chain);
             ipt!("-D", "PREROUTING", "-j", &chain);
@@ -200,6 +213,8 @@ mod tests {
         };
         let config = FirewallConfig {
             filter_from_user: None,
+            dns_port: None,
+            dns_server: None,
             listeners: vec![],
         };
 
@@ -225,6 +240,90 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_setup_family_v4_dns() {
+        let firewall = NatFirewall::new();
+        let ipv4_family = FirewallSubnetConfig {
+            enable: true,
+            listener: ListenerAddr {
+                protocol: Protocol::Tcp,
+                addr: "127.0.0.1:1024".parse().unwrap(),
+            },
+            includes: "1.2.3.0/24".parse::<SubnetsV4>().unwrap(),
+            excludes: SubnetsV4::default(),
+        };
+        let config = FirewallConfig {
+            filter_from_user: None,
+            dns_port: Some(1053),
+            dns_server: Some("10.0.0.53:53".parse().unwrap()),
+            listeners: vec![],
+        };
+
+        let expected_ipv4: [&str; 9] = [
+            "iptables -w -t nat -N sshuttle-1024",
+            "iptables -w -t nat -F sshuttle-1024",
+            "iptables -w -t nat -I OUTPUT 1 -j sshuttle-1024",
+            "iptables -w -t nat -I PREROUTING 1 -j sshuttle-1024",
+            "iptables -w -t nat -A sshuttle-1024 -j RETURN -p udp --dest 127.0.0.1 --dport 1053",
+            "iptables -w -t nat -A sshuttle-1024 -j RETURN -p udp --dest 10.0.0.53 --dport 53",
+            "iptables -w -t nat -A sshuttle-1024 -j REDIRECT -p udp --dport 53 --to-ports 1053",
+            "iptables -w -t nat -A sshuttle-1024 -j RETURN -m addrtype --dst-type LOCAL",
+            "iptables -w -t nat -A sshuttle-1024 -j REDIRECT --dest 1.2.3.0/24 -p tcp --to-ports 1024",
+        ];
+
+        let mut commands = Commands::default();
+        firewall
+            .setup_family(&config, &ipv4_family, &mut commands)
+            .unwrap();
+        assert_eq!(commands.len(), expected_ipv4.len());
+        for (command, expected_line) in commands.iter().zip(expected_ipv4.iter()) {
+            let split: Vec<String> = expected_line.split(' ').map(ToOwned::to_owned).collect();
+            let expected_command = Line(split[0].clone(), split[1..].to_vec());
+            assert_eq!(command.line, expected_command);
+        }
+    }
+
+    #[test]
+    fn test_setup_family_v4_user() {
+        let firewall = NatFirewall::new();
+        let ipv4_family = FirewallSubnetConfig {
+            enable: true,
+            listener: ListenerAddr {
+                protocol: Protocol::Tcp,
+                addr: "127.0.0.1:1024".parse().unwrap(),
+            },
+            includes: "1.2.3.0/24".parse::<SubnetsV4>().unwrap(),
+            excludes: SubnetsV4::default(),
+        };
+        let config = FirewallConfig {
+            filter_from_user: Some("1000".to_string()),
+            dns_port: None,
+            dns_server: None,
+            listeners: vec![],
+        };
+
+        let expected_ipv4: [&str; 7] = [
+            "iptables -w -t nat -N sshuttle-1024",
+            "iptables -w -t nat -F sshuttle-1024",
+            "iptables -w -t mangle -I OUTPUT 1 -m owner --uid-owner 1000 -j MARK --set-mark 1024",
+            "iptables -w -t nat -I OUTPUT 1 -m mark --mark 1024 -j sshuttle-1024",
+            "iptables -w -t nat -I PREROUTING 1 -m mark --mark 1024 -j sshuttle-1024",
+            "iptables -w -t nat -A sshuttle-1024 -j RETURN -m addrtype --dst-type LOCAL",
+            "iptables -w -t nat -A sshuttle-1024 -j REDIRECT --dest 1.2.3.0/24 -p tcp --to-ports 1024",
+        ];
+
+        let mut commands = Commands::default();
+        firewall
+            .setup_family(&config, &ipv4_family, &mut commands)
+            .unwrap();
+        assert_eq!(commands.len(), expected_ipv4.len());
+        for (command, expected_line) in commands.iter().zip(expected_ipv4.iter()) {
+            let split: Vec<String> = expected_line.split(' ').map(ToOwned::to_owned).collect();
+            let expected_command = Line(split[0].clone(), split[1..].to_vec());
+            assert_eq!(command.line, expected_command);
+        }
+    }
+
     #[test]
     fn test_setup_family_v6() {
         let firewall = NatFirewall::new();
@@ -239,6 +338,8 @@ mod tests {
         };
         let config = FirewallConfig {
             filter_from_user: None,
+            dns_port: None,
+            dns_server: None,
             listeners: vec![],
         };
         let expected_ipv6: [&str; 7] = [
@@ -277,6 +378,8 @@ mod tests {
         };
         let config = FirewallConfig {
             filter_from_user: None,
+            dns_port: None,
+            dns_server: None,
             listeners: vec![],
         };
 
@@ -299,6 +402,45 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_restore_family_v4_user() {
+        let firewall = NatFirewall::new();
+        let ipv4_family = FirewallSubnetConfig {
+            enable: true,
+            listener: ListenerAddr {
+                protocol: Protocol::Tcp,
+                addr: "127.0.0.1:1024".parse().unwrap(),
+            },
+            includes: "1.2.3.0/24".parse::<SubnetsV4>().unwrap(),
+            excludes: SubnetsV4::default(),
+        };
+        let config = FirewallConfig {
+            filter_from_user: Some("1000".to_string()),
+            dns_port: None,
+            dns_server: None,
+            listeners: vec![],
+        };
+
+        let expected_ipv4: [&str; 5] = [
+            "iptables -w -t mangle -D OUTPUT -m owner --uid-owner 1000 -j MARK --set-mark 1024",
+            "iptables -w -t nat -D OUTPUT -m mark --mark 1024 -j sshuttle-1024",
+            "iptables -w -t nat -D PREROUTING -m mark --mark 1024 -j sshuttle-1024",
+            "iptables -w -t nat -F sshuttle-1024",
+            "iptables -w -t nat -X sshuttle-1024",
+        ];
+
+        let mut commands = Commands::default();
+        firewall
+            .restore_family(&config, &ipv4_family, &mut commands)
+            .unwrap();
+        assert_eq!(commands.len(), expected_ipv4.len());
+        for (command, expected_line) in commands.iter().zip(expected_ipv4.iter()) {
+            let split: Vec<String> = expected_line.split(' ').map(ToOwned::to_owned).collect();
+            let expected_command = Line(split[0].clone(), split[1..].to_vec());
+            assert_eq!(command.line, expected_command);
+        }
+    }
+
     #[test]
     fn test_restore_family_v6() {
         let firewall = NatFirewall::new();
@@ -313,6 +455,8 @@ mod tests {
         };
         let config = FirewallConfig {
             filter_from_user: None,
+            dns_port: None,
+            dns_server: None,
             listeners: vec![],
         };
         let expected_ipv6: [&str; 4] = [
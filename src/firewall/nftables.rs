@@ -0,0 +1,387 @@
+use crate::network::Family;
+use crate::network::Ports;
+use crate::network::Protocol;
+use crate::network::SubnetFamily;
+use crate::network::SubnetsFamily;
+
+use super::{
+    Commands, Firewall, FirewallConfig, FirewallError, FirewallListenerConfig, FirewallSubnetConfig,
+};
+
+pub struct NftablesFirewall {}
+
+fn table_name(config: &FirewallConfig) -> String {
+    let ports: Vec<String> = config
+        .listeners
+        .iter()
+        .map(|family| match family {
+            FirewallListenerConfig::Ipv4(ip) => ip.listener.port().to_string(),
+            FirewallListenerConfig::Ipv6(ip) => ip.listener.port().to_string(),
+        })
+        .collect();
+
+    if ports.is_empty() {
+        "sshuttle".to_string()
+    } else {
+        format!("sshuttle-{}", ports.join("-"))
+    }
+}
+
+impl NftablesFirewall {
+    pub const fn new() -> Self {
+        NftablesFirewall {}
+    }
+
+    #[rustfmt::skip]
+    fn setup_family<T: SubnetsFamily>(
+        &self,
+        config: &FirewallConfig,
+        table: &str,
+        subnet_config: &FirewallSubnetConfig<T>,
+        commands: &mut Commands,
+    ) -> Result<(), FirewallError> {
+        if !matches!(subnet_config.listener.protocol, Protocol::Tcp) {
+            return Err(FirewallError::NotSupported(
+                "Only TCP is supported for nftables".to_string()),
+            );
+        }
+
+        let port = subnet_config.listener.port().to_string();
+        let to_port = format!(":{port}");
+        let chain = format!("sshuttle-{port}");
+        let (nfproto, addr) = match subnet_config.family() {
+            Family::Ipv4 => ("ipv4", "ip"),
+            Family::Ipv6 => ("ipv6", "ip6"),
+        };
+
+        macro_rules! nft {
+            ( $( $e:expr),* ) => {
+                let v = vec![ $( $e ),* ];
+                commands.nft(&v);
+            };
+        }
+
+        macro_rules! nft_vec {
+            ( $e:expr ) => {
+                let v = $e;
+                commands.nft(&v);
+            };
+        }
+
+        nft!("add", "chain", "inet", table, &chain);
+
+        if let Some(user) = &config.filter_from_user {
+            // Only locally generated packets have an owner, so there is nothing to
+            // match in prerouting.
+            nft!("add", "rule", "inet", table, "output", "meta", "nfproto", nfproto, "meta", "skuid", user, "jump", &chain);
+        } else {
+            nft!("add", "rule", "inet", table, "output", "meta", "nfproto", nfproto, "jump", &chain);
+            nft!("add", "rule", "inet", table, "prerouting", "meta", "nfproto", nfproto, "jump", &chain);
+        }
+
+        // DNS comes before the local addresses are skipped, to catch requests to local
+        // resolvers such as 127.0.0.53, but not those to our listener or the DNS server.
+        if let Some(dns_port) = config.dns_port {
+            let dns_to_port = format!(":{dns_port}");
+            let (listener, dns_port) = (subnet_config.listener.ip().to_string(), dns_port.to_string());
+            nft!("add", "rule", "inet", table, &chain, addr, "daddr", &listener, "udp", "dport", &dns_port, "return");
+            if let Some(server) = config.dns_server(subnet_config.family()) {
+                let (ip, port) = (server.ip().to_string(), server.port().to_string());
+                nft!("add", "rule", "inet", table, &chain, addr, "daddr", &ip, "udp", "dport", &port, "return");
+            }
+            nft!("add", "rule", "inet", table, &chain, "udp", "dport", "53", "redirect", "to", &dns_to_port);
+        }
+
+        nft!("add", "rule", "inet", table, &chain, "fib", "daddr", "type", "local", "return");
+
+        for subnet in subnet_config.excludes.iter() {
+            let subnet_str = subnet.subnet_str();
+            let ports: Vec<String> = match subnet.ports() {
+                Ports::Single(port) => vec!["tcp".to_string(), "dport".to_string(), port.to_string()],
+                Ports::Range(first, last) => vec!["tcp".to_string(), "dport".to_string(), format!("{first}-{last}")],
+                Ports::None => vec!["meta".to_string(), "l4proto".to_string(), "tcp".to_string()],
+            };
+            let mut ports = ports.iter().map(std::string::String::as_str).collect();
+            let mut cmd = vec!["add", "rule", "inet", table, &chain, addr, "daddr", &subnet_str];
+            cmd.append(&mut ports);
+            cmd.push("return");
+            nft_vec!(cmd);
+        }
+
+        for subnet in subnet_config.includes.iter() {
+            let subnet_str = subnet.subnet_str();
+            let ports: Vec<String> = match subnet.ports() {
+                Ports::Single(port) => vec!["tcp".to_string(), "dport".to_string(), port.to_string()],
+                Ports::Range(first, last) => vec!["tcp".to_string(), "dport".to_string(), format!("{first}-{last}")],
+                Ports::None => vec!["meta".to_string(), "l4proto".to_string(), "tcp".to_string()],
+            };
+            let mut ports = ports.iter().map(std::string::String::as_str).collect();
+            let mut cmd = vec!["add", "rule", "inet", table, &chain, addr, "daddr", &subnet_str];
+            cmd.append(&mut ports);
+            cmd.append(&mut vec!["redirect", "to", &to_port]);
+            nft_vec!(cmd);
+        }
+
+        Ok(())
+    }
+}
+
+impl Firewall for NftablesFirewall {
+    #[rustfmt::skip]
+    fn setup_firewall(&self, config: &FirewallConfig) -> Result<Commands, FirewallError> {
+        let mut commands: Commands = self.restore_firewall(config)?;
+        let table = table_name(config);
+
+        commands.nft(&["add", "table", "inet", &table]);
+        commands.nft(&["add", "chain", "inet", &table, "output", "{ type nat hook output priority -100 ; }"]);
+        commands.nft(&["add", "chain", "inet", &table, "prerouting", "{ type nat hook prerouting priority -100 ; }"]);
+
+        for family in &config.listeners {
+            match family {
+                FirewallListenerConfig::Ipv4(ip) => {
+                    self.setup_family(config, &table, ip, &mut commands)?;
+                }
+                FirewallListenerConfig::Ipv6(ip) => {
+                    self.setup_family(config, &table, ip, &mut commands)?;
+                }
+            }
+        }
+
+        Ok(commands)
+    }
+
+    fn restore_firewall(&self, config: &FirewallConfig) -> Result<Commands, FirewallError> {
+        let mut commands: Commands = Commands::new();
+
+        // Everything lives in our own table, so deleting it removes every chain
+        // and rule in one atomic operation.
+        let table = table_name(config);
+        commands.nft_ignore_errors(&["delete", "table", "inet", &table]);
+
+        Ok(commands)
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod tests {
+    use crate::{
+        command::Line,
+        network::{ListenerAddr, SubnetsV4, SubnetsV6},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_setup_family_v4() {
+        let firewall = NftablesFirewall::new();
+        let ipv4_family = FirewallSubnetConfig {
+            enable: true,
+            listener: ListenerAddr {
+                protocol: Protocol::Tcp,
+                addr: "127.0.0.1:1024".parse().unwrap(),
+            },
+            includes: "1.2.3.0/24:8000-9000".parse::<SubnetsV4>().unwrap(),
+            excludes: "1.2.3.66:8080".parse::<SubnetsV4>().unwrap(),
+        };
+        let config = FirewallConfig {
+            filter_from_user: None,
+            dns_port: None,
+            dns_server: None,
+            listeners: vec![],
+        };
+
+        let expected_ipv4: [&str; 6] = [
+            "nft add chain inet sshuttle-1024 sshuttle-1024",
+            "nft add rule inet sshuttle-1024 output meta nfproto ipv4 jump sshuttle-1024",
+            "nft add rule inet sshuttle-1024 prerouting meta nfproto ipv4 jump sshuttle-1024",
+            "nft add rule inet sshuttle-1024 sshuttle-1024 fib daddr type local return",
+            "nft add rule inet sshuttle-1024 sshuttle-1024 ip daddr 1.2.3.66/32 tcp dport 8080 return",
+            "nft add rule inet sshuttle-1024 sshuttle-1024 ip daddr 1.2.3.0/24 tcp dport 8000-9000 redirect to :1024",
+        ];
+
+        let mut commands = Commands::default();
+        firewall
+            .setup_family(&config, "sshuttle-1024", &ipv4_family, &mut commands)
+            .unwrap();
+        assert_eq!(commands.len(), expected_ipv4.len());
+        for (command, expected_line) in commands.iter().zip(expected_ipv4.iter()) {
+            let split: Vec<String> = expected_line.split(' ').map(ToOwned::to_owned).collect();
+            let expected_command = Line(split[0].clone(), split[1..].to_vec());
+            assert_eq!(command.line, expected_command);
+        }
+    }
+
+    #[test]
+    fn test_setup_family_v4_dns() {
+        let firewall = NftablesFirewall::new();
+        let ipv4_family = FirewallSubnetConfig {
+            enable: true,
+            listener: ListenerAddr {
+                protocol: Protocol::Tcp,
+                addr: "127.0.0.1:1024".parse().unwrap(),
+            },
+            includes: "1.2.3.0/24".parse::<SubnetsV4>().unwrap(),
+            excludes: SubnetsV4::default(),
+        };
+        let config = FirewallConfig {
+            filter_from_user: None,
+            dns_port: Some(1053),
+            dns_server: Some("10.0.0.53:53".parse().unwrap()),
+            listeners: vec![],
+        };
+
+        let expected_ipv4: [&str; 8] = [
+            "nft add chain inet sshuttle-1024 sshuttle-1024",
+            "nft add rule inet sshuttle-1024 output meta nfproto ipv4 jump sshuttle-1024",
+            "nft add rule inet sshuttle-1024 prerouting meta nfproto ipv4 jump sshuttle-1024",
+            "nft add rule inet sshuttle-1024 sshuttle-1024 ip daddr 127.0.0.1 udp dport 1053 return",
+            "nft add rule inet sshuttle-1024 sshuttle-1024 ip daddr 10.0.0.53 udp dport 53 return",
+            "nft add rule inet sshuttle-1024 sshuttle-1024 udp dport 53 redirect to :1053",
+            "nft add rule inet sshuttle-1024 sshuttle-1024 fib daddr type local return",
+            "nft add rule inet sshuttle-1024 sshuttle-1024 ip daddr 1.2.3.0/24 meta l4proto tcp redirect to :1024",
+        ];
+
+        let mut commands = Commands::default();
+        firewall
+            .setup_family(&config, "sshuttle-1024", &ipv4_family, &mut commands)
+            .unwrap();
+        assert_eq!(commands.len(), expected_ipv4.len());
+        for (command, expected_line) in commands.iter().zip(expected_ipv4.iter()) {
+            let split: Vec<String> = expected_line.split(' ').map(ToOwned::to_owned).collect();
+            let expected_command = Line(split[0].clone(), split[1..].to_vec());
+            assert_eq!(command.line, expected_command);
+        }
+    }
+
+    #[test]
+    fn test_setup_family_v6() {
+        let firewall = NftablesFirewall::new();
+        let ipv6_family = FirewallSubnetConfig {
+            enable: true,
+            listener: ListenerAddr {
+                protocol: Protocol::Tcp,
+                addr: "[::1]:1024".parse().unwrap(),
+            },
+            includes: "2404:6800:4004:80c::/64".parse::<SubnetsV6>().unwrap(),
+            excludes: "[2404:6800:4004:80c::101f]:80".parse().unwrap(),
+        };
+        let config = FirewallConfig {
+            filter_from_user: None,
+            dns_port: None,
+            dns_server: None,
+            listeners: vec![],
+        };
+
+        let expected_ipv6: [&str; 6] = [
+            "nft add chain inet sshuttle-1024 sshuttle-1024",
+            "nft add rule inet sshuttle-1024 output meta nfproto ipv6 jump sshuttle-1024",
+            "nft add rule inet sshuttle-1024 prerouting meta nfproto ipv6 jump sshuttle-1024",
+            "nft add rule inet sshuttle-1024 sshuttle-1024 fib daddr type local return",
+            "nft add rule inet sshuttle-1024 sshuttle-1024 ip6 daddr 2404:6800:4004:80c::101f/128 tcp dport 80 return",
+            "nft add rule inet sshuttle-1024 sshuttle-1024 ip6 daddr 2404:6800:4004:80c::/64 meta l4proto tcp redirect to :1024",
+        ];
+
+        let mut commands = Commands::default();
+        firewall
+            .setup_family(&config, "sshuttle-1024", &ipv6_family, &mut commands)
+            .unwrap();
+        assert_eq!(commands.len(), expected_ipv6.len());
+        for (command, expected_line) in commands.iter().zip(expected_ipv6.iter()) {
+            let split: Vec<String> = expected_line.split(' ').map(ToOwned::to_owned).collect();
+            let expected_command = Line(split[0].clone(), split[1..].to_vec());
+            assert_eq!(command.line, expected_command);
+        }
+    }
+
+    #[test]
+    fn test_setup_firewall() {
+        let firewall = NftablesFirewall::new();
+        let config = FirewallConfig {
+            filter_from_user: None,
+            dns_port: None,
+            dns_server: None,
+            listeners: vec![
+                FirewallListenerConfig::Ipv4(FirewallSubnetConfig {
+                    enable: true,
+                    listener: ListenerAddr {
+                        protocol: Protocol::Tcp,
+                        addr: "127.0.0.1:1021".parse().unwrap(),
+                    },
+                    includes: "1.2.3.0/24".parse::<SubnetsV4>().unwrap(),
+                    excludes: SubnetsV4::default(),
+                }),
+                FirewallListenerConfig::Ipv6(FirewallSubnetConfig {
+                    enable: true,
+                    listener: ListenerAddr {
+                        protocol: Protocol::Tcp,
+                        addr: "[::1]:1022".parse().unwrap(),
+                    },
+                    includes: "2404:6800:4004:80c::/64".parse::<SubnetsV6>().unwrap(),
+                    excludes: SubnetsV6::default(),
+                }),
+            ],
+        };
+
+        let expected: Vec<Line> = vec![
+            Line::new("nft", ["delete", "table", "inet", "sshuttle-1021-1022"]),
+            Line::new("nft", ["add", "table", "inet", "sshuttle-1021-1022"]),
+            Line::new(
+                "nft",
+                [
+                    "add",
+                    "chain",
+                    "inet",
+                    "sshuttle-1021-1022",
+                    "output",
+                    "{ type nat hook output priority -100 ; }",
+                ],
+            ),
+            Line::new(
+                "nft",
+                [
+                    "add",
+                    "chain",
+                    "inet",
+                    "sshuttle-1021-1022",
+                    "prerouting",
+                    "{ type nat hook prerouting priority -100 ; }",
+                ],
+            ),
+        ];
+
+        let commands = firewall.setup_firewall(&config).unwrap();
+        assert_eq!(commands.len(), expected.len() + 2 * 5);
+        for (command, expected_command) in commands.iter().zip(expected.iter()) {
+            assert_eq!(&command.line, expected_command);
+        }
+    }
+
+    #[test]
+    fn test_restore_firewall() {
+        let firewall = NftablesFirewall::new();
+        let config = FirewallConfig {
+            filter_from_user: None,
+            dns_port: None,
+            dns_server: None,
+            listeners: vec![FirewallListenerConfig::Ipv4(FirewallSubnetConfig {
+                enable: true,
+                listener: ListenerAddr {
+                    protocol: Protocol::Tcp,
+                    addr: "127.0.0.1:1024".parse().unwrap(),
+                },
+                includes: "1.2.3.0/32".parse::<SubnetsV4>().unwrap(),
+                excludes: "1.2.3.66".parse::<SubnetsV4>().unwrap(),
+            })],
+        };
+
+        let commands = firewall.restore_firewall(&config).unwrap();
+        assert_eq!(commands.len(), 1);
+        let command = commands.iter().next().unwrap();
+        assert!(command.ignore_errors);
+        assert_eq!(
+            command.line,
+            Line::new("nft", ["delete", "table", "inet", "sshuttle-1024"])
+        );
+    }
+}
@@ -1,21 +1,34 @@
+use std::io::{self, IoSliceMut};
 use std::net::SocketAddr;
-use std::os::unix::prelude::AsRawFd;
+use std::os::unix::prelude::{AsRawFd, FromRawFd, RawFd};
 
 use nix::sys::socket::setsockopt;
-use nix::sys::socket::sockopt::IpTransparent;
+use nix::sys::socket::sockopt::{IpTransparent, ReuseAddr};
+use nix::sys::socket::{
+    bind, recvmsg, socket, AddressFamily, ControlMessageOwned, MsgFlags, SockFlag, SockType,
+    SockaddrIn, SockaddrIn6, SockaddrStorage,
+};
+use tokio::io::Interest;
 use tokio::net::TcpListener;
 use tokio::net::TcpStream;
 use tokio::net::UdpSocket;
 
+use crate::network::Family;
 use crate::network::ListenerAddr;
 use crate::network::Ports;
 use crate::network::Protocol;
 use crate::network::SubnetFamily;
 use crate::network::SubnetsFamily;
 
-use super::{Commands, Firewall, FirewallConfig, FirewallError, FirewallSubnetConfig};
+use super::{
+    raw_to_socket_addr_v4, raw_to_socket_addr_v6, Commands, Firewall, FirewallConfig,
+    FirewallError, FirewallSubnetConfig,
+};
 
-pub struct TProxyFirewall {}
+pub struct TProxyFirewall {
+    fwmark: u32,
+    route_table: u32,
+}
 
 fn chain_name(listener: &ListenerAddr, name: &str) -> String {
     match listener.protocol {
@@ -24,9 +37,140 @@ fn chain_name(listener: &ListenerAddr, name: &str) -> String {
     }
 }
 
+pub const fn local_route(family: Family) -> &'static str {
+    match family {
+        Family::Ipv4 => "0.0.0.0/0",
+        Family::Ipv6 => "::/0",
+    }
+}
+
+fn listener_families(config: &FirewallConfig) -> Vec<Family> {
+    let mut families = Vec::new();
+    for family in &config.listeners {
+        let family = match family {
+            super::FirewallListenerConfig::Ipv4(ip) => ip.family(),
+            super::FirewallListenerConfig::Ipv6(ip) => ip.family(),
+        };
+        if !families.contains(&family) {
+            families.push(family);
+        }
+    }
+    families
+}
+
+fn storage_to_socket_addr(addr: &SockaddrStorage) -> Option<SocketAddr> {
+    addr.as_sockaddr_in().map_or_else(
+        || addr.as_sockaddr_in6().map(|a| SocketAddr::V6((*a).into())),
+        |a| Some(SocketAddr::V4((*a).into())),
+    )
+}
+
+fn recvmsg_orig_dst(
+    fd: RawFd,
+    buf: &mut [u8],
+) -> Result<(usize, Option<SocketAddr>, Option<SocketAddr>), nix::Error> {
+    let mut iov = [IoSliceMut::new(buf)];
+    let mut cmsg_buffer = nix::cmsg_space!(libc::sockaddr_in6);
+    let msg = recvmsg::<SockaddrStorage>(fd, &mut iov, Some(&mut cmsg_buffer), MsgFlags::empty())?;
+
+    let mut dst = None;
+    for cmsg in msg.cmsgs() {
+        match cmsg {
+            ControlMessageOwned::Ipv4OrigDstAddr(a) => dst = raw_to_socket_addr_v4(a),
+            ControlMessageOwned::Ipv6OrigDstAddr(a) => dst = raw_to_socket_addr_v6(a),
+            _ => {}
+        }
+    }
+    let src = msg.address.as_ref().and_then(storage_to_socket_addr);
+
+    Ok((msg.bytes, src, dst))
+}
+
+/// Receive a datagram redirected by TPROXY.
+///
+/// Returns the number of bytes read, the sender, and the address the datagram was
+/// originally sent to. The socket must have been set up with `setup_udp_socket`.
+pub async fn recv_from_orig_dst(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> Result<(usize, SocketAddr, SocketAddr), FirewallError> {
+    let fd = socket.as_raw_fd();
+    loop {
+        socket.readable().await?;
+        let result = socket.try_io(Interest::READABLE, || {
+            recvmsg_orig_dst(fd, buf).map_err(io::Error::from)
+        });
+        match result {
+            Ok((len, Some(src), Some(dst))) => return Ok((len, src, dst)),
+            Ok(_) => return Err(FirewallError::CannotGetDstAddress),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// Create a UDP socket bound to a (possibly non-local) address.
+///
+/// Used to send replies that appear to come from the address the client
+/// originally sent its datagram to.
+pub fn bind_transparent_udp(addr: SocketAddr) -> Result<UdpSocket, FirewallError> {
+    let family = match addr {
+        SocketAddr::V4(_) => AddressFamily::Inet,
+        SocketAddr::V6(_) => AddressFamily::Inet6,
+    };
+    let fd = socket(
+        family,
+        SockType::Datagram,
+        SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+    // Take ownership straight away, so the fd is closed on error.
+    let std_socket = unsafe { std::net::UdpSocket::from_raw_fd(fd) };
+
+    setsockopt(fd, IpTransparent, &true)?;
+    setsockopt(fd, ReuseAddr, &true)?;
+    match addr {
+        SocketAddr::V4(a) => bind(fd, &SockaddrIn::from(a))?,
+        SocketAddr::V6(a) => bind(fd, &SockaddrIn6::from(a))?,
+    }
+
+    Ok(UdpSocket::from_std(std_socket)?)
+}
+
 impl TProxyFirewall {
-    pub const fn new() -> Self {
-        TProxyFirewall {}
+    pub const fn new(fwmark: u32, route_table: u32) -> Self {
+        TProxyFirewall {
+            fwmark,
+            route_table,
+        }
+    }
+
+    fn tmark(&self) -> String {
+        format!("{:#04x}", self.fwmark)
+    }
+
+    /// Packets marked by the mangle rules need to be delivered locally, so that
+    /// the TPROXY target can hand them to our listener.
+    ///
+    /// The routing is shared by every session using the same mark and table, so
+    /// adding the rule fails harmlessly when it is already there, and the route
+    /// is replaced rather than added.
+    #[rustfmt::skip]
+    fn setup_routing(&self, family: Family, commands: &mut Commands) {
+        let tmark = self.tmark();
+        let table = self.route_table.to_string();
+
+        commands.ip_ignore_errors(family, &["rule", "add", "fwmark", &tmark, "lookup", &table]);
+        commands.ip(family, &["route", "replace", "local", local_route(family), "dev", "lo", "table", &table]);
+    }
+
+    #[rustfmt::skip]
+    fn restore_routing(&self, family: Family, commands: &mut Commands) {
+        let tmark = self.tmark();
+        let table = self.route_table.to_string();
+
+        commands.ip_ignore_errors(family, &["rule", "del", "fwmark", &tmark, "lookup", &table]);
+        commands.ip_ignore_errors(family, &["route", "del", "local", local_route(family), "dev", "lo", "table", &table]);
     }
 
     #[rustfmt::skip]
@@ -45,8 +189,8 @@ impl TProxyFirewall {
         let tproxy_chain = chain_name(&subnet_config.listener, "t");
         let divert_chain = chain_name(&subnet_config.listener, "d");
         let family = subnet_config.family();
-        // FIXME
-        let tmark = "0x01";
+        let tmark = self.tmark();
+        let tmark = tmark.as_str();
 
         macro_rules! ipm {
             ( $( $e:expr),* ) => {
@@ -70,10 +214,10 @@ impl TProxyFirewall {
         ipm!("-F", &tproxy_chain);
 
         if let Some(user) = &config.filter_from_user {
-            ipm!("-I", "OUTPUT", "1", "-m", "owner", "--uid-owner", user, "-j", "MARK", "set-mark", &port);
-
-            ipm!("-I", "OUTPUT", "1", "-m", "mark", "--mark", &port, "-j", &mark_chain);
-            ipm!("-I", "PREROUTING", "1", "-m", "mark","--mark", &port, "-j", &tproxy_chain);
+            // Only the user's packets get marked for local delivery, so only
+            // marked packets need to go through TPROXY.
+            ipm!("-I", "OUTPUT", "1", "-m", "owner", "--uid-owner", user, "-j", &mark_chain);
+            ipm!("-I", "PREROUTING", "1", "-m", "mark", "--mark", tmark, "-j", &tproxy_chain);
         } else {
             ipm!("-I", "OUTPUT", "1", "-j", &mark_chain);
             ipm!("-I", "PREROUTING", "1", "-j", &tproxy_chain);
@@ -86,6 +230,24 @@ impl TProxyFirewall {
         ipm!("-A", &divert_chain, "-j", "ACCEPT");
         ipm!("-A", &tproxy_chain, "-m", "socket", "-j", &divert_chain, "-m", protocol, "-p", protocol);
 
+        // DNS is always UDP, so it is handled by the TCP chains even if there is no
+        // UDP listener.
+        // Requests to local resolvers such as 127.0.0.53 are caught too, but not those
+        // to our listener or the DNS server.
+        if let (Some(dns_port), Protocol::Tcp) = (config.dns_port, subnet_config.listener.protocol) {
+            let dns_port = dns_port.to_string();
+            let listener = subnet_config.listener.ip().to_string();
+            ipm!("-A", &mark_chain, "-j", "RETURN", "--dest", &listener, "-m", "udp", "-p", "udp", "--dport", &dns_port);
+            ipm!("-A", &tproxy_chain, "-j", "RETURN", "--dest", &listener, "-m", "udp", "-p", "udp", "--dport", &dns_port);
+            if let Some(server) = config.dns_server(family) {
+                let (ip, port) = (server.ip().to_string(), server.port().to_string());
+                ipm!("-A", &mark_chain, "-j", "RETURN", "--dest", &ip, "-m", "udp", "-p", "udp", "--dport", &port);
+                ipm!("-A", &tproxy_chain, "-j", "RETURN", "--dest", &ip, "-m", "udp", "-p", "udp", "--dport", &port);
+            }
+            ipm!("-A", &mark_chain, "-j", "MARK", "--set-mark", tmark, "-m", "udp", "-p", "udp", "--dport", "53");
+            ipm!("-A", &tproxy_chain, "-j", "TPROXY", "--tproxy-mark", tmark, "-m", "udp", "-p", "udp", "--dport", "53", "--on-port", &dns_port);
+        }
+
         for subnet in subnet_config.excludes.iter() {
             let subnet_str = subnet.subnet_str();
             let ports: Vec<String> = match subnet.ports() {
@@ -138,12 +300,12 @@ impl TProxyFirewall {
         subnet_config: &FirewallSubnetConfig<T>,
         commands: &mut Commands,
     )  {
-        let port = subnet_config.listener.port().to_string();
         let mark_chain = chain_name(&subnet_config.listener, "m");
         let tproxy_chain = chain_name(&subnet_config.listener, "t");
         let divert_chain = chain_name(&subnet_config.listener, "d");
         let family = subnet_config.family();
-
+        let tmark = self.tmark();
+        let tmark = tmark.as_str();
 
         macro_rules! ipm {
             ( $( $e:expr),* ) => {
@@ -153,9 +315,8 @@ impl TProxyFirewall {
         }
 
         if let Some(user) = &config.filter_from_user {
-            ipm!("-D", "OUTPUT", "-m", "owner", "--uid-owner", user, "-j", "MARK", "--set-mark", &port);
-            ipm!("-D", "OUTPUT", "-m", "mark", "--mark", &port, "-j", &mark_chain);
-            ipm!("-D", "PREROUTING", "1", "-m", "mark", "--mark", &port, "-j", &tproxy_chain);
+            ipm!("-D", "OUTPUT", "-m", "owner", "--uid-owner", user, "-j", &mark_chain);
+            ipm!("-D", "PREROUTING", "-m", "mark", "--mark", tmark, "-j", &tproxy_chain);
         } else {
             ipm!("-D", "OUTPUT", "-j", &mark_chain);
             ipm!("-D", "PREROUTING", "-j", &tproxy_chain);
@@ -217,7 +378,19 @@ impl Firewall for TProxyFirewall {
     }
 
     fn setup_firewall(&self, config: &FirewallConfig) -> Result<Commands, FirewallError> {
-        let mut commands: Commands = self.restore_firewall(config)?;
+        let mut commands: Commands = Commands::new();
+
+        // Remove our chains, but not the routing another session may be using.
+        for family in &config.listeners {
+            match family {
+                super::FirewallListenerConfig::Ipv4(ip) => {
+                    self.restore_family(config, ip, &mut commands);
+                }
+                super::FirewallListenerConfig::Ipv6(ip) => {
+                    self.restore_family(config, ip, &mut commands);
+                }
+            }
+        }
 
         for family in &config.listeners {
             match family {
@@ -230,6 +403,10 @@ impl Firewall for TProxyFirewall {
             }
         }
 
+        for family in listener_families(config) {
+            self.setup_routing(family, &mut commands);
+        }
+
         Ok(commands)
     }
     fn restore_firewall(&self, config: &FirewallConfig) -> Result<Commands, FirewallError> {
@@ -246,6 +423,10 @@ impl Firewall for TProxyFirewall {
             }
         }
 
+        for family in listener_families(config) {
+            self.restore_routing(family, &mut commands);
+        }
+
         Ok(commands)
     }
 }
@@ -255,6 +436,7 @@ impl Firewall for TProxyFirewall {
 mod tests {
     use crate::{
         command::Line,
+        firewall::FirewallListenerConfig,
         network::{SubnetsV4, SubnetsV6},
     };
 
@@ -262,7 +444,7 @@ mod tests {
 
     #[test]
     fn test_setup_family_v4_tcp() {
-        let firewall = TProxyFirewall::new();
+        let firewall = TProxyFirewall::new(0x01, 100);
         let ipv4_family = FirewallSubnetConfig {
             enable: true,
             listener: ListenerAddr {
@@ -274,6 +456,8 @@ mod tests {
         };
         let config = FirewallConfig {
             filter_from_user: None,
+            dns_port: None,
+            dns_server: None,
             listeners: vec![],
         };
 
@@ -307,9 +491,154 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_setup_family_v4_tcp_dns() {
+        let firewall = TProxyFirewall::new(0x01, 100);
+        let ipv4_family = FirewallSubnetConfig {
+            enable: true,
+            listener: ListenerAddr {
+                protocol: Protocol::Tcp,
+                addr: "127.0.0.1:1024".parse().unwrap(),
+            },
+            includes: "1.2.3.0/24".parse::<SubnetsV4>().unwrap(),
+            excludes: SubnetsV4::default(),
+        };
+        let config = FirewallConfig {
+            filter_from_user: None,
+            dns_port: Some(1053),
+            dns_server: Some("10.0.0.53:53".parse().unwrap()),
+            listeners: vec![],
+        };
+
+        let expected_dns: [&str; 6] = [
+            "iptables -w -t mangle -A sshuttle-m-tcp-1024 -j RETURN --dest 127.0.0.1 -m udp -p udp --dport 1053",
+            "iptables -w -t mangle -A sshuttle-t-tcp-1024 -j RETURN --dest 127.0.0.1 -m udp -p udp --dport 1053",
+            "iptables -w -t mangle -A sshuttle-m-tcp-1024 -j RETURN --dest 10.0.0.53 -m udp -p udp --dport 53",
+            "iptables -w -t mangle -A sshuttle-t-tcp-1024 -j RETURN --dest 10.0.0.53 -m udp -p udp --dport 53",
+            "iptables -w -t mangle -A sshuttle-m-tcp-1024 -j MARK --set-mark 0x01 -m udp -p udp --dport 53",
+            "iptables -w -t mangle -A sshuttle-t-tcp-1024 -j TPROXY --tproxy-mark 0x01 -m udp -p udp --dport 53 --on-port 1053",
+        ];
+
+        let mut commands = Commands::default();
+        firewall.setup_family(&config, &ipv4_family, &mut commands);
+        let commands: Vec<_> = commands
+            .iter()
+            .filter(|c| c.line.1.iter().any(|arg| arg == "udp"))
+            .collect();
+        assert_eq!(commands.len(), expected_dns.len());
+        for (command, expected_line) in commands.iter().zip(expected_dns.iter()) {
+            let split: Vec<String> = expected_line.split(' ').map(ToOwned::to_owned).collect();
+            let expected_command = Line(split[0].clone(), split[1..].to_vec());
+            assert_eq!(command.line, expected_command);
+        }
+    }
+
+    #[test]
+    fn test_setup_family_v4_udp_dns() {
+        let firewall = TProxyFirewall::new(0x01, 100);
+        let ipv4_family = FirewallSubnetConfig {
+            enable: true,
+            listener: ListenerAddr {
+                protocol: Protocol::Udp,
+                addr: "127.0.0.1:1024".parse().unwrap(),
+            },
+            includes: "1.2.3.0/24".parse::<SubnetsV4>().unwrap(),
+            excludes: SubnetsV4::default(),
+        };
+        let config = FirewallConfig {
+            filter_from_user: None,
+            dns_port: Some(1053),
+            dns_server: None,
+            listeners: vec![],
+        };
+
+        let mut commands = Commands::default();
+        firewall.setup_family(&config, &ipv4_family, &mut commands);
+        assert!(!commands
+            .iter()
+            .any(|c| c.line.1.contains(&"53".to_string())));
+    }
+
+    /// The rules in the built in chains, which jump to ours.
+    fn is_builtin_jump(command: &crate::commands::Command) -> bool {
+        let Line(_, args) = &command.line;
+        args.iter()
+            .any(|arg| arg == "OUTPUT" || arg == "PREROUTING")
+    }
+
+    #[test]
+    fn test_setup_family_v4_tcp_user() {
+        let firewall = TProxyFirewall::new(0x01, 100);
+        let ipv4_family = FirewallSubnetConfig {
+            enable: true,
+            listener: ListenerAddr {
+                protocol: Protocol::Tcp,
+                addr: "127.0.0.1:1024".parse().unwrap(),
+            },
+            includes: "1.2.3.0/24".parse::<SubnetsV4>().unwrap(),
+            excludes: SubnetsV4::default(),
+        };
+        let config = FirewallConfig {
+            filter_from_user: Some("1000".to_string()),
+            dns_port: None,
+            dns_server: None,
+            listeners: vec![],
+        };
+
+        let expected_jumps: [&str; 2] = [
+            "iptables -w -t mangle -I OUTPUT 1 -m owner --uid-owner 1000 -j sshuttle-m-tcp-1024",
+            "iptables -w -t mangle -I PREROUTING 1 -m mark --mark 0x01 -j sshuttle-t-tcp-1024",
+        ];
+
+        let mut commands = Commands::default();
+        firewall.setup_family(&config, &ipv4_family, &mut commands);
+        let commands: Vec<_> = commands.iter().filter(|c| is_builtin_jump(c)).collect();
+        assert_eq!(commands.len(), expected_jumps.len());
+        for (command, expected_line) in commands.iter().zip(expected_jumps.iter()) {
+            let split: Vec<String> = expected_line.split(' ').map(ToOwned::to_owned).collect();
+            let expected_command = Line(split[0].clone(), split[1..].to_vec());
+            assert_eq!(command.line, expected_command);
+        }
+    }
+
+    #[test]
+    fn test_restore_family_v4_tcp_user() {
+        let firewall = TProxyFirewall::new(0x01, 100);
+        let ipv4_family = FirewallSubnetConfig {
+            enable: true,
+            listener: ListenerAddr {
+                protocol: Protocol::Tcp,
+                addr: "127.0.0.1:1024".parse().unwrap(),
+            },
+            includes: "1.2.3.0/24".parse::<SubnetsV4>().unwrap(),
+            excludes: SubnetsV4::default(),
+        };
+        let config = FirewallConfig {
+            filter_from_user: Some("1000".to_string()),
+            dns_port: None,
+            dns_server: None,
+            listeners: vec![],
+        };
+
+        let expected_jumps: [&str; 2] = [
+            "iptables -w -t mangle -D OUTPUT -m owner --uid-owner 1000 -j sshuttle-m-tcp-1024",
+            "iptables -w -t mangle -D PREROUTING -m mark --mark 0x01 -j sshuttle-t-tcp-1024",
+        ];
+
+        let mut commands = Commands::default();
+        firewall.restore_family(&config, &ipv4_family, &mut commands);
+        let commands: Vec<_> = commands.iter().filter(|c| is_builtin_jump(c)).collect();
+        assert_eq!(commands.len(), expected_jumps.len());
+        for (command, expected_line) in commands.iter().zip(expected_jumps.iter()) {
+            let split: Vec<String> = expected_line.split(' ').map(ToOwned::to_owned).collect();
+            let expected_command = Line(split[0].clone(), split[1..].to_vec());
+            assert_eq!(command.line, expected_command);
+        }
+    }
+
     #[test]
     fn test_setup_family_v6_tcp() {
-        let firewall = TProxyFirewall::new();
+        let firewall = TProxyFirewall::new(0x01, 100);
         let ipv6_family = FirewallSubnetConfig {
             enable: true,
             listener: ListenerAddr {
@@ -323,6 +652,8 @@ mod tests {
         };
         let config = FirewallConfig {
             filter_from_user: None,
+            dns_port: None,
+            dns_server: None,
             listeners: vec![],
         };
         let expected_ipv6: [&str; 17] = [
@@ -356,7 +687,7 @@ mod tests {
 
     #[test]
     fn test_restore_family_v4_tcp() {
-        let firewall = TProxyFirewall::new();
+        let firewall = TProxyFirewall::new(0x01, 100);
         let ipv4_family = FirewallSubnetConfig {
             enable: true,
             listener: ListenerAddr {
@@ -368,6 +699,8 @@ mod tests {
         };
         let config = FirewallConfig {
             filter_from_user: None,
+            dns_port: None,
+            dns_server: None,
             listeners: vec![],
         };
 
@@ -394,7 +727,7 @@ mod tests {
 
     #[test]
     fn test_restore_family_v6_tcp() {
-        let firewall = TProxyFirewall::new();
+        let firewall = TProxyFirewall::new(0x01, 100);
         let ipv6_family = FirewallSubnetConfig {
             enable: true,
             listener: ListenerAddr {
@@ -406,6 +739,8 @@ mod tests {
         };
         let config = FirewallConfig {
             filter_from_user: None,
+            dns_port: None,
+            dns_server: None,
             listeners: vec![],
         };
         let expected_ipv6: [&str; 8] = [
@@ -431,7 +766,7 @@ mod tests {
 
     #[test]
     fn test_setup_family_v4_udp() {
-        let firewall = TProxyFirewall::new();
+        let firewall = TProxyFirewall::new(0x01, 100);
         let ipv4_family = FirewallSubnetConfig {
             enable: true,
             listener: ListenerAddr {
@@ -443,6 +778,8 @@ mod tests {
         };
         let config = FirewallConfig {
             filter_from_user: None,
+            dns_port: None,
+            dns_server: None,
             listeners: vec![],
         };
 
@@ -478,7 +815,7 @@ mod tests {
 
     #[test]
     fn test_setup_family_v6_udp() {
-        let firewall = TProxyFirewall::new();
+        let firewall = TProxyFirewall::new(0x01, 100);
         let ipv6_family = FirewallSubnetConfig {
             enable: true,
             listener: ListenerAddr {
@@ -492,6 +829,8 @@ mod tests {
         };
         let config = FirewallConfig {
             filter_from_user: None,
+            dns_port: None,
+            dns_server: None,
             listeners: vec![],
         };
         let expected_ipv6: [&str; 17] = [
@@ -525,7 +864,7 @@ mod tests {
 
     #[test]
     fn test_restore_family_v4_udp() {
-        let firewall = TProxyFirewall::new();
+        let firewall = TProxyFirewall::new(0x01, 100);
         let ipv4_family = FirewallSubnetConfig {
             enable: true,
             listener: ListenerAddr {
@@ -537,6 +876,8 @@ mod tests {
         };
         let config = FirewallConfig {
             filter_from_user: None,
+            dns_port: None,
+            dns_server: None,
             listeners: vec![],
         };
 
@@ -563,7 +904,7 @@ mod tests {
 
     #[test]
     fn test_restore_family_v6_udp() {
-        let firewall = TProxyFirewall::new();
+        let firewall = TProxyFirewall::new(0x01, 100);
         let ipv6_family = FirewallSubnetConfig {
             enable: true,
             listener: ListenerAddr {
@@ -575,6 +916,8 @@ mod tests {
         };
         let config = FirewallConfig {
             filter_from_user: None,
+            dns_port: None,
+            dns_server: None,
             listeners: vec![],
         };
         let expected_ipv6: [&str; 8] = [
@@ -597,4 +940,81 @@ mod tests {
             assert_eq!(command.line, expected_command);
         }
     }
+
+    fn routing_config() -> FirewallConfig {
+        let listener = |protocol, addr: &str| {
+            FirewallListenerConfig::Ipv4(FirewallSubnetConfig {
+                enable: true,
+                listener: ListenerAddr {
+                    protocol,
+                    addr: addr.parse().unwrap(),
+                },
+                includes: "1.2.3.0/24".parse::<SubnetsV4>().unwrap(),
+                excludes: SubnetsV4::default(),
+            })
+        };
+        FirewallConfig {
+            filter_from_user: None,
+            dns_port: None,
+            dns_server: None,
+            listeners: vec![
+                listener(Protocol::Tcp, "127.0.0.1:1024"),
+                listener(Protocol::Udp, "127.0.0.1:1024"),
+                FirewallListenerConfig::Ipv6(FirewallSubnetConfig {
+                    enable: true,
+                    listener: ListenerAddr {
+                        protocol: Protocol::Tcp,
+                        addr: "[::1]:1024".parse().unwrap(),
+                    },
+                    includes: "2404:6800:4004:80c::/64".parse::<SubnetsV6>().unwrap(),
+                    excludes: SubnetsV6::default(),
+                }),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_setup_firewall_routing() {
+        let firewall = TProxyFirewall::new(0x2a, 200);
+        let config = routing_config();
+
+        let expected: [&str; 4] = [
+            "ip -4 rule add fwmark 0x2a lookup 200",
+            "ip -4 route replace local 0.0.0.0/0 dev lo table 200",
+            "ip -6 rule add fwmark 0x2a lookup 200",
+            "ip -6 route replace local ::/0 dev lo table 200",
+        ];
+
+        let commands = firewall.setup_firewall(&config).unwrap();
+        let routing: Vec<_> = commands.iter().filter(|c| c.line.0 == "ip").collect();
+        assert_eq!(routing.len(), expected.len());
+        for (command, expected_line) in routing.iter().zip(expected.iter()) {
+            let split: Vec<String> = expected_line.split(' ').map(ToOwned::to_owned).collect();
+            let expected_command = Line(split[0].clone(), split[1..].to_vec());
+            assert_eq!(command.line, expected_command);
+        }
+    }
+
+    #[test]
+    fn test_restore_firewall_routing() {
+        let firewall = TProxyFirewall::new(0x2a, 200);
+        let config = routing_config();
+
+        let expected: [&str; 4] = [
+            "ip -4 rule del fwmark 0x2a lookup 200",
+            "ip -4 route del local 0.0.0.0/0 dev lo table 200",
+            "ip -6 rule del fwmark 0x2a lookup 200",
+            "ip -6 route del local ::/0 dev lo table 200",
+        ];
+
+        let commands = firewall.restore_firewall(&config).unwrap();
+        let routing: Vec<_> = commands.iter().filter(|c| c.line.0 == "ip").collect();
+        assert_eq!(routing.len(), expected.len());
+        for (command, expected_line) in routing.iter().zip(expected.iter()) {
+            let split: Vec<String> = expected_line.split(' ').map(ToOwned::to_owned).collect();
+            let expected_command = Line(split[0].clone(), split[1..].to_vec());
+            assert_eq!(command.line, expected_command);
+            assert!(command.ignore_errors);
+        }
+    }
 }
@@ -0,0 +1,311 @@
+//! Run the firewall commands in a separate privileged process.
+//!
+//! The client starts `<program> sshuttle_rust --firewall-helper-mode`, where the
+//! program is something like sudo or doas, and sends it a `HelperRequest` as a
+//! line of JSON on stdin. The helper sets up the firewall and replies with
+//! `READY`. It then waits for stdin to be closed, which happens however the
+//! client exits, and restores the firewall.
+
+use std::process::{ExitStatus, Stdio};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdin, Command};
+use tokio::select;
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::client::{firewall_commands, get_firewall, guard_firewall, ClientError};
+use crate::firewall::{FirewallConfig, FirewallListenerConfig, FirewallSubnetConfig};
+use crate::network::{Family, Ports, SubnetFamily, SubnetsFamily};
+use crate::options::FirewallType;
+
+const READY: &str = "READY";
+
+/// More listeners or subnets than anyone needs, to bound the rules a request adds.
+const MAX_LISTENERS: usize = 16;
+const MAX_SUBNETS: usize = 1024;
+
+/// Everything the helper needs to set up and restore the firewall.
+#[derive(Serialize, Deserialize)]
+pub struct HelperRequest {
+    pub firewall: FirewallType,
+    pub tproxy_mark: u32,
+    pub tproxy_table: u32,
+    pub iptables_restore: bool,
+    pub config: FirewallConfig,
+}
+
+fn invalid(message: &str) -> String {
+    format!("invalid request: {message}")
+}
+
+const fn valid_ports(ports: Ports) -> bool {
+    match ports {
+        Ports::None => true,
+        Ports::Single(port) => port != 0,
+        Ports::Range(start, end) => start != 0 && start <= end,
+    }
+}
+
+fn check_subnets<T: SubnetsFamily>(config: &FirewallSubnetConfig<T>) -> Result<(), String> {
+    if config.listener.addr.port() == 0 {
+        return Err(invalid("listener port 0"));
+    }
+    let max_cidr = match config.family() {
+        Family::Ipv4 => 32,
+        Family::Ipv6 => 128,
+    };
+    for subnets in [&config.includes, &config.excludes] {
+        if subnets.iter().len() > MAX_SUBNETS {
+            return Err(invalid("too many subnets"));
+        }
+        for subnet in subnets.iter() {
+            if subnet.cidr() > max_cidr {
+                return Err(invalid(&format!("subnet {}", subnet.subnet_str())));
+            }
+            if !valid_ports(subnet.ports()) {
+                return Err(invalid(&format!("ports of {}", subnet.subnet_str())));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The uid of the user who ran sudo or doas.
+fn caller_uid() -> Option<String> {
+    std::env::var("SUDO_UID")
+        .or_else(|_| std::env::var("DOAS_UID"))
+        .ok()
+}
+
+/// The helper runs as root on behalf of `caller`, so it only lets them redirect
+/// their own traffic, with a bounded number of rules.
+fn validate(request: &HelperRequest, caller: Option<&str>) -> Result<(), String> {
+    if matches!(request.firewall, FirewallType::TProxy) {
+        return Err(invalid("the tproxy firewall is not supported"));
+    }
+
+    let caller = caller.ok_or_else(|| invalid("SUDO_UID or DOAS_UID is not set"))?;
+    match &request.config.filter_from_user {
+        Some(user) if user.parse::<u32>().is_ok() && user == caller => {}
+        _ => {
+            return Err(invalid(
+                "only the traffic of the calling user can be forwarded",
+            ))
+        }
+    }
+
+    if request.config.dns_port == Some(0) {
+        return Err(invalid("DNS port 0"));
+    }
+    if request.config.listeners.len() > MAX_LISTENERS {
+        return Err(invalid("too many listeners"));
+    }
+    for listener in &request.config.listeners {
+        match listener {
+            FirewallListenerConfig::Ipv4(config) => check_subnets(config)?,
+            FirewallListenerConfig::Ipv6(config) => check_subnets(config)?,
+        }
+    }
+    Ok(())
+}
+
+fn exit_error(status: ExitStatus) -> ClientError {
+    ClientError::Helper(format!("helper exited with {status}"))
+}
+
+/// The client side of a running helper.
+pub struct Helper {
+    child: Child,
+    stdin: ChildStdin,
+}
+
+impl Helper {
+    /// Start the helper with `program`, and wait until it has set up the firewall.
+    pub async fn start(program: &str, request: &HelperRequest) -> Result<Self, ClientError> {
+        let exe = std::env::current_exe()?;
+        log::info!("Starting firewall helper with {program}");
+        let mut child = Command::new(program)
+            .arg(exe)
+            .arg("--firewall-helper-mode")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+
+        let no_pipe = || ClientError::Helper("no pipe to helper".to_string());
+        let mut stdin = child.stdin.take().ok_or_else(no_pipe)?;
+        let stdout = child.stdout.take().ok_or_else(no_pipe)?;
+
+        let mut line =
+            serde_json::to_string(request).map_err(|err| ClientError::Helper(err.to_string()))?;
+        line.push('\n');
+        stdin.write_all(line.as_bytes()).await?;
+
+        let mut reply = String::new();
+        BufReader::new(stdout).read_line(&mut reply).await?;
+        if reply.trim_end() != READY {
+            return Err(exit_error(child.wait().await?));
+        }
+
+        log::info!("Firewall helper is ready");
+        Ok(Self { child, stdin })
+    }
+
+    /// Tell the helper to restore the firewall, and wait for it to exit.
+    pub async fn stop(mut self) -> Result<(), ClientError> {
+        drop(self.stdin);
+        let status = self.child.wait().await?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(exit_error(status))
+        }
+    }
+}
+
+/// The privileged side, run with --firewall-helper-mode.
+pub async fn run() -> Result<(), ClientError> {
+    // Ctrl-C in the terminal reaches the helper as well as the client; the
+    // client closes stdin once it is done.
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+
+    let mut stdin = BufReader::new(tokio::io::stdin());
+    let mut line = String::new();
+    stdin.read_line(&mut line).await?;
+    let request: HelperRequest =
+        serde_json::from_str(&line).map_err(|err| ClientError::Helper(err.to_string()))?;
+    validate(&request, caller_uid().as_deref()).map_err(ClientError::Helper)?;
+
+    let firewall = get_firewall(request.firewall, request.tproxy_mark, request.tproxy_table);
+    let (setup_commands, shutdown_commands) =
+        firewall_commands(firewall.as_ref(), &request.config, request.iptables_restore)?;
+    let (setup_commands, restore) = guard_firewall(setup_commands, shutdown_commands, None).await?;
+
+    log::info!("Setting up firewall {:#?}", setup_commands);
+    let result = setup_commands.run_all().await;
+    if let Err(err) = &result {
+        log::error!("Error setting up firewall, rolling back: {err}");
+    } else {
+        let mut stdout = tokio::io::stdout();
+        stdout.write_all(format!("{READY}\n").as_bytes()).await?;
+        stdout.flush().await?;
+
+        let mut buf = [0u8; 64];
+        loop {
+            select! {
+                res = stdin.read(&mut buf) => {
+                    if matches!(res, Ok(0) | Err(_)) {
+                        log::debug!("Client closed stdin");
+                        break;
+                    }
+                }
+                _ = interrupt.recv() => log::debug!("SIGINT ignored, waiting for the client"),
+                _ = hangup.recv() => log::debug!("SIGHUP ignored, waiting for the client"),
+                _ = terminate.recv() => {
+                    log::info!("SIGTERM received");
+                    break;
+                }
+            }
+        }
+    }
+
+    let restore_result = restore.restore().await;
+    result?;
+    restore_result
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod tests {
+    use crate::network::{ListenerAddr, Protocol, SubnetsV4};
+
+    use super::*;
+
+    fn request() -> HelperRequest {
+        HelperRequest {
+            firewall: FirewallType::Nat,
+            tproxy_mark: 1,
+            tproxy_table: 100,
+            iptables_restore: true,
+            config: FirewallConfig {
+                filter_from_user: Some("1000".to_string()),
+                dns_port: Some(1053),
+                dns_server: None,
+                listeners: vec![FirewallListenerConfig::Ipv4(FirewallSubnetConfig {
+                    enable: true,
+                    listener: ListenerAddr {
+                        protocol: Protocol::Tcp,
+                        addr: "127.0.0.1:1024".parse().unwrap(),
+                    },
+                    includes: "1.2.3.0/24:8000-9000".parse::<SubnetsV4>().unwrap(),
+                    excludes: "1.2.3.66:8080".parse::<SubnetsV4>().unwrap(),
+                })],
+            },
+        }
+    }
+
+    #[test]
+    fn test_request_round_trip() {
+        let request = request();
+
+        let line = serde_json::to_string(&request).unwrap();
+        assert!(!line.contains('\n'));
+        let received: HelperRequest = serde_json::from_str(&line).unwrap();
+
+        let commands = |request: &HelperRequest| {
+            let firewall =
+                get_firewall(request.firewall, request.tproxy_mark, request.tproxy_table);
+            let (setup, restore) =
+                firewall_commands(firewall.as_ref(), &request.config, request.iptables_restore)
+                    .unwrap();
+            (setup.to_shell_script(), restore.to_shell_script())
+        };
+        assert_eq!(commands(&request), commands(&received));
+    }
+
+    fn ipv4(request: &mut HelperRequest) -> &mut FirewallSubnetConfig<SubnetsV4> {
+        match &mut request.config.listeners[0] {
+            FirewallListenerConfig::Ipv4(config) => config,
+            FirewallListenerConfig::Ipv6(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        validate(&request(), Some("1000")).unwrap();
+
+        // Not run through sudo or doas, or by another user.
+        assert!(validate(&request(), None).is_err());
+        assert!(validate(&request(), Some("1001")).is_err());
+
+        // Everyone's traffic, or a user name that could resolve to anyone.
+        let mut unfiltered = request();
+        unfiltered.config.filter_from_user = None;
+        assert!(validate(&unfiltered, Some("1000")).is_err());
+        let mut named = request();
+        named.config.filter_from_user = Some("root".to_string());
+        assert!(validate(&named, Some("root")).is_err());
+
+        let mut tproxy = request();
+        tproxy.firewall = FirewallType::TProxy;
+        assert!(validate(&tproxy, Some("1000")).is_err());
+
+        let mut bad_subnet = request();
+        let config = ipv4(&mut bad_subnet);
+        config.includes.0[0].cidr = 33;
+        assert!(validate(&bad_subnet, Some("1000")).is_err());
+
+        let mut bad_ports = request();
+        let config = ipv4(&mut bad_ports);
+        config.excludes.0[0].ports = Ports::Range(9000, 8000);
+        assert!(validate(&bad_ports, Some("1000")).is_err());
+
+        let mut many = request();
+        let config = ipv4(&mut many);
+        let subnet = config.includes.0[0].clone();
+        config.includes.0 = vec![subnet; MAX_SUBNETS + 1];
+        assert!(validate(&many, Some("1000")).is_err());
+    }
+}
@@ -1,4 +1,58 @@
-use crate::{command::Line, commands::Commands, network::Family};
+use crate::{command::Line, commands::Commands, firewall::FirewallError, network::Family};
+
+/// Rules for one table, in the order they were generated.
+struct RestoreTable {
+    table: String,
+    rules: Vec<String>,
+}
+
+fn ipt_family(cmd: &str) -> Option<Family> {
+    match cmd {
+        "iptables" => Some(Family::Ipv4),
+        "ip6tables" => Some(Family::Ipv6),
+        _ => None,
+    }
+}
+
+/// Split an `iptables -w -t <table> ...` line into its family, table and rule.
+fn parse_ipt(line: &Line) -> Option<(Family, &str, &[String])> {
+    let Line(cmd, args) = line;
+    let family = ipt_family(cmd)?;
+    match args.as_slice() {
+        [w, t, table, rule @ ..] if w == "-w" && t == "-t" => Some((family, table, rule)),
+        _ => None,
+    }
+}
+
+/// Quote `arg` for an iptables-restore rule line.
+///
+/// A line break would start a new rule, and iptables versions disagree on how
+/// backslashes are handled inside quotes, so arguments with control characters,
+/// quotes or backslashes are rejected rather than escaped.
+fn restore_quote(arg: &str) -> Result<String, FirewallError> {
+    if arg.contains(|c: char| c.is_control() || c == '"' || c == '\\') {
+        Err(FirewallError::RestoreArgument(arg.to_string()))
+    } else if arg.is_empty() || arg.contains(char::is_whitespace) {
+        Ok(format!("\"{arg}\""))
+    } else {
+        Ok(arg.to_string())
+    }
+}
+
+fn restore_payload(tables: &[RestoreTable]) -> String {
+    let mut payload = String::new();
+    for table in tables {
+        payload.push('*');
+        payload.push_str(&table.table);
+        payload.push('\n');
+        for rule in &table.rules {
+            payload.push_str(rule);
+            payload.push('\n');
+        }
+        payload.push_str("COMMIT\n");
+    }
+    payload
+}
 
 impl Commands {
     pub fn ipt(&mut self, family: Family, table: &str, extra: &[&str]) {
@@ -26,4 +80,208 @@ impl Commands {
 
         self.push_ignore_errors(Line::new(cmd, args));
     }
+
+    pub fn ip(&mut self, family: Family, extra: &[&str]) {
+        let flag = match family {
+            Family::Ipv4 => "-4",
+            Family::Ipv6 => "-6",
+        };
+
+        let mut args = vec![flag];
+        args.extend(extra);
+
+        self.push(Line::new("ip", args));
+    }
+
+    pub fn ip_ignore_errors(&mut self, family: Family, extra: &[&str]) {
+        let flag = match family {
+            Family::Ipv4 => "-4",
+            Family::Ipv6 => "-6",
+        };
+
+        let mut args = vec![flag];
+        args.extend(extra);
+
+        self.push_ignore_errors(Line::new("ip", args));
+    }
+
+    pub fn nft(&mut self, extra: &[&str]) {
+        self.push(Line::new("nft", extra.to_vec()));
+    }
+
+    pub fn nft_ignore_errors(&mut self, extra: &[&str]) {
+        self.push_ignore_errors(Line::new("nft", extra.to_vec()));
+    }
+
+    /// Render the `iptables`/`ip6tables` commands as one `iptables-restore --noflush`
+    /// run per family.
+    ///
+    /// Each table is committed on its own, so a run is atomic per table, not per
+    /// family: if a later table fails, the tables before it stay committed until the
+    /// restore commands remove them.
+    ///
+    /// Commands that are allowed to fail (such as removing chains left over from a
+    /// previous run) can't be part of a run, so they are kept as separate commands,
+    /// as are commands for other programs. Each run replaces the first rule of its
+    /// family, so relative ordering is preserved.
+    pub fn to_iptables_restore(&self) -> Result<Commands, FirewallError> {
+        let mut payloads: Vec<(Family, Vec<RestoreTable>)> = Vec::new();
+
+        for cmd in self.iter().filter(|cmd| !cmd.ignore_errors) {
+            if let Some((family, table, rule)) = parse_ipt(&cmd.line) {
+                let tables =
+                    if let Some((_, tables)) = payloads.iter_mut().find(|(f, _)| *f == family) {
+                        tables
+                    } else {
+                        payloads.push((family, Vec::new()));
+                        #[allow(clippy::unwrap_used)]
+                        &mut payloads.last_mut().unwrap().1
+                    };
+
+                let rule = rule
+                    .iter()
+                    .map(|arg| restore_quote(arg))
+                    .collect::<Result<Vec<_>, _>>()?
+                    .join(" ");
+                if let Some(t) = tables.iter_mut().find(|t| t.table == table) {
+                    t.rules.push(rule);
+                } else {
+                    tables.push(RestoreTable {
+                        table: table.to_string(),
+                        rules: vec![rule],
+                    });
+                }
+            }
+        }
+
+        let mut commands = Commands::new();
+        let mut emitted: Vec<Family> = Vec::new();
+
+        for cmd in self.iter() {
+            match parse_ipt(&cmd.line) {
+                Some((family, _, _)) if !cmd.ignore_errors => {
+                    if !emitted.contains(&family) {
+                        emitted.push(family);
+                        let restore_cmd = match family {
+                            Family::Ipv4 => "iptables-restore",
+                            Family::Ipv6 => "ip6tables-restore",
+                        };
+                        let tables = payloads
+                            .iter()
+                            .find(|(f, _)| *f == family)
+                            .map_or(&[][..], |(_, tables)| tables.as_slice());
+                        commands.push_with_input(
+                            Line::new(restore_cmd, ["-w", "--noflush"]),
+                            restore_payload(tables),
+                        );
+                    }
+                }
+                _ => match &cmd.input {
+                    Some(input) => commands.push_with_input(cmd.line.clone(), input.clone()),
+                    None if cmd.ignore_errors => commands.push_ignore_errors(cmd.line.clone()),
+                    None => commands.push(cmd.line.clone()),
+                },
+            }
+        }
+
+        Ok(commands)
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_iptables_restore() {
+        let mut commands = Commands::new();
+        commands.ipt_ignore_errors(Family::Ipv4, "nat", &["-X", "sshuttle-1024"]);
+        commands.ipt(Family::Ipv4, "nat", &["-N", "sshuttle-1024"]);
+        commands.ipt(Family::Ipv6, "nat", &["-N", "sshuttle-1025"]);
+        commands.ipt(
+            Family::Ipv4,
+            "mangle",
+            &[
+                "-I",
+                "OUTPUT",
+                "1",
+                "-m",
+                "owner",
+                "--uid-owner",
+                "a user",
+                "-j",
+                "MARK",
+                "--set-mark",
+                "1024",
+            ],
+        );
+        commands.ipt(
+            Family::Ipv4,
+            "nat",
+            &["-A", "sshuttle-1024", "-j", "RETURN"],
+        );
+        commands.push(Line::new("ip", ["rule", "add"]));
+
+        let restore = commands.to_iptables_restore().unwrap();
+        let restore: Vec<_> = restore.iter().collect();
+        assert_eq!(restore.len(), 4);
+
+        assert_eq!(
+            restore[0].line,
+            Line::new("iptables", ["-w", "-t", "nat", "-X", "sshuttle-1024"])
+        );
+        assert!(restore[0].ignore_errors);
+        assert!(restore[0].input.is_none());
+
+        assert_eq!(
+            restore[1].line,
+            Line::new("iptables-restore", ["-w", "--noflush"])
+        );
+        assert!(!restore[1].ignore_errors);
+        assert_eq!(
+            restore[1].input.as_deref().unwrap(),
+            "*nat\n\
+             -N sshuttle-1024\n\
+             -A sshuttle-1024 -j RETURN\n\
+             COMMIT\n\
+             *mangle\n\
+             -I OUTPUT 1 -m owner --uid-owner \"a user\" -j MARK --set-mark 1024\n\
+             COMMIT\n"
+        );
+
+        assert_eq!(
+            restore[2].line,
+            Line::new("ip6tables-restore", ["-w", "--noflush"])
+        );
+        assert_eq!(
+            restore[2].input.as_deref().unwrap(),
+            "*nat\n-N sshuttle-1025\nCOMMIT\n"
+        );
+
+        assert_eq!(restore[3].line, Line::new("ip", ["rule", "add"]));
+        assert!(restore[3].input.is_none());
+    }
+
+    #[test]
+    fn test_restore_quote() {
+        assert_eq!(restore_quote("1024").unwrap(), "1024");
+        assert_eq!(restore_quote("").unwrap(), "\"\"");
+        assert_eq!(restore_quote("a user").unwrap(), "\"a user\"");
+
+        for arg in ["a\nuser", "a\ruser", "a\0user", "a\"user", "a\\user"] {
+            assert!(matches!(
+                restore_quote(arg),
+                Err(FirewallError::RestoreArgument(_))
+            ));
+        }
+
+        let mut commands = Commands::new();
+        commands.ipt(
+            Family::Ipv4,
+            "nat",
+            &["-A", "sshuttle-1024", "-j", "RETURN\n-F OUTPUT"],
+        );
+        assert!(commands.to_iptables_restore().is_err());
+    }
 }
@@ -7,10 +7,21 @@
 #![allow(clippy::use_self)]
 #![allow(clippy::unused_self)]
 
-use std::{error::Error, fmt::Display, process::ExitCode};
+use nix::unistd::{Gid, Group, Uid, User};
+use std::{
+    error::Error,
+    fmt::Display,
+    fs::OpenOptions,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::Duration,
+};
 
 mod network;
 
+mod auto_nets;
+
 mod client;
 use client::Config;
 use network::{ListenerAddr, Subnets};
@@ -24,6 +35,21 @@ mod firewall;
 
 mod linux;
 
+mod daemon;
+use daemon::Daemon;
+
+mod cleanup;
+mod helper;
+mod mux;
+mod privileges;
+mod server;
+mod ssh;
+#[cfg(feature = "builtin-ssh")]
+mod ssh_session;
+mod state;
+mod upstream;
+use upstream::{HttpProxy, Proxy, SocksProxy};
+
 #[derive(Clone, Debug)]
 pub struct ConfigError {
     message: String,
@@ -38,31 +64,347 @@ impl Display for ConfigError {
 // impl Debug for ParseError {}
 impl Error for ConfigError {}
 
-fn options_to_config(opt: &options::Options) -> Result<Config, ConfigError> {
-    let includes = {
-        let mut includes = Vec::new();
-        for list1 in &opt.include {
-            let inner_list = &list1.0;
-            includes.extend(inner_list.iter().cloned());
+fn get_dns_server(opt: &options::Options) -> Result<Option<SocketAddr>, ConfigError> {
+    match (opt.dns, opt.dns_server) {
+        (true, Some(dns_server)) => Ok(Some(dns_server)),
+        (true, None) => Err(ConfigError {
+            message: "--dns requires --dns-server".to_string(),
+        }),
+        (false, _) => Ok(None),
+    }
+}
+
+/// Resolve the --user option to a uid, so the firewall rules don't depend on name lookups.
+fn resolve_user(user: &str) -> Result<String, ConfigError> {
+    if user.parse::<u32>().is_ok() {
+        return Ok(user.to_string());
+    }
+    match User::from_name(user) {
+        Ok(Some(user)) => Ok(user.uid.to_string()),
+        Ok(None) => Err(ConfigError {
+            message: format!("Unknown user {user}"),
+        }),
+        Err(err) => Err(ConfigError {
+            message: format!("Could not look up user {user}: {err}"),
+        }),
+    }
+}
+
+fn resolve_group(group: &str) -> Result<Gid, ConfigError> {
+    if let Ok(gid) = group.parse::<u32>() {
+        return Ok(Gid::from_raw(gid));
+    }
+    match Group::from_name(group) {
+        Ok(Some(group)) => Ok(group.gid),
+        Ok(None) => Err(ConfigError {
+            message: format!("Unknown group {group}"),
+        }),
+        Err(err) => Err(ConfigError {
+            message: format!("Could not look up group {group}: {err}"),
+        }),
+    }
+}
+
+/// Resolve --setuid and --setgid, the group defaults to the primary group of the user.
+fn get_run_as(opt: &options::Options) -> Result<Option<(Uid, Gid)>, ConfigError> {
+    let user = match (&opt.setuid, &opt.setgid) {
+        (Some(user), _) => user,
+        (None, Some(_)) => {
+            return Err(ConfigError {
+                message: "--setgid requires --setuid".to_string(),
+            })
         }
-        Subnets::new(includes)
+        (None, None) => return Ok(None),
     };
 
-    let excludes = {
-        let mut excludes = Vec::new();
-        for list2 in &opt.exclude {
-            let inner_list = &list2.0;
-            excludes.extend(inner_list.iter().cloned());
+    let number = user.parse::<u32>().ok().map(Uid::from_raw);
+    let entry = number
+        .map_or_else(|| User::from_name(user), User::from_uid)
+        .map_err(|err| ConfigError {
+            message: format!("Could not look up user {user}: {err}"),
+        })?;
+
+    let uid = match (&entry, number) {
+        (Some(entry), _) => entry.uid,
+        (None, Some(uid)) => uid,
+        (None, None) => {
+            return Err(ConfigError {
+                message: format!("Unknown user {user}"),
+            })
         }
-        Subnets::new(excludes)
     };
+    let gid = match (&opt.setgid, entry) {
+        (Some(group), _) => resolve_group(group)?,
+        (None, Some(entry)) => entry.gid,
+        (None, None) => {
+            return Err(ConfigError {
+                message: format!("--setgid is required, uid {uid} has no passwd entry"),
+            })
+        }
+    };
+    Ok(Some((uid, gid)))
+}
+
+fn get_listen(opt: &options::Options) -> Vec<ListenerAddr> {
+    let mut listen = Vec::new();
+
+    opt.listen
+        .iter()
+        .map(|l| ListenerAddr {
+            addr: *l,
+            protocol: network::Protocol::Tcp,
+        })
+        .for_each(|l| listen.push(l));
+
+    if opt.udp {
+        opt.listen
+            .iter()
+            .map(|l| ListenerAddr {
+                addr: *l,
+                protocol: network::Protocol::Udp,
+            })
+            .for_each(|l| listen.push(l));
+    }
+
+    listen
+}
+
+fn get_subnets(lists: &[Subnets], files: &[PathBuf]) -> Result<Subnets, ConfigError> {
+    let mut subnets = Vec::new();
+    for list in lists {
+        subnets.extend(list.0.iter().cloned());
+    }
+    for file in files {
+        let lists = network::read_subnets_file(file).map_err(|err| ConfigError {
+            message: err.to_string(),
+        })?;
+        for list in lists {
+            subnets.extend(list.0);
+        }
+    }
+    Ok(Subnets::new(subnets))
+}
+
+/// Check the options that only work with some firewalls.
+fn check_firewall_options(opt: &options::Options) -> Result<(), ConfigError> {
+    if opt.iptables_restore && matches!(opt.firewall, options::FirewallType::Nftables) {
+        return Err(ConfigError {
+            message: "--iptables-restore is not supported by the nftables firewall".to_string(),
+        });
+    }
+
+    if opt.firewall_helper.is_some() && opt.setuid.is_some() {
+        return Err(ConfigError {
+            message: "--setuid is not needed with --firewall-helper".to_string(),
+        });
+    }
+
+    if opt.firewall_helper.is_some() && opt.user.is_some() {
+        return Err(ConfigError {
+            message:
+                "--user is not supported with --firewall-helper, only your own traffic is forwarded"
+                    .to_string(),
+        });
+    }
 
-    if opt.include.is_empty() {
+    if opt.firewall_helper.is_some() && matches!(opt.firewall, options::FirewallType::TProxy) {
+        return Err(ConfigError {
+            message: "--firewall-helper is not supported by the tproxy firewall".to_string(),
+        });
+    }
+
+    // Our own connections to the destinations would be redirected back to the listeners.
+    if opt.direct && opt.user.is_none() {
+        return Err(ConfigError {
+            message: "--direct requires --user, so our own connections are not redirected"
+                .to_string(),
+        });
+    }
+
+    if opt.udp && !matches!(opt.firewall, options::FirewallType::TProxy) {
+        return Err(ConfigError {
+            message: "UDP forwarding requires the tproxy firewall".to_string(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Check the options that don't apply to the built in ssh client.
+fn check_builtin_ssh_options(opt: &options::Options) -> Result<(), ConfigError> {
+    if !cfg!(feature = "builtin-ssh") {
+        return Err(ConfigError {
+            message: "--builtin-ssh requires building with the builtin-ssh feature".to_string(),
+        });
+    }
+
+    if opt.remote.is_none() {
+        return Err(ConfigError {
+            message: "--builtin-ssh requires --remote".to_string(),
+        });
+    }
+
+    if !opt.ssh_option.is_empty() {
+        return Err(ConfigError {
+            message: "--ssh-option is not supported by --builtin-ssh".to_string(),
+        });
+    }
+
+    if opt.udp {
+        return Err(ConfigError {
+            message: "UDP forwarding is not supported by --builtin-ssh".to_string(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Parse --remote, checking it doesn't conflict with --ssh-port.
+fn get_remote(opt: &options::Options) -> Result<Option<ssh::Remote>, ConfigError> {
+    let mut remote = opt
+        .remote
+        .as_deref()
+        .map(str::parse::<ssh::Remote>)
+        .transpose()
+        .map_err(|err| ConfigError {
+            message: err.to_string(),
+        })?;
+    if opt.ssh_port.is_some() && remote.as_ref().and_then(|r| r.port).is_some() {
+        return Err(ConfigError {
+            message: "Only one of --ssh-port and a port in --remote can be given".to_string(),
+        });
+    }
+
+    // The built in client has no command line to pass --ssh-port on.
+    if opt.builtin_ssh {
+        if let Some(remote) = &mut remote {
+            remote.port = remote.port.or(opt.ssh_port);
+        }
+    }
+    Ok(remote)
+}
+
+/// Check the options that don't apply to `sshuttle_rust server`.
+fn check_server_options(opt: &options::Options) -> Result<(), ConfigError> {
+    if opt.remote.is_none() {
+        return Err(ConfigError {
+            message: "--server-cmd requires --remote".to_string(),
+        });
+    }
+
+    if opt.builtin_ssh {
+        return Err(ConfigError {
+            message: "Only one of --server-cmd and --builtin-ssh can be given".to_string(),
+        });
+    }
+
+    Ok(())
+}
+
+/// The socks server to connect to when there is no --remote, and how to talk to it.
+fn get_socks_proxy(opt: &options::Options) -> Result<SocksProxy, ConfigError> {
+    if opt.socks_password.is_some() && opt.socks_user.is_none() {
+        return Err(ConfigError {
+            message: "--socks-password requires --socks-user".to_string(),
+        });
+    }
+
+    if matches!(opt.socks_version, options::SocksVersion::Socks4) {
+        let message = if opt.socks_password.is_some() {
+            Some("SOCKS4 only supports --socks-user, not --socks-password")
+        } else if opt.udp {
+            Some("UDP forwarding is not supported by SOCKS4")
+        } else if opt.listen.iter().any(SocketAddr::is_ipv6) {
+            Some("IPv6 is not supported by SOCKS4")
+        } else {
+            None
+        };
+        if let Some(message) = message {
+            return Err(ConfigError {
+                message: message.to_string(),
+            });
+        }
+    }
+
+    Ok(SocksProxy {
+        addr: opt.socks,
+        version: opt.socks_version,
+        user: opt.socks_user.clone(),
+        password: opt.socks_password.clone(),
+    })
+}
+
+/// What to connect through when there is no --remote.
+fn get_proxy(opt: &options::Options) -> Result<Proxy, ConfigError> {
+    let socks_options = opt.socks_user.is_some()
+        || opt.socks_password.is_some()
+        || !matches!(opt.socks_version, options::SocksVersion::Socks5);
+    if socks_options && (opt.remote.is_some() || opt.http_proxy.is_some() || opt.direct) {
+        return Err(ConfigError {
+            message: "--socks-user, --socks-password and --socks-version only apply to a socks \
+                      server used without --remote"
+                .to_string(),
+        });
+    }
+
+    if opt.http_proxy.is_none() && !opt.direct {
+        return get_socks_proxy(opt).map(Proxy::Socks);
+    }
+
+    if opt.http_proxy.is_some() && opt.direct {
+        return Err(ConfigError {
+            message: "Only one of --http-proxy and --direct can be given".to_string(),
+        });
+    }
+
+    if opt.remote.is_some() {
+        return Err(ConfigError {
+            message: "--http-proxy and --direct can't be used with --remote".to_string(),
+        });
+    }
+
+    if opt.udp && opt.http_proxy.is_some() {
+        return Err(ConfigError {
+            message: "UDP forwarding is not supported by --http-proxy".to_string(),
+        });
+    }
+
+    opt.http_proxy
+        .as_deref()
+        .map_or(Ok(Proxy::Direct), |http_proxy| {
+            http_proxy
+                .parse::<HttpProxy>()
+                .map(Proxy::Http)
+                .map_err(|err| ConfigError {
+                    message: err.to_string(),
+                })
+        })
+}
+
+fn options_to_config(opt: &options::Options) -> Result<Config, ConfigError> {
+    let stdin = Path::new("-");
+    if opt.include_from.iter().any(|p| p == stdin) && opt.exclude_from.iter().any(|p| p == stdin) {
+        return Err(ConfigError {
+            message: "Only one of --include-from and --exclude-from can read from stdin"
+                .to_string(),
+        });
+    }
+
+    let includes = get_subnets(&opt.include, &opt.include_from)?;
+    let excludes = get_subnets(&opt.exclude, &opt.exclude_from)?;
+
+    if includes.len() == 0 && !opt.auto_nets {
         return Err(ConfigError {
             message: "No subnets specified".to_string(),
         });
     }
 
+    if opt.auto_nets && opt.remote.is_none() {
+        return Err(ConfigError {
+            message: "--auto-nets requires --remote".to_string(),
+        });
+    }
+
     let num_ipv4_listen = opt.listen.iter().filter(|l| l.is_ipv4()).count();
     if num_ipv4_listen > 1 {
         return Err(ConfigError {
@@ -100,46 +442,253 @@ fn options_to_config(opt: &options::Options) -> Result<Config, ConfigError> {
         });
     }
 
-    let remote = opt.remote.clone();
+    check_firewall_options(opt)?;
+    if opt.builtin_ssh {
+        check_builtin_ssh_options(opt)?;
+    }
+    if opt.server_cmd.is_some() {
+        check_server_options(opt)?;
+    }
 
-    let listen = {
-        let mut listen = Vec::new();
+    let dns_server = get_dns_server(opt)?;
+    let user = opt.user.as_deref().map(resolve_user).transpose()?;
+    let run_as = get_run_as(opt)?;
+    let proxy = get_proxy(opt)?;
 
-        opt.listen
-            .iter()
-            .map(|l| ListenerAddr {
-                addr: *l,
-                protocol: network::Protocol::Tcp,
-            })
-            .for_each(|l| listen.push(l));
+    let remote = get_remote(opt)?;
+    let ssh_cmd = ssh::ssh_command(
+        &opt.ssh_cmd,
+        &opt.ssh_option,
+        opt.ssh_port,
+        opt.ssh_identity.as_deref(),
+    )
+    .map_err(|err| ConfigError {
+        message: err.to_string(),
+    })?;
 
-        listen
-    };
+    let listen = get_listen(opt);
 
-    let config = Config {
+    Ok(Config {
         includes,
         excludes,
         remote,
+        ssh_cmd,
         listen,
         socks_addr: opt.socks,
+        proxy,
         firewall: opt.firewall,
-    };
+        user,
+        tproxy_mark: opt.tproxy_mark,
+        tproxy_table: opt.tproxy_table,
+        iptables_restore: opt.iptables_restore,
+        udp_timeout: Duration::from_secs(opt.udp_timeout),
+        dns_server,
+        dns_port: opt.dns_port,
+        socks_ready_timeout: Duration::from_secs(opt.socks_ready_timeout),
+        ssh_max_retries: opt.max_retries,
+        hold_connections: opt.hold_connections,
+        firewall_helper: opt.firewall_helper.clone(),
+        run_as,
+        server_cmd: opt.server_cmd.clone(),
+        #[cfg(feature = "builtin-ssh")]
+        builtin_ssh: opt.builtin_ssh.then(|| ssh_session::SessionOptions {
+            identity: opt.ssh_identity.clone(),
+            run_as: run_as.map(|(uid, _)| uid),
+        }),
+    })
+}
+
+/// Add the networks routed by the remote to the includes, for the families we listen on.
+async fn add_auto_nets(config: &mut Config) -> Result<(), Box<dyn Error>> {
+    if let Some(remote) = &config.remote {
+        let has_ipv4 = config.listen.iter().any(|l| l.addr.is_ipv4());
+        let has_ipv6 = config.listen.iter().any(|l| l.addr.is_ipv6());
 
-    Ok(config)
+        for subnet in auto_nets::get_remote_subnets(&config.ssh_cmd, remote, config.run_as).await? {
+            let enabled = match subnet.address {
+                IpAddr::V4(_) => has_ipv4,
+                IpAddr::V6(_) => has_ipv6,
+            };
+            if enabled {
+                log::info!("auto-nets: forwarding {}/{}", subnet.address, subnet.cidr);
+                config.includes.0.push(subnet);
+            } else {
+                log::debug!(
+                    "auto-nets: no listener for {}/{}",
+                    subnet.address,
+                    subnet.cidr
+                );
+            }
+        }
+    }
+
+    if config.includes.len() == 0 {
+        return Err(Box::new(ConfigError {
+            message: "No subnets specified or found on the remote".to_string(),
+        }));
+    }
+    Ok(())
 }
 
-async fn run_client() -> Result<(), Box<dyn Error>> {
-    let opt = options::parse();
-    let config = options_to_config(&opt)?;
-    client::main(&config).await?;
+/// Resolve the remote, or the HTTP proxy, before any firewall rules are set up,
+/// which could redirect the connection to it or its DNS lookup when reconnecting.
+///
+/// With `dry_run`, ssh isn't run to ask where it would connect to, the host of the
+/// remote is looked up instead.
+async fn resolve_remote(config: &mut Config, dry_run: bool) -> Result<(), Box<dyn Error>> {
+    #[cfg(feature = "builtin-ssh")]
+    let ssh_cmd = config
+        .builtin_ssh
+        .is_none()
+        .then_some(config.ssh_cmd.as_slice());
+    #[cfg(not(feature = "builtin-ssh"))]
+    let ssh_cmd = Some(config.ssh_cmd.as_slice());
+    let ssh_cmd = ssh_cmd.filter(|_| !dry_run);
+
+    if let Some(remote) = &mut config.remote {
+        let addr = remote.resolve(ssh_cmd, config.run_as).await?;
+        log::info!("Connecting to {remote} at {addr}, which is excluded from the firewall");
+    }
+    if let Proxy::Http(proxy) = &mut config.proxy {
+        let addr = proxy.resolve().await?;
+        log::info!(
+            "Connecting to HTTP proxy {proxy} at {addr}, which is excluded from the firewall"
+        );
+    }
     Ok(())
 }
 
-#[tokio::main]
-async fn main() -> ExitCode {
-    env_logger::init();
+async fn run_client(
+    opt: &options::Options,
+    mut config: Config,
+    daemon: Option<&mut Daemon>,
+    keeper: Option<privileges::RestoreKeeper>,
+) -> Result<(), Box<dyn Error>> {
+    resolve_remote(&mut config, false).await?;
+    if opt.auto_nets {
+        add_auto_nets(&mut config).await?;
+    }
+    client::main(&config, daemon, keeper).await?;
+    Ok(())
+}
+
+fn init_logging(opt: &options::Options) -> Result<(), Box<dyn Error>> {
+    if let Some(path) = &opt.log_file {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        env_logger::Builder::from_default_env()
+            .target(env_logger::Target::Pipe(Box::new(file)))
+            .init();
+    } else if opt.syslog || opt.daemon {
+        let level = std::env::var("RUST_LOG")
+            .ok()
+            .and_then(|level| level.parse().ok())
+            .unwrap_or(log::LevelFilter::Info);
+        syslog::init(syslog::Facility::LOG_DAEMON, level, Some("sshuttle_rust"))?;
+    } else {
+        env_logger::init();
+    }
+    Ok(())
+}
+
+fn run(opt: &options::Options) -> Result<(), Box<dyn Error>> {
+    if matches!(opt.command, Some(options::Command::Server)) {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+        runtime.block_on(server::run())?;
+        return Ok(());
+    }
+
+    if opt.firewall_helper_mode {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        runtime.block_on(helper::run())?;
+        return Ok(());
+    }
+
+    if opt.cleanup {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        runtime.block_on(cleanup::cleanup(opt.tproxy_mark, opt.tproxy_table))?;
+        return Ok(());
+    }
+
+    let mut config = options_to_config(opt)?;
+
+    if opt.dry_run {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?
+            .block_on(resolve_remote(&mut config, true))?;
+        if opt.auto_nets {
+            log::warn!(
+                "--dry-run doesn't connect to the remote, --auto-nets subnets are not included"
+            );
+        }
+        client::dry_run(&config)?;
+        return Ok(());
+    }
+
+    // Fork before the runtime starts any threads.
+    let mut daemon = if opt.daemon {
+        Some(daemon::daemonize(&opt.pidfile, config.run_as)?)
+    } else {
+        None
+    };
+
+    // Once we switch user, a root process has to restore the firewall for us. The
+    // helper does that when there is one.
+    let keeper = if config.run_as.is_some() && config.firewall_helper.is_none() {
+        Some(privileges::fork_restore_keeper()?)
+    } else {
+        None
+    };
+
+    // Capabilities belong to a thread, so once privileges are dropped everything
+    // that still needs them has to run on this one.
+    let mut builder = if config.run_as.is_some() {
+        tokio::runtime::Builder::new_current_thread()
+    } else {
+        tokio::runtime::Builder::new_multi_thread()
+    };
+    let runtime = builder.enable_all().build()?;
+    runtime.block_on(run_client(opt, config, daemon.as_mut(), keeper))
+}
+
+fn main() -> ExitCode {
+    // ssh runs us as its askpass program when the remote has a password.
+    if let Ok(socket) = std::env::var(ssh::ASKPASS_SOCKET_ENV) {
+        let prompt = std::env::args().nth(1).unwrap_or_default();
+        let hint = std::env::var("SSH_ASKPASS_PROMPT").ok();
+        return match ssh::askpass(&socket, &prompt, hint.as_deref()) {
+            Ok(Some(password)) => {
+                println!("{password}");
+                ExitCode::SUCCESS
+            }
+            Ok(None) => ExitCode::FAILURE,
+            Err(err) => {
+                eprintln!("sshuttle_rust askpass: {err}");
+                ExitCode::FAILURE
+            }
+        };
+    }
 
-    match run_client().await {
+    let opt = match options::parse() {
+        Ok(opt) => opt,
+        Err(err) => {
+            eprintln!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if let Err(err) = init_logging(&opt) {
+        eprintln!("Could not set up logging: {err}");
+        return ExitCode::FAILURE;
+    }
+
+    match run(&opt) {
         Ok(()) => {
             log::info!("Exiting normally");
             ExitCode::SUCCESS
@@ -150,3 +699,40 @@ async fn main() -> ExitCode {
         }
     }
 }
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn options(args: &[&str]) -> options::Options {
+        options::Options::try_parse_from(args).unwrap()
+    }
+
+    #[test]
+    fn test_direct_requires_user() {
+        for firewall in ["nat", "nftables", "tproxy"] {
+            let opt = options(&[
+                "sshuttle_rust",
+                "--direct",
+                "--firewall",
+                firewall,
+                "0.0.0.0/0",
+            ]);
+            let err = check_firewall_options(&opt).unwrap_err();
+            assert!(err.message.contains("--user"), "{}", err.message);
+
+            let opt = options(&[
+                "sshuttle_rust",
+                "--direct",
+                "--user",
+                "1000",
+                "--firewall",
+                firewall,
+                "0.0.0.0/0",
+            ]);
+            check_firewall_options(&opt).unwrap();
+        }
+    }
+}
@@ -0,0 +1,840 @@
+//! The protocol spoken with `sshuttle_rust server` over the stdin and stdout of ssh.
+//!
+//! Everything is sent as frames of a kind byte, a connection id and a payload
+//! length, followed by the payload, all big endian. The client numbers its TCP
+//! connections, UDP flows and DNS queries, and the server answers with the same id.
+//!
+//! One reader serves every connection, so it never waits for one of them. Instead
+//! each side of a TCP connection may only send `WINDOW` data frames more than the
+//! other side has written out, which is granted back with WINDOW frames.
+
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex as StdMutex, PoisonError};
+
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter, DuplexStream};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinHandle;
+
+/// Sent by the server as soon as it starts, so the client knows it speaks the same protocol.
+pub const VERSION: &[u8] = b"sshuttle_rust mux 2";
+
+pub const BUFFER_SIZE: usize = 32 * 1024;
+const MAX_PAYLOAD: usize = 128 * 1024;
+const QUEUE: usize = 64;
+/// Data frames a connection may send before the other side grants it more.
+const WINDOW: u32 = 64;
+/// Grant more credit once this many data frames have been written out.
+const WINDOW_UPDATE: u32 = WINDOW / 2;
+/// The window, plus room for the frames sent without credit, such as EOF and CLOSE.
+const STREAM_QUEUE: usize = WINDOW as usize + 4;
+
+const HELLO: u8 = 0;
+const CONNECT: u8 = 1;
+const CONNECTED: u8 = 2;
+const DATA: u8 = 3;
+const EOF: u8 = 4;
+const CLOSE: u8 = 5;
+const DATAGRAM: u8 = 6;
+const DNS_QUERY: u8 = 7;
+const DNS_ANSWER: u8 = 8;
+const WINDOW_FRAME: u8 = 9;
+
+#[derive(Error, Debug)]
+pub enum MuxError {
+    #[error("IO Error `{0}`")]
+    Io(#[from] std::io::Error),
+
+    #[error("Server speaks `{0}`, expected `sshuttle_rust mux 2`")]
+    Version(String),
+
+    #[error("Connection to the server closed")]
+    Closed,
+
+    #[error("Server Error `{0}`")]
+    Remote(String),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Frame {
+    /// The protocol version, sent by the server once it is ready.
+    Hello(Vec<u8>),
+    /// Open a TCP connection to `addr`.
+    Connect { id: u32, addr: SocketAddr },
+    /// The TCP connection is open.
+    Connected { id: u32 },
+    /// Data on a TCP connection.
+    Data { id: u32, data: Vec<u8> },
+    /// Nothing more will be sent on a TCP connection.
+    Eof { id: u32 },
+    /// The connection, flow or query is over, `error` is empty unless it failed.
+    Close { id: u32, error: String },
+    /// A UDP datagram, sent to `addr` by the client, or received from `addr` by the server.
+    Datagram {
+        id: u32,
+        addr: SocketAddr,
+        data: Vec<u8>,
+    },
+    /// A DNS query for the server at `server`.
+    DnsQuery {
+        id: u32,
+        server: SocketAddr,
+        data: Vec<u8>,
+    },
+    /// The answer to a DNS query.
+    DnsAnswer { id: u32, data: Vec<u8> },
+    /// `frames` more data frames may be sent on a TCP connection.
+    Window { id: u32, frames: u32 },
+}
+
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, message)
+}
+
+fn put_addr(buf: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.push(4);
+            buf.extend(ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(6);
+            buf.extend(ip.octets());
+        }
+    }
+    buf.extend(addr.port().to_be_bytes());
+}
+
+/// Split an address off the front of `payload`.
+fn take_addr(payload: &[u8]) -> Result<(SocketAddr, &[u8]), std::io::Error> {
+    let (ip, rest): (IpAddr, _) = match payload.split_first() {
+        Some((4, rest)) if rest.len() >= 6 => {
+            let octets: [u8; 4] = rest[..4].try_into().map_err(|_| invalid("bad address"))?;
+            (Ipv4Addr::from(octets).into(), &rest[4..])
+        }
+        Some((6, rest)) if rest.len() >= 18 => {
+            let octets: [u8; 16] = rest[..16].try_into().map_err(|_| invalid("bad address"))?;
+            (Ipv6Addr::from(octets).into(), &rest[16..])
+        }
+        _ => return Err(invalid("bad address")),
+    };
+    let port = u16::from_be_bytes([rest[0], rest[1]]);
+    Ok((SocketAddr::new(ip, port), &rest[2..]))
+}
+
+impl Frame {
+    pub const fn id(&self) -> u32 {
+        match self {
+            Frame::Hello(_) => 0,
+            Frame::Connect { id, .. }
+            | Frame::Connected { id }
+            | Frame::Data { id, .. }
+            | Frame::Eof { id }
+            | Frame::Close { id, .. }
+            | Frame::Datagram { id, .. }
+            | Frame::DnsQuery { id, .. }
+            | Frame::DnsAnswer { id, .. }
+            | Frame::Window { id, .. } => *id,
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        let kind = match self {
+            Frame::Hello(version) => {
+                payload.extend(version);
+                HELLO
+            }
+            Frame::Connect { addr, .. } => {
+                put_addr(&mut payload, *addr);
+                CONNECT
+            }
+            Frame::Connected { .. } => CONNECTED,
+            Frame::Data { data, .. } => {
+                payload.extend(data);
+                DATA
+            }
+            Frame::Eof { .. } => EOF,
+            Frame::Close { error, .. } => {
+                payload.extend(error.as_bytes());
+                CLOSE
+            }
+            Frame::Datagram { addr, data, .. } => {
+                put_addr(&mut payload, *addr);
+                payload.extend(data);
+                DATAGRAM
+            }
+            Frame::DnsQuery { server, data, .. } => {
+                put_addr(&mut payload, *server);
+                payload.extend(data);
+                DNS_QUERY
+            }
+            Frame::DnsAnswer { data, .. } => {
+                payload.extend(data);
+                DNS_ANSWER
+            }
+            Frame::Window { frames, .. } => {
+                payload.extend(frames.to_be_bytes());
+                WINDOW_FRAME
+            }
+        };
+
+        // Payloads are limited to MAX_PAYLOAD by the senders.
+        let len = u32::try_from(payload.len()).unwrap_or(u32::MAX);
+        let mut buf = Vec::with_capacity(9 + payload.len());
+        buf.push(kind);
+        buf.extend(self.id().to_be_bytes());
+        buf.extend(len.to_be_bytes());
+        buf.extend(payload);
+        buf
+    }
+
+    fn decode(kind: u8, id: u32, payload: Vec<u8>) -> Result<Self, std::io::Error> {
+        let frame = match kind {
+            HELLO => Frame::Hello(payload),
+            CONNECT => Frame::Connect {
+                id,
+                addr: take_addr(&payload)?.0,
+            },
+            CONNECTED => Frame::Connected { id },
+            DATA => Frame::Data { id, data: payload },
+            EOF => Frame::Eof { id },
+            CLOSE => Frame::Close {
+                id,
+                error: String::from_utf8_lossy(&payload).into_owned(),
+            },
+            DATAGRAM => {
+                let (addr, data) = take_addr(&payload)?;
+                Frame::Datagram {
+                    id,
+                    addr,
+                    data: data.to_vec(),
+                }
+            }
+            DNS_QUERY => {
+                let (server, data) = take_addr(&payload)?;
+                Frame::DnsQuery {
+                    id,
+                    server,
+                    data: data.to_vec(),
+                }
+            }
+            DNS_ANSWER => Frame::DnsAnswer { id, data: payload },
+            WINDOW_FRAME => Frame::Window {
+                id,
+                frames: u32::from_be_bytes(
+                    payload
+                        .as_slice()
+                        .try_into()
+                        .map_err(|_| invalid("bad window"))?,
+                ),
+            },
+            _ => return Err(invalid("unknown frame kind")),
+        };
+        Ok(frame)
+    }
+}
+
+/// Read the next frame, or `None` if the other side closed the connection between frames.
+pub async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> Result<Option<Frame>, std::io::Error> {
+    let kind = match reader.read_u8().await {
+        Ok(kind) => kind,
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let id = reader.read_u32().await?;
+    let len = usize::try_from(reader.read_u32().await?).unwrap_or(usize::MAX);
+    if len > MAX_PAYLOAD {
+        return Err(invalid("frame too large"));
+    }
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload).await?;
+    Frame::decode(kind, id, payload).map(Some)
+}
+
+/// Write the frames sent on the returned queue to `writer`, until every sender is gone.
+pub fn spawn_writer<W>(writer: W) -> (mpsc::Sender<Frame>, JoinHandle<Result<(), std::io::Error>>)
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let (tx, mut rx) = mpsc::channel::<Frame>(QUEUE);
+    let handle = tokio::spawn(async move {
+        let mut writer = BufWriter::new(writer);
+        while let Some(frame) = rx.recv().await {
+            writer.write_all(&frame.encode()).await?;
+            // Only flush once everything queued so far is written.
+            while let Ok(frame) = rx.try_recv() {
+                writer.write_all(&frame.encode()).await?;
+            }
+            writer.flush().await?;
+        }
+        Ok(())
+    });
+    (tx, handle)
+}
+
+/// The queue of an open connection, and its credit for sending data.
+struct Entry {
+    tx: mpsc::Sender<Frame>,
+    credit: Arc<Semaphore>,
+}
+
+/// The frames for one connection, and its credit for sending data.
+pub struct Incoming {
+    frames: mpsc::Receiver<Frame>,
+    credit: Arc<Semaphore>,
+}
+
+impl Incoming {
+    pub async fn recv(&mut self) -> Option<Frame> {
+        self.frames.recv().await
+    }
+}
+
+/// The queues of the open connections, by id.
+#[derive(Clone, Default)]
+pub struct Streams(Arc<StdMutex<HashMap<u32, Entry>>>);
+
+impl Streams {
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u32, Entry>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Start queueing the frames for `id`.
+    pub fn insert(&self, id: u32) -> Incoming {
+        let (tx, frames) = mpsc::channel(STREAM_QUEUE);
+        let credit = Arc::new(Semaphore::new(WINDOW as usize));
+        let entry = Entry {
+            tx,
+            credit: Arc::clone(&credit),
+        };
+        self.lock().insert(id, entry);
+        Incoming { frames, credit }
+    }
+
+    pub fn contains(&self, id: u32) -> bool {
+        self.lock().contains_key(&id)
+    }
+
+    pub fn remove(&self, id: u32) {
+        self.lock().remove(&id);
+    }
+
+    /// Pass `frame` on to its connection without waiting, frames for unknown ids are dropped.
+    ///
+    /// A connection whose queue is full is closed, as the other side sent more
+    /// than its window. Datagrams that don't fit are dropped instead, as UDP would.
+    pub fn dispatch(&self, frame: Frame, outgoing: &mpsc::Sender<Frame>) {
+        let id = frame.id();
+        let overflow = self.lock().get(&id).is_some_and(|entry| match frame {
+            Frame::Window { frames, .. } => {
+                let frames = usize::try_from(frames).unwrap_or(usize::MAX);
+                let granted = entry.credit.available_permits().saturating_add(frames);
+                if granted <= WINDOW as usize {
+                    entry.credit.add_permits(frames);
+                }
+                granted > WINDOW as usize
+            }
+            frame @ Frame::Datagram { .. } => {
+                if let Err(TrySendError::Full(_)) = entry.tx.try_send(frame) {
+                    log::debug!("dropping datagram on flow {id}, queue full");
+                }
+                false
+            }
+            // The connection may have just finished.
+            frame => matches!(entry.tx.try_send(frame), Err(TrySendError::Full(_))),
+        });
+
+        if overflow {
+            log::debug!("closing connection {id}, it was sent more than its window");
+            self.remove(id);
+            let outgoing = outgoing.clone();
+            tokio::spawn(async move {
+                let error = "window exceeded".to_string();
+                _ = outgoing.send(Frame::Close { id, error }).await;
+            });
+        }
+    }
+
+    /// Drop every queue, which ends every connection.
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+}
+
+/// How the frames for a connection stopped.
+enum Received {
+    Eof,
+    Closed,
+}
+
+/// Copy between `stream` and the frames of connection `id`, until both sides
+/// have sent EOF or either side closes it.
+///
+/// Errors are sent to the other side as well as returned.
+pub async fn pump<S: AsyncRead + AsyncWrite>(
+    stream: S,
+    id: u32,
+    incoming: Incoming,
+    outgoing: mpsc::Sender<Frame>,
+) -> Result<(), std::io::Error> {
+    let result = pump_stream(stream, id, incoming, &outgoing).await;
+    if let Err(err) = &result {
+        _ = outgoing
+            .send(Frame::Close {
+                id,
+                error: err.to_string(),
+            })
+            .await;
+    }
+    result
+}
+
+async fn pump_stream<S: AsyncRead + AsyncWrite>(
+    stream: S,
+    id: u32,
+    incoming: Incoming,
+    outgoing: &mpsc::Sender<Frame>,
+) -> Result<(), std::io::Error> {
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let Incoming {
+        frames: mut incoming,
+        credit,
+    } = incoming;
+
+    let upload = async move {
+        let mut buf = vec![0; BUFFER_SIZE];
+        loop {
+            // Wait for the other side to have room before reading any more.
+            credit
+                .acquire()
+                .await
+                .map_err(|_| std::io::Error::from(ErrorKind::BrokenPipe))?
+                .forget();
+            let len = reader.read(&mut buf).await?;
+            let frame = match len {
+                0 => Frame::Eof { id },
+                _ => Frame::Data {
+                    id,
+                    data: buf[..len].to_vec(),
+                },
+            };
+            if outgoing.send(frame).await.is_err() || len == 0 {
+                return Ok::<_, std::io::Error>(());
+            }
+        }
+    };
+
+    let download = async move {
+        let mut written = 0;
+        while let Some(frame) = incoming.recv().await {
+            match frame {
+                Frame::Data { data, .. } => {
+                    writer.write_all(&data).await?;
+                    written += 1;
+                    if written == WINDOW_UPDATE {
+                        let frame = Frame::Window {
+                            id,
+                            frames: written,
+                        };
+                        if outgoing.send(frame).await.is_err() {
+                            return Ok(Received::Closed);
+                        }
+                        written = 0;
+                    }
+                }
+                Frame::Eof { .. } => {
+                    writer.shutdown().await?;
+                    return Ok(Received::Eof);
+                }
+                Frame::Close { error, .. } if !error.is_empty() => {
+                    log::debug!("connection {id} closed by the other side: {error}");
+                    return Ok(Received::Closed);
+                }
+                Frame::Close { .. } => return Ok(Received::Closed),
+                frame => log::debug!("unexpected frame on connection {id}: {frame:?}"),
+            }
+        }
+        Ok::<_, std::io::Error>(Received::Closed)
+    };
+
+    tokio::pin!(upload, download);
+    let mut uploading = true;
+    let mut downloading = true;
+    while uploading || downloading {
+        tokio::select! {
+            res = &mut upload, if uploading => {
+                res?;
+                uploading = false;
+            }
+            res = &mut download, if downloading => match res? {
+                Received::Eof => downloading = false,
+                Received::Closed => break,
+            },
+        }
+    }
+    Ok(())
+}
+
+/// The client side of a connection to `sshuttle_rust server`, cheap to clone.
+#[derive(Clone)]
+pub struct MuxClient {
+    outgoing: mpsc::Sender<Frame>,
+    streams: Streams,
+    next_id: Arc<AtomicU32>,
+}
+
+impl MuxClient {
+    /// Wait for the server to say hello, and start passing frames to and from it.
+    ///
+    /// The returned task finishes when the connection to the server is lost.
+    pub async fn start<R, W>(
+        mut reader: R,
+        writer: W,
+    ) -> Result<(Self, JoinHandle<Result<(), std::io::Error>>), MuxError>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        match read_frame(&mut reader).await? {
+            Some(Frame::Hello(version)) if version == VERSION => {}
+            Some(Frame::Hello(version)) => {
+                return Err(MuxError::Version(
+                    String::from_utf8_lossy(&version).into_owned(),
+                ))
+            }
+            _ => return Err(MuxError::Closed),
+        }
+
+        let (outgoing, _writer) = spawn_writer(writer);
+        let streams = Streams::default();
+        let reader_streams = streams.clone();
+        let reader_outgoing = outgoing.clone();
+        let handle = tokio::spawn(async move {
+            let result = async {
+                while let Some(frame) = read_frame(&mut reader).await? {
+                    reader_streams.dispatch(frame, &reader_outgoing);
+                }
+                Ok(())
+            }
+            .await;
+            reader_streams.clear();
+            result
+        });
+
+        let client = Self {
+            outgoing,
+            streams,
+            next_id: Arc::new(AtomicU32::new(1)),
+        };
+        Ok((client, handle))
+    }
+
+    fn open(&self) -> (u32, Incoming) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        (id, self.streams.insert(id))
+    }
+
+    async fn send(&self, frame: Frame) -> Result<(), MuxError> {
+        self.outgoing
+            .send(frame)
+            .await
+            .map_err(|_| MuxError::Closed)
+    }
+
+    /// Open a TCP connection to `addr` from the server.
+    pub async fn connect(&self, addr: SocketAddr) -> Result<DuplexStream, MuxError> {
+        let (id, mut incoming) = self.open();
+        let result = async {
+            self.send(Frame::Connect { id, addr }).await?;
+            match incoming.recv().await {
+                Some(Frame::Connected { .. }) => Ok(()),
+                Some(Frame::Close { error, .. }) => Err(MuxError::Remote(error)),
+                _ => Err(MuxError::Closed),
+            }
+        }
+        .await;
+        if let Err(err) = result {
+            self.streams.remove(id);
+            return Err(err);
+        }
+
+        let (stream, channel_end) = tokio::io::duplex(BUFFER_SIZE);
+        let outgoing = self.outgoing.clone();
+        let streams = self.streams.clone();
+        tokio::spawn(async move {
+            if let Err(err) = pump(channel_end, id, incoming, outgoing).await {
+                log::debug!("connection {id} to {addr} failed: {err}");
+            }
+            streams.remove(id);
+        });
+        Ok(stream)
+    }
+
+    /// Start a UDP flow, the server sends from a socket of its own for each flow.
+    pub fn datagram(&self) -> MuxDatagram {
+        let (id, incoming) = self.open();
+        MuxDatagram {
+            id,
+            incoming,
+            client: self.clone(),
+        }
+    }
+
+    /// Have the server send a DNS query to `server` over UDP, and return the answer.
+    pub async fn query_dns(&self, server: SocketAddr, data: &[u8]) -> Result<Vec<u8>, MuxError> {
+        let (id, mut incoming) = self.open();
+        let result = async {
+            self.send(Frame::DnsQuery {
+                id,
+                server,
+                data: data.to_vec(),
+            })
+            .await?;
+            match incoming.recv().await {
+                Some(Frame::DnsAnswer { data, .. }) => Ok(data),
+                Some(Frame::Close { error, .. }) => Err(MuxError::Remote(error)),
+                _ => Err(MuxError::Closed),
+            }
+        }
+        .await;
+        self.streams.remove(id);
+        result
+    }
+}
+
+/// A UDP flow through the server, closed when dropped.
+pub struct MuxDatagram {
+    id: u32,
+    incoming: Incoming,
+    client: MuxClient,
+}
+
+impl MuxDatagram {
+    pub async fn send_to(&self, data: &[u8], addr: SocketAddr) -> Result<(), MuxError> {
+        self.client
+            .send(Frame::Datagram {
+                id: self.id,
+                addr,
+                data: data.to_vec(),
+            })
+            .await
+    }
+
+    pub async fn recv_from(&mut self, buf: &mut [u8]) -> Result<(usize, SocketAddr), MuxError> {
+        loop {
+            match self.incoming.recv().await {
+                Some(Frame::Datagram { addr, data, .. }) => {
+                    let len = data.len().min(buf.len());
+                    buf[..len].copy_from_slice(&data[..len]);
+                    return Ok((len, addr));
+                }
+                // The server timed the flow out.
+                Some(Frame::Close { error, .. }) if error.is_empty() => {
+                    return Err(MuxError::Closed)
+                }
+                Some(Frame::Close { error, .. }) => return Err(MuxError::Remote(error)),
+                Some(frame) => log::debug!("unexpected frame on flow {}: {frame:?}", self.id),
+                None => return Err(MuxError::Closed),
+            }
+        }
+    }
+}
+
+impl Drop for MuxDatagram {
+    fn drop(&mut self) {
+        self.client.streams.remove(self.id);
+        let frame = Frame::Close {
+            id: self.id,
+            error: String::new(),
+        };
+        // Wait for room rather than leave the flow open on the server until it times out.
+        if let Err(TrySendError::Full(frame)) = self.client.outgoing.try_send(frame) {
+            if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+                let outgoing = self.client.outgoing.clone();
+                runtime.spawn(async move { _ = outgoing.send(frame).await });
+            }
+        }
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_frame_round_trip() {
+        let frames = [
+            Frame::Hello(VERSION.to_vec()),
+            Frame::Connect {
+                id: 1,
+                addr: "10.1.2.3:443".parse().unwrap(),
+            },
+            Frame::Connected { id: 1 },
+            Frame::Data {
+                id: 1,
+                data: b"hello".to_vec(),
+            },
+            Frame::Eof { id: 1 },
+            Frame::Close {
+                id: 1,
+                error: "Connection refused".to_string(),
+            },
+            Frame::Datagram {
+                id: 2,
+                addr: "[2001:db8::1]:53".parse().unwrap(),
+                data: vec![1, 2, 3],
+            },
+            Frame::DnsQuery {
+                id: u32::MAX,
+                server: "1.1.1.1:53".parse().unwrap(),
+                data: vec![0; 40],
+            },
+            Frame::DnsAnswer {
+                id: u32::MAX,
+                data: vec![0; 80],
+            },
+            Frame::Window { id: 1, frames: 32 },
+        ];
+
+        let mut buf = Vec::new();
+        for frame in &frames {
+            buf.extend(frame.encode());
+        }
+        let mut reader = buf.as_slice();
+        for frame in frames {
+            assert_eq!(read_frame(&mut reader).await.unwrap(), Some(frame));
+        }
+        assert_eq!(read_frame(&mut reader).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_client_connect() {
+        let (client_end, server_end) = tokio::io::duplex(BUFFER_SIZE);
+        let (client_reader, client_writer) = tokio::io::split(client_end);
+        let (mut server_reader, server_writer) = tokio::io::split(server_end);
+        let (server, _writer) = spawn_writer(server_writer);
+        server.send(Frame::Hello(VERSION.to_vec())).await.unwrap();
+
+        let (client, _reader) = MuxClient::start(client_reader, client_writer)
+            .await
+            .unwrap();
+        let addr: SocketAddr = "10.1.2.3:80".parse().unwrap();
+        let connect = tokio::spawn(async move { client.connect(addr).await });
+
+        let id = match read_frame(&mut server_reader).await.unwrap() {
+            Some(Frame::Connect { id, addr: to }) if to == addr => id,
+            frame => panic!("unexpected {frame:?}"),
+        };
+        server.send(Frame::Connected { id }).await.unwrap();
+        let mut stream = connect.await.unwrap().unwrap();
+
+        stream.write_all(b"GET /").await.unwrap();
+        assert_eq!(
+            read_frame(&mut server_reader).await.unwrap(),
+            Some(Frame::Data {
+                id,
+                data: b"GET /".to_vec()
+            })
+        );
+        stream.shutdown().await.unwrap();
+        assert_eq!(
+            read_frame(&mut server_reader).await.unwrap(),
+            Some(Frame::Eof { id })
+        );
+
+        server
+            .send(Frame::Data {
+                id,
+                data: b"200 OK".to_vec(),
+            })
+            .await
+            .unwrap();
+        server.send(Frame::Eof { id }).await.unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"200 OK");
+    }
+
+    #[tokio::test]
+    async fn test_read_frame_invalid() {
+        let mut reader: &[u8] = &[99, 0, 0, 0, 1, 0, 0, 0, 0];
+        assert!(read_frame(&mut reader).await.is_err());
+
+        let mut reader: &[u8] = &[CONNECT, 0, 0, 0, 1, 0, 0, 0, 3, 4, 10, 0];
+        assert!(read_frame(&mut reader).await.is_err());
+
+        let mut reader: &[u8] = &[DATA, 0, 0, 0, 1, 0xff, 0xff, 0xff, 0xff];
+        assert!(read_frame(&mut reader).await.is_err());
+    }
+
+    /// Serve connections like `sshuttle_rust server` does, handing our end of each one to the test.
+    async fn test_server<R, W>(
+        mut reader: R,
+        writer: W,
+        accepted: mpsc::Sender<DuplexStream>,
+    ) -> Result<(), std::io::Error>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let (outgoing, _writer) = spawn_writer(writer);
+        outgoing.send(Frame::Hello(VERSION.to_vec())).await.unwrap();
+        let streams = Streams::default();
+        while let Some(frame) = read_frame(&mut reader).await? {
+            match frame {
+                Frame::Connect { id, .. } => {
+                    let incoming = streams.insert(id);
+                    let (stream, server_end) = tokio::io::duplex(BUFFER_SIZE);
+                    outgoing.send(Frame::Connected { id }).await.unwrap();
+                    tokio::spawn(pump(stream, id, incoming, outgoing.clone()));
+                    accepted.send(server_end).await.unwrap();
+                }
+                frame => streams.dispatch(frame, &outgoing),
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stalled_connection() {
+        let (client_end, server_end) = tokio::io::duplex(BUFFER_SIZE);
+        let (client_reader, client_writer) = tokio::io::split(client_end);
+        let (server_reader, server_writer) = tokio::io::split(server_end);
+        let (accepted_tx, mut accepted) = mpsc::channel(2);
+        tokio::spawn(test_server(server_reader, server_writer, accepted_tx));
+
+        let (client, _reader) = MuxClient::start(client_reader, client_writer)
+            .await
+            .unwrap();
+        let addr: SocketAddr = "10.1.2.3:80".parse().unwrap();
+
+        // Far more than the window, which the client never reads.
+        let _stalled = client.connect(addr).await.unwrap();
+        let mut stalled_server = accepted.recv().await.unwrap();
+        tokio::spawn(async move { stalled_server.write_all(&vec![0; 16 * 1024 * 1024]).await });
+
+        let mut stream = client.connect(addr).await.unwrap();
+        let mut server = accepted.recv().await.unwrap();
+        let mut buf = [0u8; 5];
+        for _ in 0..10 {
+            stream.write_all(b"ping!").await.unwrap();
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping!");
+            server.write_all(b"pong!").await.unwrap();
+            tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&buf, b"pong!");
+        }
+    }
+}
@@ -1,13 +1,16 @@
 use std::{
     fmt::{Display, Formatter},
+    io::Read,
     net::IpAddr,
     net::Ipv6Addr,
     net::{Ipv4Addr, SocketAddr},
+    path::Path,
     str::FromStr,
 };
 
 use dns_lookup::getaddrinfo;
 use regex::Match;
+use serde::{Deserialize, Deserializer, Serialize};
 use thiserror::Error;
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -42,7 +45,7 @@ pub enum NetworkParseError {
     Regex(#[from] regex::Error),
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub enum Ports {
     None,
     Single(u16),
@@ -58,10 +61,11 @@ pub struct Subnet {
 
 pub trait SubnetFamily {
     fn subnet_str(&self) -> String;
+    fn cidr(&self) -> u8;
     fn ports(&self) -> Ports;
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SubnetV4 {
     pub address: Ipv4Addr,
     pub cidr: u8,
@@ -72,12 +76,15 @@ impl SubnetFamily for SubnetV4 {
     fn subnet_str(&self) -> String {
         format!("{}/{}", self.address, self.cidr)
     }
+    fn cidr(&self) -> u8 {
+        self.cidr
+    }
     fn ports(&self) -> Ports {
         self.ports
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SubnetV6 {
     pub address: Ipv6Addr,
     pub cidr: u8,
@@ -88,6 +95,9 @@ impl SubnetFamily for SubnetV6 {
     fn subnet_str(&self) -> String {
         format!("{}/{}", self.address, self.cidr)
     }
+    fn cidr(&self) -> u8 {
+        self.cidr
+    }
     fn ports(&self) -> Ports {
         self.ports
     }
@@ -102,7 +112,7 @@ fn parse_int<T: FromStr>(s: Match) -> Result<T, NetworkParseError> {
 #[derive(Debug)]
 pub struct Subnets(pub Vec<Subnet>);
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct SubnetsV4(pub Vec<SubnetV4>);
 
 impl SubnetsV4 {
@@ -111,7 +121,7 @@ impl SubnetsV4 {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct SubnetsV6(pub Vec<SubnetV6>);
 
 impl SubnetsV6 {
@@ -163,7 +173,6 @@ impl Subnets {
     pub fn count_ipv6(&self) -> usize {
         self.ipv6().len()
     }
-    #[allow(dead_code)]
     pub fn len(&self) -> usize {
         self.0.len()
     }
@@ -254,6 +263,47 @@ impl FromStr for Subnets {
     }
 }
 
+impl<'de> Deserialize<'de> for Subnets {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Parse one subnet spec per line, ignoring blank lines and `#` comments.
+///
+/// `name` is used to identify the input in error messages.
+pub fn parse_subnets_list(s: &str, name: &str) -> Result<Vec<Subnets>, NetworkParseError> {
+    s.lines()
+        .enumerate()
+        .filter_map(|(n, line)| {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                None
+            } else {
+                let subnets = Subnets::from_str(line).map_err(|err| {
+                    NetworkParseError::InputError(format!("{name}:{}: {err}", n + 1))
+                });
+                Some(subnets)
+            }
+        })
+        .collect()
+}
+
+/// Read subnets from a file, or from stdin if the path is `-`.
+pub fn read_subnets_file(path: &Path) -> Result<Vec<Subnets>, NetworkParseError> {
+    let name = path.display().to_string();
+    let contents = if path == Path::new("-") {
+        let mut contents = String::new();
+        std::io::stdin().read_to_string(&mut contents)?;
+        contents
+    } else {
+        std::fs::read_to_string(path)
+            .map_err(|err| NetworkParseError::InputError(format!("Could not read {name}: {err}")))?
+    };
+    parse_subnets_list(&contents, &name)
+}
+
 pub trait SubnetsFamily {
     type Subnet: SubnetFamily;
     fn family(&self) -> Family;
@@ -329,14 +379,13 @@ impl FromStr for SubnetsV6 {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Serialize, Deserialize)]
 pub enum Protocol {
     Tcp,
-    #[allow(dead_code)]
     Udp,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct ListenerAddr {
     pub protocol: Protocol,
     pub addr: SocketAddr,
@@ -476,6 +525,28 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_parse_subnets_list() {
+        let s = "# internal networks\n\
+                 10.0.0.0/8\n\
+                 \n\
+                 192.168.1.0/24:443   # web\n\
+                 [2404:6800:4004:80c::/64]:80-90\n";
+        let subnets = parse_subnets_list(s, "test").unwrap();
+        assert_eq!(subnets.len(), 3);
+        assert_eq!(subnets[0].0[0].address.to_string(), "10.0.0.0");
+        assert_eq!(subnets[0].0[0].cidr, 8);
+        assert_eq!(subnets[1].0[0].ports, Ports::Single(443));
+        assert_eq!(subnets[2].0[0].ports, Ports::Range(80, 90));
+    }
+
+    #[test]
+    fn test_parse_subnets_list_error() {
+        let s = "10.0.0.0/8\n# comment\n10.0.0.0/99\n";
+        let err = parse_subnets_list(s, "test").unwrap_err();
+        assert!(err.to_string().contains("test:3:"), "{err}");
+    }
+
     const IP6_REPRS: [(&str, &str); 4] = [
         ("::", "::"),
         ("::1", "::1"),
@@ -1,5 +1,11 @@
-use clap::Parser;
-use std::{error::Error, fmt::Display, net::SocketAddr};
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, ValueSource};
+use serde::{Deserialize, Serialize};
+use std::{
+    error::Error,
+    fmt::Display,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
 
 use crate::network::Subnets;
 
@@ -17,23 +23,89 @@ impl Display for ParseError {
 // impl Debug for ParseError {}
 impl Error for ParseError {}
 
-#[derive(Clone, clap::ArgEnum, Debug, Copy)]
+#[derive(Clone, clap::ArgEnum, Debug, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum FirewallType {
     Nat,
     #[clap(name = "tproxy")]
     TProxy,
+    Nftables,
+}
+
+#[derive(Clone, clap::ArgEnum, Debug, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SocksVersion {
+    Socks5,
+    /// Also works with socks4a servers.
+    #[clap(alias = "socks4a")]
+    #[serde(alias = "socks4a")]
+    Socks4,
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum Command {
+    /// Run on the remote for --server-cmd, speaking to the client on stdin and stdout.
+    Server,
 }
 
 /// Simple program to greet a person
 #[derive(Parser, Debug)]
+#[allow(clippy::struct_excessive_bools)]
 #[clap(author, version, about, long_about = None)]
 pub struct Options {
+    /// Read options from this TOML file.
+    ///
+    /// Keys are the long option names, options given on the command line take precedence.
+    /// Flags can only be turned on from the command line, so a flag set to true in the
+    /// file stays on.
+    #[clap(long, value_parser)]
+    pub config: Option<PathBuf>,
+
     /// ssh hostname (and optional username and password) of remote server.
     ///
     /// [USERNAME[:PASSWORD]@]ADDR[:PORT]
     #[clap(short, long, value_parser)]
     pub remote: Option<String>,
 
+    /// The ssh command to run, split into words like a shell would.
+    ///
+    /// For example "ssh -o ProxyJump=bastion", or a wrapper script.
+    #[clap(long, default_value = "ssh")]
+    pub ssh_cmd: String,
+
+    /// Extra option to pass to ssh with -o, such as ProxyJump=bastion.
+    ///
+    /// May be given more than once.
+    #[clap(long, value_parser)]
+    pub ssh_option: Vec<String>,
+
+    /// Port to connect to on the remote with ssh.
+    #[clap(long)]
+    pub ssh_port: Option<u16>,
+
+    /// Identity (private key) file for ssh.
+    #[clap(long, value_parser)]
+    pub ssh_identity: Option<PathBuf>,
+
+    /// Run this command on the remote with ssh, and forward over its stdin and stdout
+    /// instead of running ssh -D.
+    ///
+    /// The command is normally `sshuttle_rust server`, which has to be installed on
+    /// the remote. TCP connections, UDP datagrams and DNS requests are all carried
+    /// over the one ssh connection, and the server sends DNS requests over UDP.
+    #[clap(long, value_parser)]
+    pub server_cmd: Option<String>,
+
+    /// Connect with the built in ssh client instead of running ssh -D.
+    ///
+    /// Each connection is forwarded over its own channel of a single ssh session,
+    /// without a socks server in between. Authenticates with the password in
+    /// --remote, the ssh agent or --ssh-identity, and checks the host key against
+    /// the known hosts file in your home directory. Needs the builtin-ssh cargo
+    /// feature, and doesn't support --udp.
+    #[clap(long)]
+    pub builtin_ssh: bool,
+
     /// Transproxy to this ip address and port number.
     ///
     /// Maybe used twice, once for IPv4 and once for IPv6.
@@ -49,17 +121,447 @@ pub struct Options {
     #[clap(short, long)]
     pub exclude: Vec<Subnets>,
 
+    /// Capture and forward traffic to the subnets listed in this file, one per line.
+    ///
+    /// Use `-` to read from stdin.
+    #[clap(long, value_parser)]
+    pub include_from: Vec<PathBuf>,
+
+    /// Exclude the subnets listed in this file, one per line.
+    ///
+    /// Use `-` to read from stdin.
+    #[clap(long, value_parser)]
+    pub exclude_from: Vec<PathBuf>,
+
+    /// Also forward traffic to the networks in the remote's routing table.
+    ///
+    /// The routes are read with `ip route` (or `netstat -rn`) over a separate ssh
+    /// connection. Use --exclude if the ssh server itself is in one of them.
+    #[clap(long)]
+    pub auto_nets: bool,
+
     /// Connect to this socks server.
     ///
     /// If --remote is used then this value will be passed to ssh using -D.
     #[clap(short, long, default_value = "127.0.0.1:1080")]
     pub socks: SocketAddr,
 
+    /// Version of the socks protocol to speak to --socks.
+    ///
+    /// socks4 also works with socks4a servers, but supports neither IPv6 nor --udp.
+    /// Only used without --remote.
+    #[clap(long, arg_enum, default_value_t = SocksVersion::Socks5)]
+    pub socks_version: SocksVersion,
+
+    /// Username for the --socks server.
+    ///
+    /// Sent with username/password authentication by socks5, or as the user id by
+    /// socks4. Only used without --remote.
+    #[clap(long, env = "SSHUTTLE_RUST_SOCKS_USER")]
+    pub socks_user: Option<String>,
+
+    /// Password for the --socks server, used with --socks-user.
+    #[clap(long, env = "SSHUTTLE_RUST_SOCKS_PASSWORD", hide_env_values = true)]
+    pub socks_password: Option<String>,
+
+    /// Connect through this HTTP proxy with CONNECT, instead of a socks server.
+    ///
+    /// [USERNAME[:PASSWORD]@]HOST:PORT, the username and password are sent with
+    /// Basic authentication. Only used without --remote, and doesn't support --udp.
+    #[clap(long, value_parser)]
+    pub http_proxy: Option<String>,
+
+    /// Connect to the destinations from this host, instead of through a proxy.
+    ///
+    /// Only used without --remote. Requires --user, as the firewall would redirect our
+    /// own connections too, so this is for forwarding the traffic of other users.
+    #[clap(long)]
+    pub direct: bool,
+
+    /// Seconds to wait for the socks server to accept connections before giving up.
+    ///
+    /// The firewall is only set up once the socks server is ready.
+    #[clap(long, default_value_t = 30)]
+    pub socks_ready_timeout: u64,
+
+    /// Number of times to restart ssh after it exits, before giving up.
+    ///
+    /// The count is reset once ssh has connected successfully.
+    #[clap(long, default_value_t = 5)]
+    pub max_retries: u32,
+
+    /// Hold new connections while ssh is restarting instead of failing them.
+    #[clap(long)]
+    pub hold_connections: bool,
+
+    /// Only forward traffic from this user (name or uid).
+    #[clap(long)]
+    pub user: Option<String>,
+
     /// What kind of firewall to use.
     #[clap(short, long, arg_enum, default_value_t = FirewallType::Nat)]
     pub firewall: FirewallType,
+
+    /// Forward UDP traffic as well as TCP.
+    ///
+    /// Only supported by the tproxy firewall, and requires --server-cmd, --direct or
+    /// a socks server with UDP ASSOCIATE support.
+    #[clap(long)]
+    pub udp: bool,
+
+    /// Seconds a UDP flow may be idle before it is closed.
+    ///
+    /// With --server-cmd the server also closes flows that are idle for five minutes.
+    #[clap(long, default_value_t = 60)]
+    pub udp_timeout: u64,
+
+    /// Firewall mark used by the tproxy firewall to route packets to the listener.
+    #[clap(long, value_parser = parse_mark, default_value = "0x01")]
+    pub tproxy_mark: u32,
+
+    /// Routing table used by the tproxy firewall for marked packets.
+    #[clap(long, default_value_t = 100)]
+    pub tproxy_table: u32,
+
+    /// Capture local DNS requests and forward them to --dns-server.
+    ///
+    /// Requests are sent over TCP through the socks server.
+    #[clap(long)]
+    pub dns: bool,
+
+    /// DNS server to forward requests to when using --dns.
+    #[clap(long)]
+    pub dns_server: Option<SocketAddr>,
+
+    /// Local port to listen on for captured DNS requests.
+    #[clap(long, default_value_t = 1053)]
+    pub dns_port: u16,
+
+    /// Run in the background once the firewall has been set up.
+    ///
+    /// Logs go to syslog, unless --log-file is given.
+    #[clap(long)]
+    pub daemon: bool,
+
+    /// Write the process id of the daemon to this file.
+    ///
+    /// The daemon refuses to start while the process in it is still running.
+    #[clap(long, value_parser, default_value = "/run/sshuttle_rust.pid")]
+    pub pidfile: PathBuf,
+
+    /// Send log messages to syslog instead of stderr.
+    #[clap(long, conflicts_with = "log-file")]
+    pub syslog: bool,
+
+    /// Append log messages to this file instead of stderr.
+    #[clap(long, value_parser)]
+    pub log_file: Option<PathBuf>,
+
+    /// Switch to this user (name or uid) once the firewall is set up and the listeners are bound.
+    ///
+    /// Only the capabilities needed to create transparent sockets are kept, a process
+    /// that stays root restores the firewall. ssh is started as this user, so that their
+    /// ssh agent and known hosts are used.
+    #[clap(long, value_parser)]
+    pub setuid: Option<String>,
+
+    /// Switch to this group (name or gid), defaults to the primary group of --setuid.
+    #[clap(long, value_parser)]
+    pub setgid: Option<String>,
+
+    /// Set up the firewall in a helper process started with this program, such as sudo or doas.
+    ///
+    /// Only the helper runs as root, the helper restores the firewall as soon as the
+    /// client exits. Only the traffic of the user running the client is forwarded. Not
+    /// supported by the tproxy firewall, which needs transparent sockets.
+    #[clap(long, value_parser)]
+    pub firewall_helper: Option<String>,
+
+    /// Run as the firewall helper, reading the firewall config from stdin.
+    #[clap(long, hide = true)]
+    pub firewall_helper_mode: bool,
+
+    /// Apply iptables rules with iptables-restore, each table atomically.
+    ///
+    /// Only supported by the nat and tproxy firewalls.
+    #[clap(long)]
+    pub iptables_restore: bool,
+
+    /// Remove firewall rules left behind by a session that was killed, then exit.
+    ///
+    /// Removes the chains created by the nat and tproxy firewalls for any port, and the
+    /// tproxy routing rules for --tproxy-mark and --tproxy-table.
+    #[clap(long)]
+    pub cleanup: bool,
+
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
+    /// Print the commands that would set up and restore the firewall, then exit.
+    ///
+    /// Nothing is run, ssh isn't started and no listeners are opened. The host
+    /// of the remote is only looked up in DNS to exclude it, ignoring the ssh
+    /// configuration. With --iptables-restore the rules are printed as
+    /// iptables-restore input.
+    #[clap(long)]
+    pub dry_run: bool,
+}
+
+fn parse_mark(s: &str) -> Result<u32, ParseError> {
+    s.strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .map_or_else(|| s.parse::<u32>(), |hex| u32::from_str_radix(hex, 16))
+        .map_err(|err| ParseError {
+            message: format!("Invalid firewall mark '{s}': {err}"),
+        })
+}
+
+/// Options loaded with --config, using the long option names as keys.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct ConfigFile {
+    remote: Option<String>,
+    ssh_cmd: Option<String>,
+    ssh_option: Option<Vec<String>>,
+    ssh_port: Option<u16>,
+    ssh_identity: Option<PathBuf>,
+    server_cmd: Option<String>,
+    builtin_ssh: Option<bool>,
+    listen: Option<Vec<SocketAddr>>,
+    include: Option<Vec<Subnets>>,
+    exclude: Option<Vec<Subnets>>,
+    include_from: Option<Vec<PathBuf>>,
+    exclude_from: Option<Vec<PathBuf>>,
+    auto_nets: Option<bool>,
+    socks: Option<SocketAddr>,
+    socks_version: Option<SocksVersion>,
+    socks_user: Option<String>,
+    socks_password: Option<String>,
+    http_proxy: Option<String>,
+    direct: Option<bool>,
+    socks_ready_timeout: Option<u64>,
+    max_retries: Option<u32>,
+    hold_connections: Option<bool>,
+    user: Option<String>,
+    firewall: Option<FirewallType>,
+    udp: Option<bool>,
+    udp_timeout: Option<u64>,
+    tproxy_mark: Option<u32>,
+    tproxy_table: Option<u32>,
+    dns: Option<bool>,
+    dns_server: Option<SocketAddr>,
+    dns_port: Option<u16>,
+    daemon: Option<bool>,
+    pidfile: Option<PathBuf>,
+    syslog: Option<bool>,
+    log_file: Option<PathBuf>,
+    setuid: Option<String>,
+    setgid: Option<String>,
+    firewall_helper: Option<String>,
+    iptables_restore: Option<bool>,
+}
+
+impl ConfigFile {
+    fn load(path: &Path) -> Result<Self, ParseError> {
+        let contents = std::fs::read_to_string(path).map_err(|err| ParseError {
+            message: format!("Could not read config file {}: {err}", path.display()),
+        })?;
+        toml::from_str(&contents).map_err(|err| ParseError {
+            message: format!("Invalid config file {}: {err}", path.display()),
+        })
+    }
+
+    /// Use the values from the file for every option not given on the command line.
+    fn merge_into(self, opt: &mut Options, matches: &ArgMatches) {
+        // Argument ids are the kebab-case field names.
+        let from_cli = |field: &str| {
+            matches!(
+                matches.value_source(field.replace('_', "-")),
+                Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+            )
+        };
+
+        macro_rules! merge {
+            ( $( $field:ident ),* ) => {
+                $(
+                    if let Some(value) = self.$field {
+                        if !from_cli(stringify!($field)) {
+                            opt.$field = value;
+                        }
+                    }
+                )*
+            };
+        }
+
+        macro_rules! merge_optional {
+            ( $( $field:ident ),* ) => {
+                $(
+                    if self.$field.is_some() && !from_cli(stringify!($field)) {
+                        opt.$field = self.$field;
+                    }
+                )*
+            };
+        }
+
+        merge!(
+            ssh_cmd,
+            ssh_option,
+            builtin_ssh,
+            listen,
+            include,
+            exclude,
+            include_from,
+            exclude_from,
+            auto_nets,
+            socks,
+            socks_version,
+            direct,
+            socks_ready_timeout,
+            max_retries,
+            hold_connections,
+            firewall,
+            udp,
+            udp_timeout,
+            tproxy_mark,
+            tproxy_table,
+            dns,
+            dns_port,
+            daemon,
+            pidfile,
+            syslog,
+            iptables_restore
+        );
+        merge_optional!(
+            remote,
+            ssh_port,
+            ssh_identity,
+            server_cmd,
+            socks_user,
+            socks_password,
+            http_proxy,
+            user,
+            dns_server,
+            log_file,
+            setuid,
+            setgid,
+            firewall_helper
+        );
+    }
+}
+
+fn parse_matches(matches: &ArgMatches) -> Result<Options, ParseError> {
+    let mut opt = Options::from_arg_matches(matches).map_err(|err| ParseError {
+        message: err.to_string(),
+    })?;
+
+    if let Some(path) = &opt.config {
+        let config_file = ConfigFile::load(path)?;
+        config_file.merge_into(&mut opt, matches);
+        check_conflicts(&opt)?;
+    }
+
+    Ok(opt)
+}
+
+/// The conflicts clap checks on the command line, checked again once values
+/// from the config file are merged in.
+fn check_conflicts(opt: &Options) -> Result<(), ParseError> {
+    if opt.syslog && opt.log_file.is_some() {
+        return Err(ParseError {
+            message: "Only one of --syslog and --log-file can be given".to_string(),
+        });
+    }
+    Ok(())
+}
+
+pub fn parse() -> Result<Options, ParseError> {
+    parse_matches(&Options::command().get_matches())
 }
 
-pub fn parse() -> Options {
-    Options::parse()
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merge(args: &[&str], config: &str) -> Options {
+        let matches = Options::command().try_get_matches_from(args).unwrap();
+        let mut opt = Options::from_arg_matches(&matches).unwrap();
+        let config_file: ConfigFile = toml::from_str(config).unwrap();
+        config_file.merge_into(&mut opt, &matches);
+        opt
+    }
+
+    #[test]
+    fn test_config_file() {
+        let config = r#"
+            remote = "user@host.example.org"
+            listen = ["127.0.0.1:1021", "[::1]:1022"]
+            include = ["10.0.0.0/8", "192.168.1.0/24:443"]
+            exclude = ["10.1.0.0/16"]
+            firewall = "tproxy"
+            tproxy-mark = 0x10
+            udp = true
+        "#;
+
+        let opt = merge(&["sshuttle_rust"], config);
+        assert_eq!(opt.remote.as_deref(), Some("user@host.example.org"));
+        assert_eq!(opt.listen.len(), 2);
+        assert_eq!(opt.include.len(), 2);
+        assert_eq!(opt.exclude.len(), 1);
+        assert!(matches!(opt.firewall, FirewallType::TProxy));
+        assert_eq!(opt.tproxy_mark, 0x10);
+        assert!(opt.udp);
+        assert_eq!(opt.socks, "127.0.0.1:1080".parse().unwrap());
+    }
+
+    #[test]
+    fn test_config_file_cli_overrides() {
+        let config = r#"
+            remote = "user@host.example.org"
+            listen = ["127.0.0.1:1021"]
+            include = ["10.0.0.0/8"]
+            firewall = "tproxy"
+            socks = "127.0.0.1:1081"
+        "#;
+
+        let opt = merge(
+            &[
+                "sshuttle_rust",
+                "--remote",
+                "other@host.example.org",
+                "--firewall",
+                "nat",
+                "192.168.0.0/16",
+                "172.16.0.0/12",
+            ],
+            config,
+        );
+        assert_eq!(opt.remote.as_deref(), Some("other@host.example.org"));
+        assert!(matches!(opt.firewall, FirewallType::Nat));
+        assert_eq!(opt.include.len(), 2);
+        assert_eq!(opt.listen, vec!["127.0.0.1:1021".parse().unwrap()]);
+        assert_eq!(opt.socks, "127.0.0.1:1081".parse().unwrap());
+    }
+
+    #[test]
+    fn test_config_file_conflicts() {
+        let opt = merge(
+            &["sshuttle_rust", "--log-file", "/tmp/sshuttle.log"],
+            "syslog = true",
+        );
+        assert!(check_conflicts(&opt).is_err());
+        let opt = merge(
+            &["sshuttle_rust", "--syslog"],
+            r#"log-file = "/tmp/sshuttle.log""#,
+        );
+        assert!(check_conflicts(&opt).is_err());
+        let opt = merge(&["sshuttle_rust", "--syslog"], "daemon = true");
+        assert!(check_conflicts(&opt).is_ok());
+    }
+
+    #[test]
+    fn test_config_file_unknown_key() {
+        let result = toml::from_str::<ConfigFile>("no-such-option = 1");
+        assert!(result.is_err());
+    }
 }
@@ -0,0 +1,167 @@
+//! Switch to an unprivileged user once the firewall is set up.
+
+use nix::{
+    errno::Errno,
+    unistd::{fork, pipe, setgid, setgroups, setuid, ForkResult, Gid, Pid, Uid},
+};
+use std::{fs::File, io::Read, os::unix::prelude::FromRawFd, path::Path};
+use thiserror::Error;
+
+use crate::state::{self, StateError, STATE_DIR};
+
+#[derive(Error, Debug)]
+pub enum PrivilegesError {
+    #[error("Errno error `{0}`")]
+    Errno(#[from] Errno),
+
+    #[error("Could not restore the firewall, see the logs")]
+    Restore,
+}
+
+const CAP_NET_ADMIN: u32 = 12;
+const CAP_NET_RAW: u32 = 13;
+const NET_CAPS: u32 = 1 << CAP_NET_ADMIN | 1 << CAP_NET_RAW;
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[repr(C)]
+struct CapUserHeader {
+    version: u32,
+    pid: i32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapUserData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+fn capset(caps: u32) -> Result<(), Errno> {
+    let mut header = CapUserHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    // Version 3 uses two sets of 32 bits, the capabilities we keep are in the first.
+    let data = [
+        CapUserData {
+            effective: caps,
+            permitted: caps,
+            inheritable: 0,
+        },
+        CapUserData::default(),
+    ];
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_capset,
+            std::ptr::addr_of_mut!(header),
+            data.as_ptr(),
+        )
+    };
+    Errno::result(res).map(drop)
+}
+
+fn prctl(option: libc::c_int, arg: libc::c_ulong) -> Result<(), Errno> {
+    let res = unsafe { libc::prctl(option, arg, 0, 0, 0) };
+    Errno::result(res).map(drop)
+}
+
+/// A process that stays root, and restores the firewall once we exit.
+///
+/// After `drop_privileges` we can't do that ourselves: iptables needs more than
+/// `CAP_NET_ADMIN` to open its root owned lock file.
+pub struct RestoreKeeper {
+    pid: Pid,
+    pipe: Option<File>,
+}
+
+impl RestoreKeeper {
+    /// Have the keeper restore the firewall now, and wait until it has.
+    pub fn restore(mut self) -> Result<(), PrivilegesError> {
+        drop(self.pipe.take());
+        let mut status = 0;
+        loop {
+            match Errno::result(unsafe {
+                libc::waitpid(self.pid.as_raw(), std::ptr::addr_of_mut!(status), 0)
+            }) {
+                Ok(_) => break,
+                Err(Errno::EINTR) => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        if libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0 {
+            Ok(())
+        } else {
+            Err(PrivilegesError::Restore)
+        }
+    }
+}
+
+/// Fork the `RestoreKeeper`.
+///
+/// It waits for its pipe to be closed, which happens however we exit, then undoes
+/// what the state file of this process records. This must be called before any
+/// threads are started.
+pub fn fork_restore_keeper() -> Result<RestoreKeeper, PrivilegesError> {
+    let pid = std::process::id();
+    let (read_fd, write_fd) = pipe()?;
+    let read_end = unsafe { File::from_raw_fd(read_fd) };
+    let write_end = unsafe { File::from_raw_fd(write_fd) };
+
+    match unsafe { fork() }? {
+        ForkResult::Parent { child } => {
+            drop(read_end);
+            Ok(RestoreKeeper {
+                pid: child,
+                pipe: Some(write_end),
+            })
+        }
+        ForkResult::Child => {
+            drop(write_end);
+            run_keeper(read_end, pid)
+        }
+    }
+}
+
+fn run_keeper(mut pipe: File, pid: u32) -> ! {
+    // Signals from the terminal or the service manager reach us as well as the
+    // client. We restore once the client is gone, not before.
+    for signal in [libc::SIGINT, libc::SIGHUP, libc::SIGQUIT, libc::SIGTERM] {
+        unsafe { libc::signal(signal, libc::SIG_IGN) };
+    }
+
+    let mut buf = [0u8; 64];
+    while !matches!(pipe.read(&mut buf), Ok(0) | Err(_)) {}
+
+    let result = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(StateError::from)
+        .and_then(|runtime| runtime.block_on(state::restore_pid(Path::new(STATE_DIR), pid)));
+    match result {
+        Ok(()) => std::process::exit(0),
+        Err(err) => {
+            log::error!("Error restoring firewall of process {pid}: {err}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Become `uid` and `gid`, keeping only `CAP_NET_ADMIN` and `CAP_NET_RAW`.
+///
+/// Those are enough to create transparent sockets, restoring the firewall is left
+/// to the `RestoreKeeper`. Capabilities belong to a thread, so only the calling
+/// thread keeps them. They are not inherited by the commands it runs.
+pub fn drop_privileges(uid: Uid, gid: Gid) -> Result<(), PrivilegesError> {
+    // Without this setuid clears the permitted capabilities as well.
+    prctl(libc::PR_SET_KEEPCAPS, 1)?;
+
+    setgroups(&[gid])?;
+    setgid(gid)?;
+    setuid(uid)?;
+
+    capset(NET_CAPS)?;
+
+    log::info!("Now running as uid {uid} gid {gid}");
+    Ok(())
+}
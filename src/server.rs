@@ -0,0 +1,182 @@
+//! `sshuttle_rust server`, run on the remote by the client over ssh.
+//!
+//! Frames are read from stdin and answered on stdout, see `mux`. Log messages go
+//! to stderr, which ssh passes back to the client.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::select;
+use tokio::sync::mpsc;
+use tokio::time::{sleep_until, timeout, Instant};
+
+use crate::mux::{self, Frame, Incoming, MuxError, Streams, VERSION};
+
+/// Give up on a DNS query if the resolver hasn't answered by now.
+const DNS_TIMEOUT: Duration = Duration::from_secs(10);
+/// Close a UDP flow that has been idle this long, even if the client never does.
+const UDP_TIMEOUT: Duration = Duration::from_mins(5);
+const UDP_BUFFER_SIZE: usize = 65536;
+
+const fn unspecified(addr: SocketAddr) -> &'static str {
+    match addr {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    }
+}
+
+/// Serve the client on stdin and stdout until it goes away.
+pub async fn run() -> Result<(), MuxError> {
+    let (outgoing, _writer) = mux::spawn_writer(tokio::io::stdout());
+    outgoing
+        .send(Frame::Hello(VERSION.to_vec()))
+        .await
+        .map_err(|_| MuxError::Closed)?;
+
+    let streams = Streams::default();
+    let mut stdin = tokio::io::stdin();
+    while let Some(frame) = mux::read_frame(&mut stdin).await? {
+        match frame {
+            Frame::Connect { id, addr } => {
+                let incoming = streams.insert(id);
+                let outgoing = outgoing.clone();
+                let streams = streams.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = handle_connect(id, addr, incoming, outgoing).await {
+                        log::debug!("connection {id} to {addr} failed: {err}");
+                    }
+                    streams.remove(id);
+                });
+            }
+            Frame::Datagram { id, .. } if !streams.contains(id) => {
+                let incoming = streams.insert(id);
+                streams.dispatch(frame, &outgoing);
+                let outgoing = outgoing.clone();
+                let streams = streams.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = handle_udp_flow(id, incoming, &outgoing).await {
+                        log::debug!("udp flow {id} failed: {err}");
+                        _ = outgoing
+                            .send(Frame::Close {
+                                id,
+                                error: err.to_string(),
+                            })
+                            .await;
+                    }
+                    streams.remove(id);
+                });
+            }
+            Frame::DnsQuery { id, server, data } => {
+                let outgoing = outgoing.clone();
+                tokio::spawn(async move {
+                    let frame = match query_dns(server, &data).await {
+                        Ok(data) => Frame::DnsAnswer { id, data },
+                        Err(err) => {
+                            log::debug!("DNS query {id} to {server} failed: {err}");
+                            Frame::Close {
+                                id,
+                                error: err.to_string(),
+                            }
+                        }
+                    };
+                    _ = outgoing.send(frame).await;
+                });
+            }
+            frame => streams.dispatch(frame, &outgoing),
+        }
+    }
+
+    log::info!("Client closed the connection");
+    Ok(())
+}
+
+async fn handle_connect(
+    id: u32,
+    addr: SocketAddr,
+    incoming: Incoming,
+    outgoing: mpsc::Sender<Frame>,
+) -> Result<(), std::io::Error> {
+    let stream = match TcpStream::connect(addr).await {
+        Ok(stream) => stream,
+        Err(err) => {
+            _ = outgoing
+                .send(Frame::Close {
+                    id,
+                    error: err.to_string(),
+                })
+                .await;
+            return Err(err);
+        }
+    };
+    log::debug!("connection {id} to {addr} is open");
+    if outgoing.send(Frame::Connected { id }).await.is_err() {
+        return Ok(());
+    }
+    mux::pump(stream, id, incoming, outgoing).await
+}
+
+/// Forward the datagrams of one flow from a socket of its own, until the client
+/// closes it or it times out.
+async fn handle_udp_flow(
+    id: u32,
+    mut incoming: Incoming,
+    outgoing: &mpsc::Sender<Frame>,
+) -> Result<(), std::io::Error> {
+    let mut socket: Option<UdpSocket> = None;
+    let mut buf = vec![0; UDP_BUFFER_SIZE];
+    let mut deadline = Instant::now() + UDP_TIMEOUT;
+    loop {
+        select! {
+            frame = incoming.recv() => match frame {
+                Some(Frame::Datagram { addr, data, .. }) => {
+                    if socket.is_none() {
+                        socket = Some(UdpSocket::bind(unspecified(addr)).await?);
+                    }
+                    if let Some(socket) = &socket {
+                        socket.send_to(&data, addr).await?;
+                    }
+                    deadline = Instant::now() + UDP_TIMEOUT;
+                }
+                Some(Frame::Close { .. }) | None => return Ok(()),
+                Some(frame) => log::debug!("unexpected frame on flow {id}: {frame:?}"),
+            },
+            res = recv_from(socket.as_ref(), &mut buf) => {
+                let (len, addr) = res?;
+                let frame = Frame::Datagram { id, addr, data: buf[..len].to_vec() };
+                if outgoing.send(frame).await.is_err() {
+                    return Ok(());
+                }
+                deadline = Instant::now() + UDP_TIMEOUT;
+            }
+            () = sleep_until(deadline) => {
+                log::debug!("udp flow {id} timed out");
+                let error = String::new();
+                _ = outgoing.send(Frame::Close { id, error }).await;
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Receive on the socket of a flow, once it has one.
+async fn recv_from(
+    socket: Option<&UdpSocket>,
+    buf: &mut [u8],
+) -> Result<(usize, SocketAddr), std::io::Error> {
+    match socket {
+        Some(socket) => socket.recv_from(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn query_dns(server: SocketAddr, query: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    let socket = UdpSocket::bind(unspecified(server)).await?;
+    socket.connect(server).await?;
+    socket.send(query).await?;
+
+    let mut buf = vec![0; UDP_BUFFER_SIZE];
+    let len = timeout(DNS_TIMEOUT, socket.recv(&mut buf)).await??;
+    buf.truncate(len);
+    Ok(buf)
+}
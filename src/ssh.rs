@@ -0,0 +1,517 @@
+//! Build the ssh command line.
+
+use std::{
+    fmt::{Display, Formatter},
+    io::{self, Read, Write},
+    net::SocketAddr as IpSocketAddr,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::{
+            net::{SocketAddr, UnixListener, UnixStream},
+            prelude::AsRawFd,
+        },
+    },
+    path::Path,
+    str::FromStr,
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use nix::{
+    sys::socket::{getsockopt, sockopt::PeerCredentials},
+    unistd::{Gid, Uid},
+};
+use thiserror::Error;
+use tokio::{net::lookup_host, process::Command};
+
+/// Set in the environment of ssh to the abstract socket that hands out the
+/// password, so that when ssh runs us as its askpass program we print it.
+pub const ASKPASS_SOCKET_ENV: &str = "SSHUTTLE_RUST_ASKPASS_SOCKET";
+
+/// The socket serving the password of the remote, started by the first ssh command.
+static ASKPASS_SOCKET: OnceLock<Option<String>> = OnceLock::new();
+
+#[derive(Error, Debug)]
+pub enum SshError {
+    #[error("Invalid ssh command `{0}`")]
+    Parse(#[from] shell_words::ParseError),
+
+    #[error("The ssh command is empty")]
+    Empty,
+
+    #[error("Invalid remote `{0}`, expected [USERNAME[:PASSWORD]@]ADDR[:PORT]")]
+    InvalidRemote(String),
+
+    #[error("Could not resolve `{0}`: {1}")]
+    Resolve(String, io::Error),
+
+    #[error("No address found for `{0}`")]
+    NoAddress(String),
+}
+
+/// Where a remote was resolved to, so ssh always reconnects to the same server.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Resolved {
+    pub addr: IpSocketAddr,
+    /// The name the host key is known by, which `addr` no longer tells ssh.
+    pub host_key_alias: String,
+}
+
+/// A remote given as `[USERNAME[:PASSWORD]@]ADDR[:PORT]`.
+///
+/// IPv6 addresses with a port need brackets, as in `[::1]:2222`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Remote {
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub host: String,
+    pub port: Option<u16>,
+    /// Set by `resolve`.
+    pub resolved: Option<Resolved>,
+}
+
+impl Remote {
+    /// The arguments that tell ssh where to connect to, these go after any options.
+    pub fn ssh_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(resolved) = &self.resolved {
+            args.push("-o".to_string());
+            args.push(format!("HostName={}", resolved.addr.ip()));
+            args.push("-o".to_string());
+            args.push(format!("HostKeyAlias={}", resolved.host_key_alias));
+        }
+        if let Some(port) = self.port {
+            args.push("-p".to_string());
+            args.push(port.to_string());
+        }
+        match &self.user {
+            Some(user) => args.push(format!("{user}@{}", self.host)),
+            None => args.push(self.host.clone()),
+        }
+        args
+    }
+}
+
+impl FromStr for Remote {
+    type Err = SshError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || SshError::InvalidRemote(s.to_string());
+
+        let (user, password, host_port) = match s.rsplit_once('@') {
+            Some((user_info, host_port)) => match user_info.split_once(':') {
+                Some((user, password)) => (Some(user), Some(password), host_port),
+                None => (Some(user_info), None, host_port),
+            },
+            None => (None, None, s),
+        };
+
+        let (host, port) = if let Some(rest) = host_port.strip_prefix('[') {
+            let (host, rest) = rest.split_once(']').ok_or_else(invalid)?;
+            match rest {
+                "" => (host, None),
+                _ => (host, Some(rest.strip_prefix(':').ok_or_else(invalid)?)),
+            }
+        } else {
+            match host_port.split_once(':') {
+                // A bare IPv6 address has more than one colon, and can't have a port.
+                Some((host, port)) if !port.contains(':') => (host, Some(port)),
+                _ => (host_port, None),
+            }
+        };
+
+        if host.is_empty() || user == Some("") {
+            return Err(invalid());
+        }
+        let port = port
+            .map(|port| port.parse::<u16>().map_err(|_| invalid()))
+            .transpose()?;
+
+        Ok(Remote {
+            user: user.map(ToString::to_string),
+            password: password.map(ToString::to_string),
+            host: host.to_string(),
+            port,
+            resolved: None,
+        })
+    }
+}
+
+/// The host, port and host key alias from the output of `ssh -G`.
+fn parse_ssh_config(output: &str) -> Option<(String, u16, String)> {
+    let mut host = None;
+    let mut port = None;
+    let mut alias = None;
+    for line in output.lines() {
+        match line.split_once(' ') {
+            Some(("hostname", value)) => host = Some(value.to_string()),
+            Some(("port", value)) => port = value.parse().ok(),
+            Some(("hostkeyalias", value)) => alias = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    let host = host?;
+    let alias = alias.unwrap_or_else(|| host.clone());
+    Some((host, port?, alias))
+}
+
+impl Remote {
+    /// Resolve the remote once, before the firewall is set up, so that reconnecting
+    /// never needs DNS, and goes to an address that can be excluded from the firewall.
+    ///
+    /// With `ssh_cmd`, ssh is asked with `-G` where it would connect to, so its
+    /// options and `ssh_config` are honoured. Otherwise, or if that fails, the host and
+    /// port of the remote are used.
+    pub async fn resolve(
+        &mut self,
+        ssh_cmd: Option<&[String]>,
+        run_as: Option<(Uid, Gid)>,
+    ) -> Result<IpSocketAddr, SshError> {
+        let mut destination = None;
+        if let Some(ssh_cmd) = ssh_cmd {
+            match command(ssh_cmd, &["-G"], self, run_as).output().await {
+                Ok(output) if output.status.success() => {
+                    destination = parse_ssh_config(&String::from_utf8_lossy(&output.stdout));
+                }
+                Ok(output) => log::warn!(
+                    "`ssh -G` failed for {self}: {}",
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ),
+                Err(err) => log::warn!("Could not run `ssh -G` for {self}: {err}"),
+            }
+        }
+        let (host, port, host_key_alias) = destination.unwrap_or_else(|| {
+            (
+                self.host.clone(),
+                self.port.unwrap_or(22),
+                self.host.clone(),
+            )
+        });
+
+        let addr = lookup_host((host.as_str(), port))
+            .await
+            .map_err(|err| SshError::Resolve(host.clone(), err))?
+            .next()
+            .ok_or_else(|| SshError::NoAddress(host.clone()))?;
+        self.resolved = Some(Resolved {
+            addr,
+            host_key_alias,
+        });
+        Ok(addr)
+    }
+}
+
+impl Display for Remote {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        if let Some(user) = &self.user {
+            write!(f, "{user}@")?;
+        }
+        if self.host.contains(':') {
+            write!(f, "[{}]", self.host)?;
+        } else {
+            write!(f, "{}", self.host)?;
+        }
+        if let Some(port) = self.port {
+            write!(f, ":{port}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Serve `password` to processes of `uid` on a new abstract unix socket, and return
+/// its name.
+///
+/// The password stays in this process, so it is not in the environment of ssh and
+/// everything ssh runs.
+fn serve_password(password: String, uid: Uid) -> io::Result<String> {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    let name = format!("sshuttle_rust-askpass-{}-{nanos}", std::process::id());
+    let listener = UnixListener::bind_addr(&SocketAddr::from_abstract_name(&name)?)?;
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    log::warn!("askpass accept failed: {err}");
+                    continue;
+                }
+            };
+            // Abstract sockets have no permissions, so check who is asking.
+            match getsockopt(stream.as_raw_fd(), PeerCredentials) {
+                Ok(cred) if cred.uid() == uid.as_raw() => {
+                    if let Err(err) = stream.write_all(password.as_bytes()) {
+                        log::warn!("Could not send the password to askpass: {err}");
+                    }
+                }
+                Ok(cred) => log::warn!("Refusing the password to uid {}", cred.uid()),
+                Err(err) => log::warn!("Refusing the password to unknown process: {err}"),
+            }
+        }
+    });
+    Ok(name)
+}
+
+/// Whether ssh is asking for a password, as opposed to a key passphrase or to
+/// confirm a host key, which must never be answered with the password.
+///
+/// ssh sets `SSH_ASKPASS_PROMPT`, given as `hint`, for confirmations only.
+pub fn is_password_prompt(prompt: &str, hint: Option<&str>) -> bool {
+    hint.is_none() && prompt.trim_end().to_lowercase().ends_with("password:")
+}
+
+/// Run as ssh's askpass program: fetch the password from `socket`, if ssh is
+/// asking for one.
+pub fn askpass(socket: &str, prompt: &str, hint: Option<&str>) -> io::Result<Option<String>> {
+    if !is_password_prompt(prompt, hint) {
+        return Ok(None);
+    }
+    let mut stream = UnixStream::connect_addr(&SocketAddr::from_abstract_name(socket)?)?;
+    let mut password = String::new();
+    stream.read_to_string(&mut password)?;
+    if password.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "the password was refused",
+        ));
+    }
+    Ok(Some(password))
+}
+
+/// The ssh `Command` to connect to `remote`, with `options` given before the destination.
+///
+/// ssh runs as `run_as` when given, so it uses the ssh agent and known hosts of that
+/// user. If the remote has a password, ssh is told to ask for it by running this
+/// program as its askpass helper, which needs OpenSSH 8.4 or later.
+pub fn command(
+    ssh_cmd: &[String],
+    options: &[&str],
+    remote: &Remote,
+    run_as: Option<(Uid, Gid)>,
+) -> Command {
+    let mut command = Command::new(&ssh_cmd[0]);
+    command
+        .args(&ssh_cmd[1..])
+        .args(options)
+        .args(remote.ssh_args());
+    if let Some((uid, gid)) = run_as {
+        command.uid(uid.as_raw()).gid(gid.as_raw());
+    }
+
+    if let Some(password) = &remote.password {
+        let socket = ASKPASS_SOCKET.get_or_init(|| {
+            let uid = run_as.map_or_else(Uid::current, |(uid, _)| uid);
+            match serve_password(password.clone(), uid) {
+                Ok(socket) => Some(socket),
+                Err(err) => {
+                    log::error!("Cannot use the password for {remote}: {err}");
+                    None
+                }
+            }
+        });
+        match (std::env::current_exe(), socket) {
+            (Ok(exe), Some(socket)) => {
+                command
+                    .env("SSH_ASKPASS", exe)
+                    .env("SSH_ASKPASS_REQUIRE", "force")
+                    .env(ASKPASS_SOCKET_ENV, socket);
+            }
+            (Err(err), _) => log::error!("Cannot use the password for {remote}: {err}"),
+            (Ok(_), None) => {}
+        }
+    }
+    command
+}
+
+/// The ssh program and the arguments to pass before anything else.
+///
+/// `cmd` is split into words the way a shell does, so it can carry its own options
+/// or be a wrapper script.
+pub fn ssh_command(
+    cmd: &str,
+    options: &[String],
+    port: Option<u16>,
+    identity: Option<&Path>,
+) -> Result<Vec<String>, SshError> {
+    let mut command = shell_words::split(cmd)?;
+    if command.is_empty() {
+        return Err(SshError::Empty);
+    }
+
+    for option in options {
+        command.push("-o".to_string());
+        command.push(option.clone());
+    }
+    if let Some(port) = port {
+        command.push("-p".to_string());
+        command.push(port.to_string());
+    }
+    if let Some(identity) = identity {
+        command.push("-i".to_string());
+        command.push(identity.display().to_string());
+    }
+    Ok(command)
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_password_prompt() {
+        assert!(is_password_prompt(
+            "user@host.example.org's password: ",
+            None
+        ));
+        assert!(is_password_prompt("Password:", None));
+        assert!(!is_password_prompt(
+            "Enter passphrase for key '/home/user/.ssh/id_ed25519': ",
+            None
+        ));
+        assert!(!is_password_prompt(
+            "Are you sure you want to continue connecting (yes/no/[fingerprint])? ",
+            None
+        ));
+        assert!(!is_password_prompt(
+            "Allow use of key? password:",
+            Some("confirm")
+        ));
+    }
+
+    #[test]
+    fn test_askpass() {
+        let socket = serve_password("p@ss word".to_string(), Uid::current()).unwrap();
+        let password = askpass(&socket, "user@host's password: ", None).unwrap();
+        assert_eq!(password.as_deref(), Some("p@ss word"));
+        assert_eq!(askpass(&socket, "Enter passphrase: ", None).unwrap(), None);
+
+        // Only the given user gets the password.
+        let other = Uid::from_raw(Uid::current().as_raw() + 1);
+        let socket = serve_password("p@ss word".to_string(), other).unwrap();
+        assert!(askpass(&socket, "user@host's password: ", None).is_err());
+    }
+
+    #[test]
+    fn test_ssh_command() {
+        let command = ssh_command(
+            r#"ssh -o "ProxyJump=bastion host" -i 'my key'"#,
+            &["ServerAliveInterval=15".to_string()],
+            Some(2222),
+            Some(Path::new("/home/user/.ssh/id_ed25519")),
+        )
+        .unwrap();
+        assert_eq!(
+            command,
+            [
+                "ssh",
+                "-o",
+                "ProxyJump=bastion host",
+                "-i",
+                "my key",
+                "-o",
+                "ServerAliveInterval=15",
+                "-p",
+                "2222",
+                "-i",
+                "/home/user/.ssh/id_ed25519",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_remote() {
+        let remote: Remote = "user:p@ss:word@host.example.org:2222".parse().unwrap();
+        assert_eq!(remote.user.as_deref(), Some("user"));
+        assert_eq!(remote.password.as_deref(), Some("p@ss:word"));
+        assert_eq!(remote.host, "host.example.org");
+        assert_eq!(remote.port, Some(2222));
+        assert_eq!(remote.ssh_args(), ["-p", "2222", "user@host.example.org"]);
+        assert_eq!(remote.to_string(), "user@host.example.org:2222");
+
+        let remote: Remote = "host.example.org".parse().unwrap();
+        assert_eq!(remote.ssh_args(), ["host.example.org"]);
+
+        let remote: Remote = "root@[2001:db8::1]:22".parse().unwrap();
+        assert_eq!(remote.host, "2001:db8::1");
+        assert_eq!(remote.port, Some(22));
+        assert_eq!(remote.to_string(), "root@[2001:db8::1]:22");
+
+        let remote: Remote = "2001:db8::1".parse().unwrap();
+        assert_eq!(remote.host, "2001:db8::1");
+        assert_eq!(remote.port, None);
+    }
+
+    #[test]
+    fn test_remote_resolved() {
+        let mut remote: Remote = "user@host.example.org".parse().unwrap();
+        remote.resolved = Some(Resolved {
+            addr: "192.0.2.1:2222".parse().unwrap(),
+            host_key_alias: "host.example.org".to_string(),
+        });
+        assert_eq!(
+            remote.ssh_args(),
+            [
+                "-o",
+                "HostName=192.0.2.1",
+                "-o",
+                "HostKeyAlias=host.example.org",
+                "user@host.example.org"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_ssh_config() {
+        let output = "user root\nhostname host.example.org\nport 2222\naddressfamily any\n";
+        assert_eq!(
+            parse_ssh_config(output),
+            Some((
+                "host.example.org".to_string(),
+                2222,
+                "host.example.org".to_string()
+            ))
+        );
+
+        let output = "hostname 192.0.2.1\nport 22\nhostkeyalias myhost\n";
+        assert_eq!(
+            parse_ssh_config(output),
+            Some(("192.0.2.1".to_string(), 22, "myhost".to_string()))
+        );
+
+        assert_eq!(parse_ssh_config("user root\n"), None);
+    }
+
+    #[test]
+    fn test_remote_invalid() {
+        for remote in [
+            "",
+            "user@",
+            "@host",
+            "host:port",
+            "[::1",
+            "[::1]22",
+            "host:99999",
+        ] {
+            assert!(
+                matches!(remote.parse::<Remote>(), Err(SshError::InvalidRemote(_))),
+                "{remote}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_ssh_command_invalid() {
+        assert!(matches!(
+            ssh_command("", &[], None, None),
+            Err(SshError::Empty)
+        ));
+        assert!(matches!(
+            ssh_command("ssh -o 'unterminated", &[], None, None),
+            Err(SshError::Parse(_))
+        ));
+    }
+}
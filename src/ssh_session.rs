@@ -0,0 +1,559 @@
+//! A built in ssh client, used instead of running `ssh -D`.
+//!
+//! Each redirected connection is a `direct-tcpip` channel on one ssh session.
+//! libssh2 is not async, so the session lives on a thread of its own in
+//! non-blocking mode. Every channel is paired with a `DuplexStream`, and a task
+//! copies between the two through a pair of queues, waking the session thread
+//! whenever it queues or takes something.
+
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream as StdTcpStream;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc as std_mpsc, Arc};
+use std::time::Duration;
+
+use nix::unistd::{Uid, User};
+use ssh2::{BlockDirections, CheckResult, ErrorCode, KnownHostFileKind, Session};
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::ssh::Remote;
+
+const BUFFER_SIZE: usize = 32 * 1024;
+const CHANNEL_QUEUE: usize = 16;
+const KEEPALIVE_INTERVAL: u32 = 30;
+/// How long the session thread sleeps when there is nothing to do, so keepalives are sent.
+const IDLE_POLL: Duration = Duration::from_secs(1);
+/// Identities tried when the agent can't authenticate us and no --ssh-identity is given.
+const DEFAULT_IDENTITIES: [&str; 3] = [".ssh/id_ed25519", ".ssh/id_ecdsa", ".ssh/id_rsa"];
+
+#[derive(Error, Debug)]
+pub enum SessionError {
+    #[error("IO Error `{0}`")]
+    Io(#[from] std::io::Error),
+
+    #[error("ssh Error `{0}`")]
+    Ssh(#[from] ssh2::Error),
+
+    #[error("Host key for {0} {1}")]
+    HostKey(String, &'static str),
+
+    #[error("Could not authenticate to {0}")]
+    Auth(String),
+
+    #[error("ssh session closed")]
+    Closed,
+
+    #[error("Could not open channel to {0}: {1}")]
+    Open(String, String),
+}
+
+/// How to connect with the built in ssh client.
+#[derive(Clone)]
+pub struct SessionOptions {
+    /// Private key to use if the ssh agent doesn't have one the server accepts.
+    pub identity: Option<PathBuf>,
+    /// The --setuid user, whose known hosts, agent and keys are used instead of
+    /// those of the user we were started as.
+    pub run_as: Option<Uid>,
+}
+
+/// Wakes the session thread up from `poll`.
+#[derive(Clone)]
+struct Waker(Arc<UnixStream>);
+
+impl Waker {
+    fn wake(&self) {
+        // A full socket already wakes the thread up.
+        _ = (&*self.0).write(&[0]);
+    }
+}
+
+struct Open {
+    host: String,
+    port: u16,
+    to_local: mpsc::Sender<Vec<u8>>,
+    from_local: mpsc::Receiver<Vec<u8>>,
+    reply: oneshot::Sender<Result<(), String>>,
+}
+
+/// A connected ssh session, cheap to clone.
+#[derive(Clone)]
+pub struct SshSession {
+    opens: std_mpsc::Sender<Open>,
+    waker: Waker,
+}
+
+impl SshSession {
+    /// Connect and authenticate to `remote`.
+    ///
+    /// Returns the session, and a receiver that gets the error that ended it.
+    pub async fn start(
+        remote: Remote,
+        options: &SessionOptions,
+    ) -> Result<(Self, oneshot::Receiver<SessionError>), SessionError> {
+        let options = options.clone();
+        let session = tokio::task::spawn_blocking(move || connect(&remote, &options))
+            .await
+            .map_err(|_| SessionError::Closed)??;
+
+        let (wake_rx, wake_tx) = UnixStream::pair()?;
+        wake_rx.set_nonblocking(true)?;
+        wake_tx.set_nonblocking(true)?;
+        let (opens, opens_rx) = std_mpsc::channel();
+        let (closed_tx, closed_rx) = oneshot::channel();
+
+        std::thread::spawn(move || {
+            let err = run_session(&session, &opens_rx, &wake_rx);
+            _ = closed_tx.send(err);
+        });
+
+        let waker = Waker(Arc::new(wake_tx));
+        Ok((Self { opens, waker }, closed_rx))
+    }
+
+    /// Open a `direct-tcpip` channel to `host` and `port`, as seen from the remote.
+    pub async fn connect(&self, host: &str, port: u16) -> Result<DuplexStream, SessionError> {
+        let (to_local, from_remote) = mpsc::channel(CHANNEL_QUEUE);
+        let (to_remote, from_local) = mpsc::channel(CHANNEL_QUEUE);
+        let (reply, reply_rx) = oneshot::channel();
+        let open = Open {
+            host: host.to_string(),
+            port,
+            to_local,
+            from_local,
+            reply,
+        };
+        self.opens.send(open).map_err(|_| SessionError::Closed)?;
+        self.waker.wake();
+
+        reply_rx
+            .await
+            .map_err(|_| SessionError::Closed)?
+            .map_err(|err| SessionError::Open(format!("{host}:{port}"), err))?;
+
+        let (stream, channel_end) = tokio::io::duplex(BUFFER_SIZE);
+        let waker = self.waker.clone();
+        tokio::spawn(async move {
+            if let Err(err) = pump(channel_end, to_remote, from_remote, waker).await {
+                log::debug!("ssh channel pump failed: {err}");
+            }
+        });
+        Ok(stream)
+    }
+}
+
+/// Copy between our end of the duplex stream and the channel queues.
+async fn pump(
+    stream: DuplexStream,
+    to_remote: mpsc::Sender<Vec<u8>>,
+    mut from_remote: mpsc::Receiver<Vec<u8>>,
+    waker: Waker,
+) -> Result<(), std::io::Error> {
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let waker = &waker;
+
+    let upload = async move {
+        let mut buf = vec![0; BUFFER_SIZE];
+        loop {
+            let len = reader.read(&mut buf).await?;
+            if len == 0 || to_remote.send(buf[..len].to_vec()).await.is_err() {
+                break;
+            }
+            waker.wake();
+        }
+        // Dropping the sender tells the session thread to send EOF.
+        drop(to_remote);
+        waker.wake();
+        Ok::<_, std::io::Error>(())
+    };
+
+    let download = async move {
+        while let Some(data) = from_remote.recv().await {
+            // There is room in the queue again.
+            waker.wake();
+            writer.write_all(&data).await?;
+        }
+        writer.shutdown().await
+    };
+
+    let (upload, download) = tokio::join!(upload, download);
+    upload.and(download)
+}
+
+/// The home directory of `run_as` from its passwd entry, as `$HOME` is still
+/// ours. Otherwise `$HOME`.
+fn home(run_as: Option<Uid>) -> Option<PathBuf> {
+    run_as.map_or_else(
+        || std::env::var_os("HOME").map(PathBuf::from),
+        |uid| User::from_uid(uid).ok().flatten().map(|user| user.dir),
+    )
+}
+
+fn user_name(remote: &Remote, run_as: Option<Uid>) -> Result<String, SessionError> {
+    if let Some(user) = &remote.user {
+        return Ok(user.clone());
+    }
+    match User::from_uid(run_as.unwrap_or_else(Uid::current)) {
+        Ok(Some(user)) => Ok(user.name),
+        _ => Err(SessionError::Auth(remote.to_string())),
+    }
+}
+
+/// Whether the agent in `$SSH_AUTH_SOCK` may be used: with `run_as` it must be
+/// that user's agent, as sudo may have left us with another one.
+fn agent_usable(run_as: Option<Uid>) -> bool {
+    run_as.is_none_or(|uid| {
+        std::env::var_os("SSH_AUTH_SOCK")
+            .and_then(|socket| std::fs::metadata(socket).ok())
+            .is_some_and(|socket| socket.uid() == uid.as_raw())
+    })
+}
+
+/// Check the host key against `~/.ssh/known_hosts`, unknown hosts are refused.
+fn check_host_key(
+    session: &Session,
+    remote: &Remote,
+    port: u16,
+    home: Option<&Path>,
+) -> Result<(), SessionError> {
+    let host = remote.to_string();
+    let (key, _) = session
+        .host_key()
+        .ok_or_else(|| SessionError::HostKey(host.clone(), "is missing"))?;
+
+    let mut known_hosts = session.known_hosts()?;
+    if let Some(home) = home {
+        let file = home.join(".ssh/known_hosts");
+        if file.exists() {
+            known_hosts.read_file(&file, KnownHostFileKind::OpenSSH)?;
+        }
+    }
+
+    match known_hosts.check_port(&remote.host, port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => Err(SessionError::HostKey(host, "does not match known_hosts")),
+        CheckResult::NotFound => Err(SessionError::HostKey(host, "is not in known_hosts")),
+        CheckResult::Failure => Err(SessionError::HostKey(host, "could not be checked")),
+    }
+}
+
+/// Try the password, the ssh agent, then the identity files, in that order.
+fn authenticate(
+    session: &Session,
+    remote: &Remote,
+    options: &SessionOptions,
+    home: Option<&Path>,
+) -> Result<(), SessionError> {
+    let user = user_name(remote, options.run_as)?;
+
+    if let Some(password) = &remote.password {
+        session.userauth_password(&user, password)?;
+        return Ok(());
+    }
+
+    if !agent_usable(options.run_as) {
+        log::debug!("Not using the ssh agent, it doesn't belong to the --setuid user");
+    } else if let Err(err) = session.userauth_agent(&user) {
+        log::debug!("ssh agent authentication failed: {err}");
+    }
+
+    let identities: Vec<PathBuf> = match (options.identity.as_deref(), home) {
+        (Some(identity), _) => vec![identity.to_path_buf()],
+        (None, Some(home)) => DEFAULT_IDENTITIES.iter().map(|f| home.join(f)).collect(),
+        (None, None) => Vec::new(),
+    };
+    for identity in identities {
+        if session.authenticated() {
+            break;
+        }
+        if identity.exists() {
+            if let Err(err) = session.userauth_pubkey_file(&user, None, &identity, None) {
+                log::debug!("{} authentication failed: {err}", identity.display());
+            }
+        }
+    }
+
+    if session.authenticated() {
+        Ok(())
+    } else {
+        Err(SessionError::Auth(remote.to_string()))
+    }
+}
+
+fn connect(remote: &Remote, options: &SessionOptions) -> Result<Session, SessionError> {
+    let port = remote.port.unwrap_or(22);
+    log::info!("Connecting to {remote} with the built in ssh client");
+    let tcp = match &remote.resolved {
+        Some(resolved) => StdTcpStream::connect(resolved.addr)?,
+        None => StdTcpStream::connect((remote.host.as_str(), port))?,
+    };
+
+    let mut session = Session::new()?;
+    session.set_tcp_stream(tcp);
+    session.handshake()?;
+    let home = home(options.run_as);
+    check_host_key(&session, remote, port, home.as_deref())?;
+    authenticate(&session, remote, options, home.as_deref())?;
+    session.set_keepalive(false, KEEPALIVE_INTERVAL);
+
+    log::info!("ssh session to {remote} is ready");
+    Ok(session)
+}
+
+/// `LIBSSH2_ERROR_EAGAIN`, the call would block.
+const ERROR_EAGAIN: i32 = -37;
+
+fn would_block(err: &ssh2::Error) -> bool {
+    err.code() == ErrorCode::Session(ERROR_EAGAIN)
+}
+
+/// One `direct-tcpip` channel and the queues to its duplex stream.
+struct Forward {
+    channel: ssh2::Channel,
+    /// `None` once the remote has sent EOF, or the stream is gone.
+    to_local: Option<mpsc::Sender<Vec<u8>>>,
+    from_local: mpsc::Receiver<Vec<u8>>,
+    /// Read from the channel, waiting for room in the queue.
+    unread: Option<Vec<u8>>,
+    /// Taken from the queue, waiting for the channel to accept it.
+    unwritten: Vec<u8>,
+    local_eof: bool,
+    eof_sent: bool,
+}
+
+impl Forward {
+    const fn done(&self) -> bool {
+        self.to_local.is_none() && self.eof_sent
+    }
+
+    /// Move data as far as it will go without blocking, returns true if anything moved.
+    fn step(&mut self, buf: &mut [u8]) -> Result<bool, std::io::Error> {
+        let mut progress = self.download(buf)?;
+
+        if self.unwritten.is_empty() && !self.local_eof {
+            match self.from_local.try_recv() {
+                Ok(data) => self.unwritten = data,
+                Err(mpsc::error::TryRecvError::Empty) => {}
+                Err(mpsc::error::TryRecvError::Disconnected) => self.local_eof = true,
+            }
+        }
+        if !self.unwritten.is_empty() {
+            match self.channel.write(&self.unwritten) {
+                Ok(len) => {
+                    self.unwritten.drain(..len);
+                    progress = true;
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err),
+            }
+        }
+        if self.local_eof && self.unwritten.is_empty() && !self.eof_sent {
+            match self.channel.send_eof() {
+                Ok(()) => {
+                    self.eof_sent = true;
+                    progress = true;
+                }
+                Err(err) if would_block(&err) => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(progress)
+    }
+
+    fn download(&mut self, buf: &mut [u8]) -> Result<bool, std::io::Error> {
+        if self.to_local.is_none() {
+            return Ok(false);
+        }
+
+        let data = match self.unread.take() {
+            Some(data) => data,
+            None => match self.channel.read(buf) {
+                Ok(0) if self.channel.eof() => {
+                    self.to_local = None;
+                    return Ok(true);
+                }
+                Ok(0) => return Ok(false),
+                Ok(len) => buf[..len].to_vec(),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(err) => return Err(err),
+            },
+        };
+
+        match self
+            .to_local
+            .as_ref()
+            .map(|to_local| to_local.try_send(data))
+        {
+            Some(Ok(())) => Ok(true),
+            Some(Err(mpsc::error::TrySendError::Full(data))) => {
+                self.unread = Some(data);
+                Ok(false)
+            }
+            Some(Err(mpsc::error::TrySendError::Closed(_))) | None => {
+                self.to_local = None;
+                Ok(true)
+            }
+        }
+    }
+}
+
+/// Wait until the session socket is ready in the direction libssh2 is blocked
+/// on, or we are woken up.
+fn wait(session: &Session, wake: &UnixStream) {
+    let events = match session.block_directions() {
+        BlockDirections::Inbound => libc::POLLIN,
+        BlockDirections::Outbound => libc::POLLOUT,
+        BlockDirections::Both => libc::POLLIN | libc::POLLOUT,
+        BlockDirections::None => 0,
+    };
+    let mut fds = [
+        libc::pollfd {
+            fd: wake.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        },
+        libc::pollfd {
+            fd: session.as_raw_fd(),
+            events,
+            revents: 0,
+        },
+    ];
+    let timeout = i32::try_from(IDLE_POLL.as_millis()).unwrap_or(i32::MAX);
+    // Errors, such as EINTR, just mean another trip round the loop.
+    unsafe { libc::poll(fds.as_mut_ptr(), 2, timeout) };
+
+    let mut buf = [0u8; 64];
+    while matches!((&*wake).read(&mut buf), Ok(len) if len > 0) {}
+}
+
+/// Service the channels until the session fails, or every handle to it is dropped.
+fn run_session(
+    session: &Session,
+    opens: &std_mpsc::Receiver<Open>,
+    wake: &UnixStream,
+) -> SessionError {
+    session.set_blocking(false);
+    let mut pending: VecDeque<Open> = VecDeque::new();
+    let mut forwards: Vec<Forward> = Vec::new();
+    // Channels are closed without blocking as well, so it can take a few tries.
+    let mut closing: Vec<ssh2::Channel> = Vec::new();
+    let mut buf = vec![0; BUFFER_SIZE];
+
+    loop {
+        loop {
+            match opens.try_recv() {
+                Ok(open) => pending.push_back(open),
+                Err(std_mpsc::TryRecvError::Empty) => break,
+                // Every handle is gone, we are shutting down.
+                Err(std_mpsc::TryRecvError::Disconnected) => {
+                    if forwards.is_empty() && pending.is_empty() {
+                        return SessionError::Closed;
+                    }
+                    break;
+                }
+            }
+        }
+        let mut progress = false;
+
+        // libssh2 can only open one channel at a time.
+        if let Some(open) = pending.front() {
+            let result = session.channel_direct_tcpip(&open.host, open.port, None);
+            if !matches!(&result, Err(err) if would_block(err)) {
+                progress = true;
+            }
+            match (result, pending.pop_front()) {
+                (Err(err), Some(open)) if would_block(&err) => pending.push_front(open),
+                (Ok(channel), Some(open)) => {
+                    if open.reply.send(Ok(())).is_ok() {
+                        forwards.push(Forward {
+                            channel,
+                            to_local: Some(open.to_local),
+                            from_local: open.from_local,
+                            unread: None,
+                            unwritten: Vec::new(),
+                            local_eof: false,
+                            eof_sent: false,
+                        });
+                    } else {
+                        closing.push(channel);
+                    }
+                }
+                (Err(err), Some(open)) => {
+                    _ = open.reply.send(Err(err.to_string()));
+                }
+                (_, None) => {}
+            }
+        }
+
+        let mut i = 0;
+        while i < forwards.len() {
+            let finished = match forwards[i].step(&mut buf) {
+                Ok(step_progress) => {
+                    progress |= step_progress;
+                    forwards[i].done()
+                }
+                Err(err) => {
+                    log::debug!("ssh channel failed: {err}");
+                    true
+                }
+            };
+            if finished {
+                closing.push(forwards.swap_remove(i).channel);
+                progress = true;
+            } else {
+                i += 1;
+            }
+        }
+
+        closing.retain_mut(|channel| matches!(channel.close(), Err(err) if would_block(&err)));
+
+        match session.keepalive_send() {
+            Err(err) if !would_block(&err) => return err.into(),
+            _ => {}
+        }
+
+        if !progress {
+            wait(session, wake);
+        }
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_pump() {
+        let (wake_rx, wake_tx) = UnixStream::pair().unwrap();
+        wake_rx.set_nonblocking(true).unwrap();
+        let waker = Waker(Arc::new(wake_tx));
+
+        let (to_local, from_remote) = mpsc::channel(CHANNEL_QUEUE);
+        let (to_remote, mut from_local) = mpsc::channel(CHANNEL_QUEUE);
+        let (mut stream, channel_end) = tokio::io::duplex(BUFFER_SIZE);
+        let pump = tokio::spawn(pump(channel_end, to_remote, from_remote, waker));
+
+        stream.write_all(b"request").await.unwrap();
+        assert_eq!(from_local.recv().await.unwrap(), b"request");
+        let mut buf = [0u8; 1];
+        assert_eq!((&wake_rx).read(&mut buf).unwrap(), 1);
+
+        to_local.send(b"response".to_vec()).await.unwrap();
+        drop(to_local);
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"response");
+
+        // Closing our side sends EOF to the remote.
+        drop(stream);
+        assert!(from_local.recv().await.is_none());
+        pump.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,368 @@
+//! Record the firewall commands applied by each session, so they can be undone
+//! exactly, even by another invocation after a crash.
+
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{command::Line, commands::Commands};
+
+pub const STATE_DIR: &str = "/run/sshuttle_rust";
+
+#[derive(Error, Debug)]
+pub enum StateError {
+    #[error("IO Error `{0}`")]
+    Io(#[from] std::io::Error),
+
+    #[error("JSON Error `{0}`")]
+    Json(#[from] serde_json::Error),
+
+    #[error("Command Error `{0}`")]
+    Command(Box<crate::command::Error>),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct State {
+    pub pid: u32,
+    /// When process `pid` started, so that another process reusing the pid
+    /// isn't mistaken for it. Missing from the files of older versions.
+    #[serde(default)]
+    pub start_time: Option<u64>,
+    /// The commands that were run to set up the firewall.
+    pub setup: Commands,
+    /// The commands that undo `setup`.
+    pub restore: Commands,
+}
+
+/// The state file of the running session, one per process.
+#[derive(Debug)]
+pub struct StateFile {
+    path: PathBuf,
+}
+
+impl StateFile {
+    /// Write the state before any of its setup commands are run.
+    pub fn create(dir: &Path, state: &State) -> Result<Self, StateError> {
+        std::fs::create_dir_all(dir)?;
+        let path = state_path(dir, state.pid);
+        // Write to a temporary file first, so a crash never leaves a truncated state.
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(state)?)?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(Self { path })
+    }
+
+    /// The directory holding the state files of every session.
+    pub fn dir(&self) -> &Path {
+        self.path.parent().unwrap_or_else(|| Path::new(STATE_DIR))
+    }
+
+    /// Called once the restore commands have succeeded.
+    pub fn remove(self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            log::error!("Could not remove state file {}: {err}", self.path.display());
+        }
+    }
+}
+
+fn state_path(dir: &Path, pid: u32) -> PathBuf {
+    dir.join(format!("{pid}.json"))
+}
+
+/// When process `pid` started, in clock ticks since boot, from field 22 of
+/// `/proc/<pid>/stat`. `None` if there is no such process.
+pub fn start_time(pid: u32) -> Option<u64> {
+    let stat =
+        std::fs::read_to_string(Path::new("/proc").join(pid.to_string()).join("stat")).ok()?;
+    // The command name, field 2, is in parentheses and may contain anything.
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(22 - 3)?.parse().ok()
+}
+
+/// Whether the process that recorded `state` is still running.
+fn is_running(state: &State) -> bool {
+    match (start_time(state.pid), state.start_time) {
+        (None, _) => false,
+        (Some(started), Some(recorded)) => started == recorded,
+        (Some(_), None) => true,
+    }
+}
+
+fn read_state(path: &Path) -> Result<State, StateError> {
+    let contents = std::fs::read(path)?;
+    Ok(serde_json::from_slice(&contents)?)
+}
+
+/// Every state file in `dir`, except those of this process.
+fn read_states(dir: &Path) -> Result<Vec<(PathBuf, State)>, StateError> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut states = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension() != Some(OsStr::new("json")) {
+            continue;
+        }
+        match read_state(&path) {
+            Ok(state) if state.pid == std::process::id() => {}
+            Ok(state) => states.push((path, state)),
+            Err(err) => log::warn!("Ignoring state file {}: {err}", path.display()),
+        }
+    }
+    Ok(states)
+}
+
+/// State files left by sessions that are no longer running.
+fn stale_states(dir: &Path) -> Result<Vec<(PathBuf, State)>, StateError> {
+    let mut states = read_states(dir)?;
+    states.retain(|(_, state)| !is_running(state));
+    Ok(states)
+}
+
+/// The states of the other sessions that are still running.
+pub fn live_states(dir: &Path) -> Result<Vec<State>, StateError> {
+    Ok(read_states(dir)?
+        .into_iter()
+        .map(|(_, state)| state)
+        .filter(is_running)
+        .collect())
+}
+
+/// The routing table an `ip` command uses, as in `lookup 100` or `table 100`.
+fn routing_table(line: &Line) -> Option<&str> {
+    let Line(program, args) = line;
+    if program != "ip" {
+        return None;
+    }
+    args.windows(2)
+        .find(|w| w[0] == "lookup" || w[0] == "table")
+        .map(|w| w[1].as_str())
+}
+
+/// Whether one of `states` routes marked packets with `table`, and so still
+/// needs that routing.
+pub fn live_table(states: &[State], table: &str) -> bool {
+    states
+        .iter()
+        .flat_map(|state| state.restore.iter())
+        .any(|cmd| routing_table(&cmd.line) == Some(table))
+}
+
+/// Drop the `ip` commands for routing tables that another running session
+/// uses, as the TPROXY firewalls of all sessions share their policy routing.
+///
+/// `pid` is the session the commands belong to.
+pub fn skip_shared_routing(commands: &mut Commands, dir: &Path, pid: u32) {
+    let mut live = match live_states(dir) {
+        Ok(live) => live,
+        Err(err) => {
+            log::warn!("Could not read the state of running sessions: {err}");
+            return;
+        }
+    };
+    live.retain(|state| state.pid != pid);
+    commands.retain(|cmd| {
+        let shared = routing_table(&cmd.line).is_some_and(|table| live_table(&live, table));
+        if shared {
+            log::info!(
+                "Skipping {}, the routing is used by another session",
+                cmd.line
+            );
+        }
+        !shared
+    });
+}
+
+/// Run the restore commands of `state`, then remove its file at `path`.
+async fn restore(dir: &Path, path: &Path, mut state: State) -> Result<(), StateError> {
+    skip_shared_routing(&mut state.restore, dir, state.pid);
+    state
+        .restore
+        .run_all()
+        .await
+        .map_err(|err| StateError::Command(Box::new(err)))?;
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+/// Undo the firewall changes of every session that exited without restoring them.
+///
+/// Returns the process ids of the recovered sessions.
+pub async fn recover(dir: &Path) -> Result<Vec<u32>, StateError> {
+    let mut recovered = Vec::new();
+    for (path, state) in stale_states(dir)? {
+        log::warn!(
+            "Restoring firewall left by process {}, from {}",
+            state.pid,
+            path.display()
+        );
+        let pid = state.pid;
+        restore(dir, &path, state).await?;
+        recovered.push(pid);
+    }
+    Ok(recovered)
+}
+
+/// Undo the firewall changes recorded by process `pid`, if it recorded any.
+///
+/// Used by the `RestoreKeeper` of a session that dropped privileges.
+pub async fn restore_pid(dir: &Path, pid: u32) -> Result<(), StateError> {
+    let path = state_path(dir, pid);
+    let state = match read_state(&path) {
+        Ok(state) => state,
+        Err(StateError::Io(err)) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    log::info!("Restoring firewall of process {pid}");
+    restore(dir, &path, state).await
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::Family;
+
+    #[test]
+    fn test_state_file() {
+        let dir = std::env::temp_dir().join(format!("sshuttle_rust-test-{}", std::process::id()));
+
+        let mut setup = Commands::new();
+        setup.ipt(Family::Ipv4, "nat", &["-N", "sshuttle-1024"]);
+        let mut restore = Commands::new();
+        restore.ipt_ignore_errors(Family::Ipv4, "nat", &["-X", "sshuttle-1024"]);
+
+        // No process has pid u32::MAX, so this looks like a crashed session.
+        let state = State {
+            pid: u32::MAX,
+            start_time: None,
+            setup,
+            restore,
+        };
+        let file = StateFile::create(&dir, &state).unwrap();
+
+        let found = stale_states(&dir).unwrap();
+        assert_eq!(found.len(), 1);
+        let (_, read) = &found[0];
+        assert_eq!(read.pid, u32::MAX);
+        let commands: Vec<_> = read.restore.iter().collect();
+        assert_eq!(commands.len(), 1);
+        assert_eq!(
+            commands[0].line.to_string(),
+            "iptables -w -t nat -X sshuttle-1024"
+        );
+        assert!(commands[0].ignore_errors);
+
+        file.remove();
+        assert!(stale_states(&dir).unwrap().is_empty());
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_restore_pid() {
+        let dir =
+            std::env::temp_dir().join(format!("sshuttle_rust-test-pid-{}", std::process::id()));
+        // Nothing recorded, nothing to do.
+        restore_pid(&dir, u32::MAX).await.unwrap();
+
+        let mut restore = Commands::new();
+        restore.push(crate::command::Line::new("true", [""; 0]));
+        let state = State {
+            pid: u32::MAX,
+            start_time: None,
+            setup: Commands::new(),
+            restore,
+        };
+        StateFile::create(&dir, &state).unwrap();
+
+        restore_pid(&dir, u32::MAX).await.unwrap();
+        assert!(!state_path(&dir, u32::MAX).exists());
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_skip_shared_routing() {
+        let dir =
+            std::env::temp_dir().join(format!("sshuttle_rust-test-routing-{}", std::process::id()));
+
+        // Process 1 is always running, and routes with table 100.
+        let mut restore = Commands::new();
+        restore.ip_ignore_errors(
+            Family::Ipv4,
+            &["rule", "del", "fwmark", "0x01", "lookup", "100"],
+        );
+        let live = State {
+            pid: 1,
+            start_time: start_time(1),
+            setup: Commands::new(),
+            restore,
+        };
+        assert!(live_table(std::slice::from_ref(&live), "100"));
+        assert!(!live_table(std::slice::from_ref(&live), "200"));
+        let file = StateFile::create(&dir, &live).unwrap();
+
+        let mut commands = Commands::new();
+        commands.ipt(Family::Ipv4, "mangle", &["-X", "sshuttle-t-tcp-1024"]);
+        commands.ip(
+            Family::Ipv4,
+            &["rule", "del", "fwmark", "0x01", "lookup", "100"],
+        );
+        commands.ip(
+            Family::Ipv4,
+            &[
+                "route",
+                "del",
+                "local",
+                "0.0.0.0/0",
+                "dev",
+                "lo",
+                "table",
+                "100",
+            ],
+        );
+        commands.ip(
+            Family::Ipv4,
+            &["rule", "del", "fwmark", "0x02", "lookup", "200"],
+        );
+        skip_shared_routing(&mut commands, &dir, std::process::id());
+
+        let lines: Vec<_> = commands.iter().map(|c| c.line.to_string()).collect();
+        assert_eq!(
+            lines,
+            [
+                "iptables -w -t mangle -X sshuttle-t-tcp-1024",
+                "ip -4 rule del fwmark 0x02 lookup 200",
+            ]
+        );
+
+        file.remove();
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_is_running() {
+        let pid = std::process::id();
+        let mut state = State {
+            pid,
+            start_time: start_time(pid),
+            setup: Commands::new(),
+            restore: Commands::new(),
+        };
+        assert!(state.start_time.is_some());
+        assert!(is_running(&state));
+        // Another process that was given the same pid.
+        state.start_time = state.start_time.map(|started| started + 1);
+        assert!(!is_running(&state));
+        state.pid = u32::MAX;
+        state.start_time = None;
+        assert!(!is_running(&state));
+    }
+}
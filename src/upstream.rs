@@ -0,0 +1,761 @@
+//! Where redirected connections are sent.
+//!
+//! The listeners only see an `Upstream`, so the same firewall and redirect
+//! machinery works with a socks server, an HTTP proxy, `sshuttle_rust server`,
+//! the built in ssh client, or no proxy at all.
+
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use fast_socks5::client::{Socks5Datagram, Socks5Stream};
+use fast_socks5::SocksError;
+use futures::future::BoxFuture;
+use thiserror::Error;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::net::{lookup_host, TcpStream, UdpSocket};
+use tokio::sync::watch;
+
+use crate::client::ClientError;
+use crate::mux::{MuxClient, MuxDatagram, MuxError};
+use crate::options::SocksVersion;
+use crate::ssh::Remote;
+#[cfg(feature = "builtin-ssh")]
+use crate::ssh_session::{SessionError, SshSession};
+
+#[derive(Error, Debug)]
+pub enum UpstreamError {
+    #[error("Socks server {0} {1}")]
+    SocksAuth(SocketAddr, &'static str),
+
+    #[error("Socks server {0} refused the connection, SOCKS4 reply {1:#04x}")]
+    Socks4(SocketAddr, u8),
+
+    #[error("Invalid HTTP proxy `{0}`, expected [USERNAME[:PASSWORD]@]HOST:PORT")]
+    InvalidHttpProxy(String),
+
+    #[error("HTTP proxy {0} {1}")]
+    HttpProxyAuth(String, &'static str),
+
+    #[error("HTTP proxy {0} refused the connection: {1}")]
+    HttpProxy(String, String),
+
+    #[error("Invalid response from HTTP proxy {0}")]
+    HttpProxyResponse(String),
+
+    #[error("Cannot resolve HTTP proxy {0}: {1}")]
+    HttpProxyResolve(String, std::io::Error),
+
+    #[error("HTTP proxy {0} has no address")]
+    HttpProxyNoAddress(String),
+}
+
+/// A connection through an upstream, whatever kind it is.
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
+
+/// Something redirected connections can be sent through.
+pub trait Upstream: Send + Sync {
+    /// Open a TCP connection to `addr`.
+    fn connect(&self, addr: SocketAddr)
+        -> BoxFuture<'_, Result<Box<dyn AsyncStream>, ClientError>>;
+
+    /// Start a UDP flow to `dst`, if the upstream can carry UDP.
+    fn datagram(&self, dst: SocketAddr) -> BoxFuture<'_, Result<Box<dyn Datagram>, ClientError>> {
+        Box::pin(async move {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("UDP to {dst} is not supported by this upstream"),
+            )
+            .into())
+        })
+    }
+
+    /// Send a DNS request to `dns_server`, and return the response.
+    ///
+    /// By default the request is sent over TCP.
+    fn query_dns<'a>(
+        &'a self,
+        request: &'a [u8],
+        dns_server: SocketAddr,
+    ) -> BoxFuture<'a, Result<Vec<u8>, ClientError>> {
+        Box::pin(dns_over_tcp(self, request, dns_server))
+    }
+}
+
+/// A UDP flow through an upstream.
+pub trait Datagram: Send {
+    fn send_to<'a>(
+        &'a self,
+        data: &'a [u8],
+        dst: SocketAddr,
+    ) -> BoxFuture<'a, Result<(), ClientError>>;
+
+    /// Receive a reply, returning its length.
+    fn recv<'a>(&'a mut self, buf: &'a mut [u8]) -> BoxFuture<'a, Result<usize, ClientError>>;
+}
+
+/// What to send connections through when we don't start ssh ourselves.
+#[derive(Clone, Debug)]
+pub enum Proxy {
+    /// A socks server, such as `ssh -D` run separately.
+    Socks(SocksProxy),
+    /// An HTTP proxy that supports CONNECT.
+    Http(HttpProxy),
+    /// Connect straight to the destination.
+    Direct,
+}
+
+impl Proxy {
+    pub fn upstream(&self) -> Arc<dyn Upstream> {
+        match self {
+            Proxy::Socks(proxy) => Arc::new(SocksUpstream(proxy.clone())),
+            Proxy::Http(proxy) => Arc::new(HttpUpstream(proxy.clone())),
+            Proxy::Direct => Arc::new(DirectUpstream),
+        }
+    }
+}
+
+const fn unspecified(addr: SocketAddr) -> &'static str {
+    match addr {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    }
+}
+
+/// A socks server, and how to talk to it.
+#[derive(Clone, Debug)]
+pub struct SocksProxy {
+    pub addr: SocketAddr,
+    pub version: SocksVersion,
+    /// Sent with username/password authentication by socks5, or as the user id by socks4.
+    pub user: Option<String>,
+    pub password: Option<String>,
+}
+
+impl SocksProxy {
+    /// A socks5 server without authentication, such as `ssh -D`.
+    pub const fn new(addr: SocketAddr) -> Self {
+        SocksProxy {
+            addr,
+            version: SocksVersion::Socks5,
+            user: None,
+            password: None,
+        }
+    }
+
+    /// Explain the errors that mean the server didn't accept our credentials.
+    pub fn error(&self, err: SocksError) -> ClientError {
+        let reason = match (&err, &self.user) {
+            (SocksError::AuthenticationRejected(_), Some(_)) => {
+                "rejected the username and password"
+            }
+            (
+                SocksError::AuthenticationRejected(_) | SocksError::AuthMethodUnacceptable(_),
+                None,
+            ) => "requires a username and password",
+            (SocksError::AuthMethodUnacceptable(_), Some(_)) => {
+                "does not accept username and password authentication"
+            }
+            _ => return err.into(),
+        };
+        UpstreamError::SocksAuth(self.addr, reason).into()
+    }
+}
+
+/// A socks server, usually `ssh -D`.
+pub struct SocksUpstream(pub SocksProxy);
+
+impl Upstream for SocksUpstream {
+    fn connect(
+        &self,
+        addr: SocketAddr,
+    ) -> BoxFuture<'_, Result<Box<dyn AsyncStream>, ClientError>> {
+        Box::pin(async move {
+            let proxy = &self.0;
+            if matches!(proxy.version, SocksVersion::Socks4) {
+                let remote = socks4_connect(proxy, addr).await?;
+                return Ok(Box::new(remote) as Box<dyn AsyncStream>);
+            }
+
+            let mut remote_config = fast_socks5::client::Config::default();
+            remote_config.set_skip_auth(false);
+            let (ip, port) = (addr.ip().to_string(), addr.port());
+            let remote = match &proxy.user {
+                Some(user) => {
+                    let password = proxy.password.clone().unwrap_or_default();
+                    Socks5Stream::connect_with_password(
+                        proxy.addr,
+                        ip,
+                        port,
+                        user.clone(),
+                        password,
+                        remote_config,
+                    )
+                    .await
+                }
+                None => Socks5Stream::connect(proxy.addr, ip, port, remote_config).await,
+            }
+            .map_err(|err| proxy.error(err))?;
+            Ok(Box::new(remote) as Box<dyn AsyncStream>)
+        })
+    }
+
+    fn datagram(&self, dst: SocketAddr) -> BoxFuture<'_, Result<Box<dyn Datagram>, ClientError>> {
+        Box::pin(async move {
+            let proxy = &self.0;
+            let backing_socket = TcpStream::connect(proxy.addr).await?;
+            let bind_addr = unspecified(dst);
+            let remote = match &proxy.user {
+                Some(user) => {
+                    let password = proxy.password.as_deref().unwrap_or_default();
+                    Socks5Datagram::bind_with_password(backing_socket, bind_addr, user, password)
+                        .await
+                }
+                None => Socks5Datagram::bind(backing_socket, bind_addr).await,
+            }
+            .map_err(|err| proxy.error(err))?;
+            Ok(Box::new(remote) as Box<dyn Datagram>)
+        })
+    }
+}
+
+/// The SOCKS4 reply code for a granted request.
+const SOCKS4_GRANTED: u8 = 0x5a;
+
+/// Connect to `addr` through a SOCKS4 server.
+///
+/// Only IPv4 destinations can be given. They are always addresses, so socks4a
+/// servers are happy with this as well.
+async fn socks4_connect(proxy: &SocksProxy, addr: SocketAddr) -> Result<TcpStream, ClientError> {
+    let addr = match addr {
+        SocketAddr::V4(addr) => addr,
+        SocketAddr::V6(_) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("SOCKS4 can't connect to the IPv6 address {addr}"),
+            )
+            .into())
+        }
+    };
+
+    let mut stream = TcpStream::connect(proxy.addr).await?;
+    let mut request = vec![0x04, 0x01];
+    request.extend_from_slice(&addr.port().to_be_bytes());
+    request.extend_from_slice(&addr.ip().octets());
+    request.extend_from_slice(proxy.user.as_deref().unwrap_or_default().as_bytes());
+    request.push(0);
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 8];
+    stream.read_exact(&mut reply).await?;
+    match reply[1] {
+        SOCKS4_GRANTED => Ok(stream),
+        0x5c => Err(UpstreamError::SocksAuth(
+            proxy.addr,
+            "could not check the user id with identd",
+        )
+        .into()),
+        0x5d => Err(UpstreamError::SocksAuth(proxy.addr, "rejected the user id").into()),
+        code => Err(UpstreamError::Socks4(proxy.addr, code).into()),
+    }
+}
+
+impl Datagram for Socks5Datagram<TcpStream> {
+    fn send_to<'a>(
+        &'a self,
+        data: &'a [u8],
+        dst: SocketAddr,
+    ) -> BoxFuture<'a, Result<(), ClientError>> {
+        Box::pin(async move {
+            Socks5Datagram::send_to(self, data, dst).await?;
+            Ok(())
+        })
+    }
+
+    fn recv<'a>(&'a mut self, buf: &'a mut [u8]) -> BoxFuture<'a, Result<usize, ClientError>> {
+        Box::pin(async move { Ok(Box::pin(self.recv_from(buf)).await?.0) })
+    }
+}
+
+/// An HTTP proxy given as `[USERNAME[:PASSWORD]@]HOST:PORT`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HttpProxy {
+    pub host: String,
+    pub port: u16,
+    /// Sent with Basic authentication.
+    pub user: Option<String>,
+    pub password: Option<String>,
+    /// Where `host` was resolved to at startup, connections go there.
+    pub resolved: Option<SocketAddr>,
+}
+
+impl HttpProxy {
+    /// Look up the proxy once, so that its address can be excluded from the
+    /// firewall and the lookup isn't redirected later.
+    pub async fn resolve(&mut self) -> Result<SocketAddr, UpstreamError> {
+        let addr = lookup_host((self.host.as_str(), self.port))
+            .await
+            .map_err(|err| UpstreamError::HttpProxyResolve(self.to_string(), err))?
+            .next()
+            .ok_or_else(|| UpstreamError::HttpProxyNoAddress(self.to_string()))?;
+        self.resolved = Some(addr);
+        Ok(addr)
+    }
+
+    async fn connect(&self) -> std::io::Result<TcpStream> {
+        match self.resolved {
+            Some(addr) => TcpStream::connect(addr).await,
+            None => TcpStream::connect((self.host.as_str(), self.port)).await,
+        }
+    }
+
+    /// The `Proxy-Authorization` header value, if we have credentials.
+    fn authorization(&self) -> Option<String> {
+        self.user.as_ref().map(|user| {
+            let password = self.password.as_deref().unwrap_or_default();
+            format!("Basic {}", STANDARD.encode(format!("{user}:{password}")))
+        })
+    }
+}
+
+impl FromStr for HttpProxy {
+    type Err = UpstreamError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || UpstreamError::InvalidHttpProxy(s.to_string());
+
+        // The same syntax as --remote, but the port is required.
+        let remote: Remote = s.parse().map_err(|_| invalid())?;
+        Ok(HttpProxy {
+            port: remote.port.ok_or_else(invalid)?,
+            host: remote.host,
+            user: remote.user,
+            password: remote.password,
+            resolved: None,
+        })
+    }
+}
+
+impl Display for HttpProxy {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// An HTTP proxy, each connection is opened with `CONNECT`.
+pub struct HttpUpstream(pub HttpProxy);
+
+impl Upstream for HttpUpstream {
+    fn connect(
+        &self,
+        addr: SocketAddr,
+    ) -> BoxFuture<'_, Result<Box<dyn AsyncStream>, ClientError>> {
+        Box::pin(async move {
+            let proxy = &self.0;
+            // Anything the proxy sends after its response is kept in the buffer.
+            let mut remote = BufReader::new(proxy.connect().await?);
+
+            let authorization = proxy
+                .authorization()
+                .map(|authorization| format!("Proxy-Authorization: {authorization}\r\n"))
+                .unwrap_or_default();
+            let request = format!("CONNECT {addr} HTTP/1.1\r\nHost: {addr}\r\n{authorization}\r\n");
+            remote.write_all(request.as_bytes()).await?;
+
+            let status_line = read_response(&mut remote, proxy).await?;
+            match http_status(&status_line) {
+                // Any 2xx response means the tunnel is open.
+                Some(200..=299) => Ok(Box::new(remote) as Box<dyn AsyncStream>),
+                Some(407) if proxy.user.is_some() => Err(UpstreamError::HttpProxyAuth(
+                    proxy.to_string(),
+                    "rejected the username and password",
+                )
+                .into()),
+                Some(407) => Err(UpstreamError::HttpProxyAuth(
+                    proxy.to_string(),
+                    "requires a username and password",
+                )
+                .into()),
+                Some(_) => Err(UpstreamError::HttpProxy(
+                    proxy.to_string(),
+                    status_line.trim_end().to_string(),
+                )
+                .into()),
+                None => Err(UpstreamError::HttpProxyResponse(proxy.to_string()).into()),
+            }
+        })
+    }
+}
+
+/// Responses to `CONNECT` with longer headers than this are refused.
+const MAX_RESPONSE_SIZE: u64 = 16 * 1024;
+
+/// Read the proxy's response up to the end of its headers, and return the status line.
+async fn read_response<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    proxy: &HttpProxy,
+) -> Result<String, UpstreamError> {
+    let invalid = || UpstreamError::HttpProxyResponse(proxy.to_string());
+    let mut reader = reader.take(MAX_RESPONSE_SIZE);
+
+    let mut status_line = String::new();
+    reader
+        .read_line(&mut status_line)
+        .await
+        .map_err(|_| invalid())?;
+
+    let mut header = String::new();
+    loop {
+        header.clear();
+        // The connection was closed, or the headers were too long.
+        if reader.read_line(&mut header).await.map_err(|_| invalid())? == 0 {
+            return Err(invalid());
+        }
+        if header == "\r\n" || header == "\n" {
+            return Ok(status_line);
+        }
+    }
+}
+
+/// The status code of an HTTP response's status line.
+fn http_status(status_line: &str) -> Option<u16> {
+    let mut parts = status_line.split_whitespace();
+    if !parts.next()?.starts_with("HTTP/") {
+        return None;
+    }
+    parts.next()?.parse().ok()
+}
+
+/// No proxy, connections are made from this host.
+pub struct DirectUpstream;
+
+impl Upstream for DirectUpstream {
+    fn connect(
+        &self,
+        addr: SocketAddr,
+    ) -> BoxFuture<'_, Result<Box<dyn AsyncStream>, ClientError>> {
+        Box::pin(
+            async move { Ok(Box::new(TcpStream::connect(addr).await?) as Box<dyn AsyncStream>) },
+        )
+    }
+
+    fn datagram(&self, dst: SocketAddr) -> BoxFuture<'_, Result<Box<dyn Datagram>, ClientError>> {
+        Box::pin(async move {
+            let socket = UdpSocket::bind(unspecified(dst)).await?;
+            // Only replies from the destination are received.
+            socket.connect(dst).await?;
+            Ok(Box::new(socket) as Box<dyn Datagram>)
+        })
+    }
+}
+
+impl Datagram for UdpSocket {
+    fn send_to<'a>(
+        &'a self,
+        data: &'a [u8],
+        dst: SocketAddr,
+    ) -> BoxFuture<'a, Result<(), ClientError>> {
+        Box::pin(async move {
+            UdpSocket::send_to(self, data, dst).await?;
+            Ok(())
+        })
+    }
+
+    fn recv<'a>(&'a mut self, buf: &'a mut [u8]) -> BoxFuture<'a, Result<usize, ClientError>> {
+        Box::pin(async move { Ok(UdpSocket::recv(self, buf).await?) })
+    }
+}
+
+/// `sshuttle_rust server` on the remote, while it is running.
+pub struct ServerUpstream(pub watch::Receiver<Option<MuxClient>>);
+
+impl ServerUpstream {
+    fn mux(&self) -> Result<MuxClient, MuxError> {
+        self.0.borrow().clone().ok_or(MuxError::Closed)
+    }
+}
+
+impl Upstream for ServerUpstream {
+    fn connect(
+        &self,
+        addr: SocketAddr,
+    ) -> BoxFuture<'_, Result<Box<dyn AsyncStream>, ClientError>> {
+        Box::pin(async move {
+            let mux = self.mux()?;
+            Ok(Box::new(mux.connect(addr).await?) as Box<dyn AsyncStream>)
+        })
+    }
+
+    fn datagram(&self, _dst: SocketAddr) -> BoxFuture<'_, Result<Box<dyn Datagram>, ClientError>> {
+        Box::pin(async move { Ok(Box::new(self.mux()?.datagram()) as Box<dyn Datagram>) })
+    }
+
+    /// The server sends it over UDP.
+    fn query_dns<'a>(
+        &'a self,
+        request: &'a [u8],
+        dns_server: SocketAddr,
+    ) -> BoxFuture<'a, Result<Vec<u8>, ClientError>> {
+        Box::pin(async move { Ok(self.mux()?.query_dns(dns_server, request).await?) })
+    }
+}
+
+impl Datagram for MuxDatagram {
+    fn send_to<'a>(
+        &'a self,
+        data: &'a [u8],
+        dst: SocketAddr,
+    ) -> BoxFuture<'a, Result<(), ClientError>> {
+        Box::pin(async move { Ok(MuxDatagram::send_to(self, data, dst).await?) })
+    }
+
+    fn recv<'a>(&'a mut self, buf: &'a mut [u8]) -> BoxFuture<'a, Result<usize, ClientError>> {
+        Box::pin(async move { Ok(self.recv_from(buf).await?.0) })
+    }
+}
+
+/// `direct-tcpip` channels on the built in ssh client's session, while it is connected.
+#[cfg(feature = "builtin-ssh")]
+pub struct SshUpstream(pub watch::Receiver<Option<SshSession>>);
+
+#[cfg(feature = "builtin-ssh")]
+impl Upstream for SshUpstream {
+    fn connect(
+        &self,
+        addr: SocketAddr,
+    ) -> BoxFuture<'_, Result<Box<dyn AsyncStream>, ClientError>> {
+        Box::pin(async move {
+            let session = self.0.borrow().clone().ok_or(SessionError::Closed)?;
+            let remote = session.connect(&addr.ip().to_string(), addr.port()).await?;
+            Ok(Box::new(remote) as Box<dyn AsyncStream>)
+        })
+    }
+}
+
+/// Send a DNS request over TCP, where each message is prefixed by its length.
+async fn dns_over_tcp<U: Upstream + ?Sized>(
+    upstream: &U,
+    request: &[u8],
+    dns_server: SocketAddr,
+) -> Result<Vec<u8>, ClientError> {
+    let len = u16::try_from(request.len()).map_err(|_| {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, "DNS request too large")
+    })?;
+
+    let mut remote = upstream.connect(dns_server).await?;
+
+    remote.write_all(&len.to_be_bytes()).await?;
+    remote.write_all(request).await?;
+
+    let len = remote.read_u16().await?;
+    let mut response = vec![0; usize::from(len)];
+    remote.read_exact(&mut response).await?;
+
+    Ok(response)
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    #[test]
+    fn test_http_status() {
+        assert_eq!(
+            http_status("HTTP/1.1 200 Connection established\r\n"),
+            Some(200)
+        );
+        assert_eq!(
+            http_status("HTTP/1.0 407 Proxy Authentication Required"),
+            Some(407)
+        );
+        assert_eq!(http_status("SSH-2.0-OpenSSH_9.0\r\n"), None);
+        assert_eq!(http_status(""), None);
+    }
+
+    #[test]
+    fn test_http_proxy() {
+        let proxy: HttpProxy = "user:pass:word@proxy.example.org:3128".parse().unwrap();
+        assert_eq!(proxy.host, "proxy.example.org");
+        assert_eq!(proxy.port, 3128);
+        assert_eq!(proxy.to_string(), "proxy.example.org:3128");
+        assert_eq!(
+            proxy.authorization().as_deref(),
+            Some("Basic dXNlcjpwYXNzOndvcmQ=")
+        );
+
+        let proxy: HttpProxy = "[2001:db8::1]:8080".parse().unwrap();
+        assert_eq!(proxy.to_string(), "[2001:db8::1]:8080");
+        assert_eq!(proxy.authorization(), None);
+
+        for proxy in ["proxy.example.org", "user@", "proxy:http"] {
+            assert!(
+                matches!(
+                    proxy.parse::<HttpProxy>(),
+                    Err(UpstreamError::InvalidHttpProxy(_))
+                ),
+                "{proxy}"
+            );
+        }
+    }
+
+    /// Answer one CONNECT with `response`, and return the request.
+    async fn mock_proxy(response: &'static [u8]) -> (HttpProxy, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = HttpProxy {
+            host: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+            user: None,
+            password: None,
+            resolved: None,
+        };
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut socket = BufReader::new(socket);
+            let mut request = String::new();
+            loop {
+                let mut line = String::new();
+                socket.read_line(&mut line).await.unwrap();
+                request.push_str(&line);
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            socket.write_all(response).await.unwrap();
+            request
+        });
+        (proxy, server)
+    }
+
+    #[tokio::test]
+    async fn test_http_connect() {
+        let (mut proxy, server) =
+            mock_proxy(b"HTTP/1.1 200 Connection established\r\nVia: test\r\n\r\nhello").await;
+        proxy.user = Some("user".to_string());
+        proxy.password = Some("secret".to_string());
+        // Connect to the address resolved at startup.
+        let addr = proxy.resolve().await.unwrap();
+        assert_eq!(proxy.resolved, Some(addr));
+
+        let upstream = HttpUpstream(proxy);
+        let mut remote = upstream
+            .connect("[2001:db8::1]:443".parse().unwrap())
+            .await
+            .unwrap();
+        let mut data = String::new();
+        remote.read_to_string(&mut data).await.unwrap();
+        assert_eq!(data, "hello");
+        assert_eq!(
+            server.await.unwrap(),
+            "CONNECT [2001:db8::1]:443 HTTP/1.1\r\n\
+             Host: [2001:db8::1]:443\r\n\
+             Proxy-Authorization: Basic dXNlcjpzZWNyZXQ=\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_http_connect_refused() {
+        let addr = "10.0.0.1:22".parse().unwrap();
+
+        let (proxy, _) = mock_proxy(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n").await;
+        let err = HttpUpstream(proxy).connect(addr).await.err().unwrap();
+        assert!(matches!(
+            err,
+            ClientError::Upstream(UpstreamError::HttpProxyAuth(_, _))
+        ));
+
+        let (proxy, _) = mock_proxy(b"HTTP/1.1 403 Forbidden\r\n\r\n").await;
+        let err = HttpUpstream(proxy).connect(addr).await.err().unwrap();
+        assert!(
+            matches!(err, ClientError::Upstream(UpstreamError::HttpProxy(_, ref status)) if status == "HTTP/1.1 403 Forbidden")
+        );
+
+        // Closed before the end of the headers.
+        let (proxy, _) = mock_proxy(b"HTTP/1.1 200 OK\r\n").await;
+        let err = HttpUpstream(proxy).connect(addr).await.err().unwrap();
+        assert!(matches!(
+            err,
+            ClientError::Upstream(UpstreamError::HttpProxyResponse(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_socks4_connect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut proxy = SocksProxy::new(listener.local_addr().unwrap());
+        proxy.version = SocksVersion::Socks4;
+        proxy.user = Some("alice".to_string());
+        let server = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for reply in [SOCKS4_GRANTED, 0x5d] {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = [0u8; 14];
+                socket.read_exact(&mut request).await.unwrap();
+                socket
+                    .write_all(&[0, reply, 0, 0, 0, 0, 0, 0])
+                    .await
+                    .unwrap();
+                requests.push(request);
+            }
+            requests
+        });
+
+        let upstream = SocksUpstream(proxy);
+        let addr = "10.1.2.3:8080".parse().unwrap();
+        upstream.connect(addr).await.unwrap();
+        let err = upstream.connect(addr).await.err().unwrap();
+        assert!(matches!(
+            err,
+            ClientError::Upstream(UpstreamError::SocksAuth(_, "rejected the user id"))
+        ));
+        let err = upstream
+            .connect("[2001:db8::1]:80".parse().unwrap())
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, ClientError::Io(_)));
+
+        let requests = server.await.unwrap();
+        assert_eq!(requests[0], *b"\x04\x01\x1f\x90\x0a\x01\x02\x03alice\0");
+    }
+
+    #[test]
+    fn test_socks_error() {
+        let mut proxy = SocksProxy::new("127.0.0.1:1080".parse().unwrap());
+        let rejected = || SocksError::AuthenticationRejected(String::new());
+        let unacceptable = || SocksError::AuthMethodUnacceptable(vec![0xff]);
+
+        assert!(matches!(
+            proxy.error(unacceptable()),
+            ClientError::Upstream(UpstreamError::SocksAuth(
+                _,
+                "requires a username and password"
+            ))
+        ));
+        proxy.user = Some("alice".to_string());
+        assert!(matches!(
+            proxy.error(rejected()),
+            ClientError::Upstream(UpstreamError::SocksAuth(
+                _,
+                "rejected the username and password"
+            ))
+        ));
+        assert!(matches!(
+            proxy.error(SocksError::ExceededMaxDomainLen(300)),
+            ClientError::Socks5(_)
+        ));
+    }
+}